@@ -1,142 +1,155 @@
-use std::collections::BTreeMap;
-use std::fs::File;
+use std::env;
 use std::io;
-use std::io::{BufWriter, Read, Write};
+use std::io::BufRead;
 
-mod indexing;
-mod parsing;
-use indexing::index::{write_documents, write_postings, write_term, Document, Posting};
-use indexing::varint::write_varint;
+use asgn1::indexing::codec::Codec;
+use asgn1::indexing::deletes::delete_documents;
+use asgn1::indexing::manifest::Manifest;
+use asgn1::indexing::merge::{force_merge, merge_segments, TieredMergePolicy};
+use asgn1::indexing::parallel::ParallelIndexer;
+use asgn1::indexing::stats::codec_stats;
+use asgn1::parsing::stream::TermStream;
+use asgn1::{Analyzer, IndexBuilder};
 
 fn main() -> std::io::Result<()> {
-    let stdin = io::stdin();
-    let mut content = String::new();
-    stdin.lock().read_to_string(&mut content).unwrap();
-
-    // Docno, term count
-    let mut documents: Vec<(&str, u64)> = Vec::new();
-    let mut term_count: u64 = 0;
-
-    // Term -> [document -> frequency]
-    // Dictionary is set of terms/keys
-    let mut index: BTreeMap<&str, Vec<(u64, u64)>> = BTreeMap::new();
-    let mut lines = content.split(|c| c == '\n');
-
-    while let Some(line) = lines.next() {
-        if documents.is_empty() {
-            documents.push((line, 0));
-            continue;
+    // Parse options
+    let args: Vec<String> = env::args().collect();
+    let dir = args
+        .iter()
+        .position(|a| a == "--index")
+        .and_then(|i| args.get(i + 1))
+        .map_or(".", String::as_str);
+
+    // Memory budget for the in-memory index, in MiB. Once it is exceeded the
+    // index is flushed to a sorted run inside the index directory.
+    let memory = args
+        .iter()
+        .position(|a| a == "--memory")
+        .and_then(|i| args.get(i + 1))
+        .map_or(1024, |m| {
+            m.parse::<usize>().expect("--memory takes a size in MiB")
+        });
+
+    // Number of threads documents are inverted on.
+    let threads = args
+        .iter()
+        .position(|a| a == "--threads")
+        .and_then(|i| args.get(i + 1))
+        .map_or(1, |t| t.parse::<usize>().expect("--threads takes a number"));
+
+    // Compares how well each codec compresses the postings of the index, and
+    // how quickly they decode.
+    if args.get(1).map(String::as_str) == Some("stats") {
+        let manifest = Manifest::read(dir)?;
+        let stats = codec_stats(dir, &Codec::ALL)?;
+
+        println!("Index codec: {}", manifest.codec.name());
+        println!("Postings: {}", stats.first().map_or(0, |s| s.postings));
+        println!();
+        println!(
+            "{:<14} {:>12} {:>13} {:>7} {:>14}",
+            "codec", "bytes", "bits/posting", "ratio", "M postings/s"
+        );
+        for s in stats {
+            println!(
+                "{:<14} {:>12} {:>13.2} {:>7.2} {:>14.1}",
+                s.codec.name(),
+                s.bytes,
+                s.bits_per_posting(),
+                s.ratio(),
+                s.decode_speed()
+            );
         }
-
-        if line.is_empty() {
-            match lines.next() {
-                Some(doc_line) => {
-                    // TODO: refactor
-                    let prev = documents.len() - 1;
-                    documents[prev].1 = term_count;
-                    term_count = 0;
-                    documents.push((doc_line, 0))
-                }
-                None => break,
-            }
-            continue;
-        }
-
-        term_count += 1;
-
-        assert!(!line.is_empty());
-
-        let k = documents.len() as u64 - 1;
-
-        match index.get_mut(line) {
-            Some(ind) => {
-                let i = ind.len() - 1;
-                if ind[i].0 != k {
-                    ind.push((k, 1));
-                } else {
-                    ind.get_mut(i).unwrap().1 += 1;
-                }
-            }
-            None => {
-                index.insert(line, vec![(k, 1)]);
-            }
-        };
+        return Ok(());
     }
 
-    // TODO: refactor
-    let prev = documents.len() - 1;
-    documents[prev].1 = term_count;
-
-    // Write documents
-    {
-        let avg_dl = documents.iter().fold(0, |a, &b| a + b.1) as f32 / documents.len() as f32;
-
-        let docs_file = File::create("documents.bin")?;
-        let mut docs_out = BufWriter::new(docs_file);
-
-        write_documents(
-            documents.len() as u64,
-            avg_dl,
-            documents.iter().map(|(name, term_count)| Document {
-                term_count: *term_count,
-                name: name.to_string(),
-            }),
-            &mut docs_out,
-        )?;
+    // Merging works on the segments already on disk, so no input is read.
+    if args.iter().any(|a| a == "--merge") {
+        let merges = merge_segments(dir, &TieredMergePolicy::default())?;
+        eprintln!("Performed {} merge(s).", merges);
+        return Ok(());
     }
 
-    // Write postings and blocks files concurrently
-    let index = index.iter().collect::<Vec<_>>();
-
-    {
-        let post_file = File::create("postings.bin")?;
-        let mut post_out = BufWriter::new(post_file);
+    if args.iter().any(|a| a == "--optimize") {
+        return force_merge(dir);
+    }
 
-        let block_file = File::create("blocks.bin")?;
-        let mut block_out = BufWriter::new(block_file);
+    let stdin = io::stdin();
 
-        let index_file = File::create("index.bin")?;
-        let mut index_out = BufWriter::new(index_file);
+    // Deletion reads document names (DOCNOs), one per line, rather than
+    // parsed documents.
+    if args.iter().any(|a| a == "--delete") {
+        let names = stdin
+            .lock()
+            .lines()
+            .map(|line| line.map(|l| l.trim().to_string()))
+            .filter(|line| !matches!(line, Ok(l) if l.is_empty()))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let deleted = delete_documents(dir, names)?;
+        eprintln!("Deleted {} document(s).", deleted);
+        return Ok(());
+    }
 
-        // `index_count` indicates the length of the root index.
-        // If the number of terms is exactly divisible by 1000, we have that
-        // many terms in the root index. Otherwise, there is an extra entry.
-        let index_count = if index.len() % 1000 == 0 {
-            (index.len() as u64) / 1000
+    // How postings are compressed. Only applies to new indexes; appending
+    // keeps the codec the index was created with.
+    let codec = args
+        .iter()
+        .position(|a| a == "--codec")
+        .and_then(|i| args.get(i + 1))
+        .map_or(Codec::default(), |c| {
+            Codec::from_name(c).unwrap_or_else(|| {
+                let names = Codec::ALL.iter().map(|c| c.name()).collect::<Vec<_>>();
+                panic!("--codec takes one of {}", names.join(", "))
+            })
+        });
+
+    // Positions are needed for phrase queries, at the cost of a larger index.
+    let mut builder = IndexBuilder::new()
+        .memory_budget(memory * 1024 * 1024)
+        .positions(args.iter().any(|a| a == "--positions"))
+        .fields(args.iter().any(|a| a == "--fields"))
+        .codec(codec)
+        .temp_dir(dir);
+
+    // Reads the XML collection itself rather than the output of `parse`,
+    // so each document is analyzed on the thread which inverts it.
+    if args.iter().any(|a| a == "--xml") {
+        let analyzer = Analyzer::new();
+        let documents = analyzer.raw_documents(stdin.lock());
+
+        if threads > 1 {
+            ParallelIndexer::new(threads).run(&mut builder, documents, |raw| {
+                analyzer.documents(&raw).collect::<Vec<_>>()
+            })?;
         } else {
-            ((index.len() as u64) / 1000) + 1
-        };
-
-        let mut postings_offset: usize = 0;
-        let mut blocks_offset: usize = write_varint(&mut block_out, index.len() as u64)?;
-        let mut index_offset: usize = write_varint(&mut index_out, index_count)?;
-
-        for (n, (term, postings)) in index.into_iter().enumerate() {
-            let post_ptr = postings_offset;
-
-            postings_offset += write_postings(
-                postings.len() as u64,
-                postings.iter().map(|&(document, frequency)| Posting {
-                    document,
-                    frequency,
-                }),
-                &mut post_out,
-            )?;
-
-            let block_ptr = blocks_offset;
-
-            blocks_offset += write_term(term.as_bytes(), post_ptr as u64, &mut block_out)?;
-
-            // Write every 1000 terms to the root index
-            if n % 1000 == 0 {
-                index_offset += write_term(term.as_bytes(), block_ptr as u64, &mut index_out)?;
+            for raw in documents {
+                for doc in analyzer.documents(&raw?) {
+                    builder.add_parsed_document(&doc)?;
+                }
             }
         }
+    } else {
+        let documents = TermStream::new(stdin.lock());
 
-        post_out.flush()?;
-        block_out.flush()?;
-        index_out.flush()?;
+        if threads > 1 {
+            ParallelIndexer::new(threads).run(&mut builder, documents, Some)?;
+        } else {
+            for doc in documents {
+                let doc = doc?;
+                builder.add_parsed_document(&doc)?;
+            }
+        }
     }
 
-    Ok(())
+    // Appending adds the documents as a new segment rather than replacing
+    // the whole index. Updating does the same, but first deletes any
+    // documents already indexed under the same names.
+    if args.iter().any(|a| a == "--update") {
+        builder.update(dir)
+    } else if args.iter().any(|a| a == "--append") {
+        builder.write_segment(dir)
+    } else {
+        builder.write(dir)
+    }
 }
@@ -0,0 +1,531 @@
+use crate::indexing::codec::Codec;
+use crate::indexing::deletes::{mark_deleted, remove_stale};
+use crate::indexing::index::{Document, Posting};
+use crate::indexing::manifest::{Manifest, SegmentInfo};
+use crate::indexing::runs::{merge_runs, write_run, RunReader};
+use crate::indexing::segment::{remove_segment, SegmentWriter};
+use crate::parsing::analyzer::ParsedDocument;
+use crate::parsing::field::Field;
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Rough cost, in bytes, of a term in the in-memory index on top of the term
+// itself (the `String` and `Vec` headers, plus the B-Tree's bookkeeping).
+const TERM_OVERHEAD: usize = 64;
+
+// Cost, in bytes, of a single in-memory posting.
+const POSTING_SIZE: usize = std::mem::size_of::<(u64, u64)>();
+
+// Cost, in bytes, of a single in-memory position.
+const POSITION_SIZE: usize = std::mem::size_of::<u64>();
+
+// Distinguishes the runs of builders living in the same process.
+static BUILDER_ID: AtomicUsize = AtomicUsize::new(0);
+
+// Accumulates documents in memory and writes them out in the on-disk format
+// understood by `DiskIndex`.
+//
+// Once the in-memory index grows past the memory budget it is flushed to a
+// sorted run in the temporary directory (single-pass in-memory indexing, or
+// SPIMI). The runs are merged into the final index files by `write`.
+pub struct IndexBuilder {
+    // Docno, term count
+    documents: Vec<Document>,
+
+    // Term -> [document -> frequency] (and positions)
+    // Dictionary is set of terms/keys
+    index: BTreeMap<String, TermPostings>,
+    positions: bool,
+    fields: bool,
+    codec: Codec,
+
+    // Approximate number of bytes held by `index`.
+    memory: usize,
+    memory_budget: usize,
+
+    id: usize,
+    temp_dir: PathBuf,
+    runs: Vec<PathBuf>,
+}
+
+// The postings of a single term in the in-memory index.
+struct TermPostings {
+    // Document -> frequency
+    postings: Vec<(u64, u64)>,
+    // The positions of the term within each document, in posting order. Left
+    // empty unless positions are kept.
+    positions: Vec<u64>,
+}
+
+impl Default for IndexBuilder {
+    fn default() -> Self {
+        IndexBuilder::new()
+    }
+}
+
+impl IndexBuilder {
+    // Creates a builder which keeps the whole index in memory.
+    pub fn new() -> Self {
+        IndexBuilder {
+            documents: Vec::new(),
+            index: BTreeMap::new(),
+            positions: false,
+            fields: false,
+            codec: Codec::Varint,
+            memory: 0,
+            memory_budget: usize::MAX,
+            id: BUILDER_ID.fetch_add(1, Ordering::Relaxed),
+            temp_dir: env::temp_dir(),
+            runs: Vec::new(),
+        }
+    }
+
+    // Sets the approximate number of bytes the in-memory index may use before
+    // it is flushed to a run on disk.
+    pub fn memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = bytes;
+        self
+    }
+
+    // Sets the directory runs are written to. Defaults to the system's
+    // temporary directory.
+    pub fn temp_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.temp_dir = dir.as_ref().to_path_buf();
+        self
+    }
+
+    // Keeps the position of every term within its document, so the index
+    // can answer phrase queries.
+    pub fn positions(mut self, keep: bool) -> Self {
+        self.positions = keep;
+        self
+    }
+
+    pub fn keeps_positions(&self) -> bool {
+        self.positions
+    }
+
+    // Also indexes the terms of each field of a parsed document under the
+    // field, as `hl:merger` and so on, so queries can be scoped to a field.
+    pub fn fields(mut self, keep: bool) -> Self {
+        self.fields = keep;
+        self
+    }
+
+    pub fn keeps_fields(&self) -> bool {
+        self.fields
+    }
+
+    // Compresses postings with `codec`. Only applies when `write` creates a
+    // new index; segments added to an existing index use its codec.
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    // Number of documents added so far.
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    // Number of runs flushed to disk so far.
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    // Adds a document to the index, returning the document ID it was
+    // assigned. Document IDs are handed out sequentially from 0.
+    pub fn add_document<I, S>(&mut self, name: &str, terms: I) -> std::io::Result<u64>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.add(name, terms.into_iter().map(|term| (term, None)))
+    }
+
+    // Adds a parsed document to the index. If fields are kept, the terms of
+    // each field are also indexed under the field.
+    pub fn add_parsed_document(&mut self, doc: &ParsedDocument) -> std::io::Result<u64> {
+        if self.fields {
+            self.add(&doc.name, doc.terms.iter().zip(doc.term_fields()))
+        } else {
+            self.add_document(&doc.name, &doc.terms)
+        }
+    }
+
+    fn add<I, S>(&mut self, name: &str, terms: I) -> std::io::Result<u64>
+    where
+        I: Iterator<Item = (S, Option<Field>)>,
+        S: AsRef<str>,
+    {
+        let k = self.documents.len() as u64;
+        let mut term_count: u64 = 0;
+        let mut fields = if self.fields {
+            vec![0; Field::COUNT]
+        } else {
+            Vec::new()
+        };
+
+        for (position, (term, field)) in terms.enumerate() {
+            let term = term.as_ref();
+            term_count += 1;
+
+            self.insert(term, k, position as u64);
+
+            // The field's copy of the term shares its position, and doesn't
+            // count towards the length of the document.
+            if let Some(field) = field {
+                self.insert(&field.qualify(term), k, position as u64);
+                fields[field.index()] += 1;
+            }
+        }
+
+        self.documents.push(Document {
+            term_count,
+            name: name.to_string(),
+            fields,
+        });
+
+        // Runs are only ever cut between documents, so the postings of a
+        // single document never straddle two runs.
+        if self.memory >= self.memory_budget {
+            self.flush_run()?;
+        }
+
+        Ok(k)
+    }
+
+    // Records an occurrence of `term` in document `k`.
+    fn insert(&mut self, term: &str, k: u64, position: u64) {
+        let entry = match self.index.get_mut(term) {
+            Some(entry) => {
+                let ind = &mut entry.postings;
+                let i = ind.len() - 1;
+                if ind[i].0 != k {
+                    ind.push((k, 1));
+                    self.memory += POSTING_SIZE;
+                } else {
+                    ind[i].1 += 1;
+                }
+                entry
+            }
+            None => {
+                self.memory += term.len() + TERM_OVERHEAD + POSTING_SIZE;
+                self.index.entry(term.to_string()).or_insert(TermPostings {
+                    postings: vec![(k, 1)],
+                    positions: Vec::new(),
+                })
+            }
+        };
+
+        if self.positions {
+            entry.positions.push(position);
+            self.memory += POSITION_SIZE;
+        }
+    }
+
+    // Appends every document of `other` to this index, in order. The
+    // documents are renumbered to follow the ones already added, so building
+    // batches of documents separately and appending them gives exactly the
+    // same index as adding each document here directly.
+    //
+    // `other` must not have flushed any runs of its own, and must keep
+    // positions and fields if this builder does.
+    pub fn append(&mut self, mut other: IndexBuilder) -> std::io::Result<()> {
+        if !other.runs.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "cannot append an index builder which has flushed runs",
+            ));
+        }
+
+        if (self.positions && !other.positions) || (self.fields && !other.fields) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "cannot append an index builder without positions or fields",
+            ));
+        }
+
+        let base = self.documents.len() as u64;
+
+        for (term, mut entry) in std::mem::take(&mut other.index) {
+            for posting in entry.postings.iter_mut() {
+                posting.0 += base;
+            }
+            if !self.positions {
+                entry.positions.clear();
+            }
+
+            match self.index.get_mut(&term) {
+                Some(ind) => {
+                    ind.postings.append(&mut entry.postings);
+                    ind.positions.append(&mut entry.positions);
+                }
+                None => {
+                    self.index.insert(term, entry);
+                }
+            }
+        }
+
+        self.documents.append(&mut other.documents);
+        self.memory += other.memory;
+
+        if self.memory >= self.memory_budget {
+            self.flush_run()?;
+        }
+
+        Ok(())
+    }
+
+    // Writes the in-memory index to a new run and clears it.
+    fn flush_run(&mut self) -> std::io::Result<()> {
+        if self.index.is_empty() {
+            return Ok(());
+        }
+
+        fs::create_dir_all(&self.temp_dir)?;
+        let path = self.temp_dir.join(format!(
+            "asgn1-run-{}-{}-{}.tmp",
+            std::process::id(),
+            self.id,
+            self.runs.len()
+        ));
+
+        write_run(
+            &path,
+            self.index.iter().map(|(term, entry)| {
+                (
+                    term.as_str(),
+                    entry.postings.as_slice(),
+                    entry.positions.as_slice(),
+                )
+            }),
+        )?;
+        self.runs.push(path);
+
+        self.index.clear();
+        self.memory = 0;
+
+        Ok(())
+    }
+
+    // Writes the documents as a brand new index in the directory `dir`,
+    // creating it if necessary. Any index already in `dir` is replaced. The
+    // manifest is written last, once every other file is complete.
+    pub fn write<P: AsRef<Path>>(self, dir: P) -> std::io::Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        // Any existing index in `dir` is about to be replaced, so it is no
+        // longer valid. Its segments are removed once the new index is in
+        // place.
+        let old = Manifest::read(dir).ok();
+        Manifest::remove(dir)?;
+
+        let mut manifest = Manifest::new();
+        manifest.generation = old.as_ref().map_or(0, |m| m.generation);
+        manifest.codec = self.codec;
+
+        let name = manifest.next_segment_name();
+        manifest
+            .segments
+            .push(self.write_segment_files(dir, &name, manifest.codec)?);
+        manifest.write(dir)?;
+
+        for segment in old.map_or_else(Vec::new, |m| m.segments) {
+            remove_segment(dir, &segment.name)?;
+        }
+
+        Ok(())
+    }
+
+    // Adds the documents to the index in `dir` as a new segment, next to the
+    // segments already there. If `dir` doesn't hold an index yet, a new one
+    // is created.
+    pub fn write_segment<P: AsRef<Path>>(self, dir: P) -> std::io::Result<()> {
+        let dir = dir.as_ref();
+        if !Manifest::exists(dir) {
+            return self.write(dir);
+        }
+
+        let mut manifest = Manifest::read(dir)?;
+        manifest.verify(dir)?;
+
+        let name = manifest.next_segment_name();
+        manifest
+            .segments
+            .push(self.write_segment_files(dir, &name, manifest.codec)?);
+        manifest.write(dir)
+    }
+
+    // Adds the documents to the index in `dir` as a new segment, replacing
+    // any documents already in the index with the same names. The old
+    // documents are deleted and the new segment added by a single manifest
+    // write, so searches see either every old document or every new one.
+    pub fn update<P: AsRef<Path>>(self, dir: P) -> std::io::Result<()> {
+        let dir = dir.as_ref();
+        if !Manifest::exists(dir) {
+            return self.write(dir);
+        }
+
+        let mut manifest = Manifest::read(dir)?;
+        manifest.verify(dir)?;
+
+        let names = self
+            .documents
+            .iter()
+            .map(|d| d.name.clone())
+            .collect::<HashSet<_>>();
+        let (_, stale) = mark_deleted(dir, &mut manifest, &names)?;
+
+        let name = manifest.next_segment_name();
+        manifest
+            .segments
+            .push(self.write_segment_files(dir, &name, manifest.codec)?);
+        manifest.write(dir)?;
+
+        remove_stale(dir, stale)
+    }
+
+    // Writes the segment `name` inside the index directory `dir`, either
+    // straight from the in-memory index or by merging every run.
+    fn write_segment_files(
+        mut self,
+        dir: &Path,
+        name: &str,
+        codec: Codec,
+    ) -> std::io::Result<SegmentInfo> {
+        let documents = std::mem::take(&mut self.documents);
+        let mut writer = SegmentWriter::create(dir.join(name), documents, self.positions, codec)?;
+
+        if self.runs.is_empty() {
+            for (term, entry) in self.index.iter() {
+                writer.push(
+                    term,
+                    entry.postings.len() as u64,
+                    entry.postings.iter().map(|&(document, frequency)| Posting {
+                        document,
+                        frequency,
+                    }),
+                    &entry.positions,
+                )?;
+            }
+        } else {
+            self.flush_run()?;
+
+            let runs = self
+                .runs
+                .iter()
+                .map(RunReader::open)
+                .collect::<std::io::Result<Vec<_>>>()?;
+
+            merge_runs(runs, |term, postings, positions| {
+                writer.push(
+                    term,
+                    postings.len() as u64,
+                    postings.iter().copied(),
+                    positions,
+                )
+            })?;
+
+            for run in self.runs.drain(..) {
+                fs::remove_file(run)?;
+            }
+        }
+
+        writer.finish(dir, name)
+    }
+}
+
+impl Drop for IndexBuilder {
+    // Runs left behind by a builder which was never written are removed.
+    fn drop(&mut self) {
+        for run in &self.runs {
+            let _ = fs::remove_file(run);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::index::{
+        DiskIndex, BLOCKS_FILE, DOCUMENTS_FILE, INDEX_FILE, POSTINGS_FILE,
+    };
+    use crate::indexing::testing::TempDir;
+
+    #[test]
+    fn write_and_search() {
+        let dir = TempDir::new("builder");
+
+        let mut builder = IndexBuilder::new();
+        builder
+            .add_document("a", vec!["wall", "street", "journal"])
+            .unwrap();
+        builder
+            .add_document("b", vec!["wall", "wall", "paper"])
+            .unwrap();
+        builder
+            .add_document("c", vec!["tokyo", "stock", "exchange"])
+            .unwrap();
+        builder.write(&dir).unwrap();
+
+        let index = DiskIndex::from_disk(&dir).unwrap();
+        assert_eq!(index.postings("wall").unwrap().len(), 2);
+        assert!(index.postings("missing").unwrap().is_empty());
+
+        let results = index.search("wall paper").unwrap().collect::<Vec<_>>();
+        assert_eq!(index.document(results[0].1), "b");
+        assert_eq!(results.len(), 2);
+
+        // A half-written index is rejected.
+        fs::remove_file(dir.join("seg-000000").join(BLOCKS_FILE)).unwrap();
+        assert!(DiskIndex::from_disk(&dir).is_err());
+    }
+
+    #[test]
+    fn runs_match_in_memory() {
+        let root = TempDir::new("spimi");
+
+        let docs = (0..200u64)
+            .map(|n| {
+                let terms = (0..(n % 13 + 1))
+                    .map(|t| format!("t{}", (n * 7 + t * 3) % 97))
+                    .collect::<Vec<_>>();
+                (format!("doc{}", n), terms)
+            })
+            .collect::<Vec<_>>();
+
+        let mut memory = IndexBuilder::new();
+        let mut spimi = IndexBuilder::new()
+            .memory_budget(2048)
+            .temp_dir(root.join("runs"));
+
+        for (name, terms) in &docs {
+            memory.add_document(name, terms).unwrap();
+            spimi.add_document(name, terms).unwrap();
+        }
+
+        assert!(spimi.runs() > 1);
+
+        memory.write(root.join("memory")).unwrap();
+        spimi.write(root.join("spimi")).unwrap();
+
+        for name in &[DOCUMENTS_FILE, POSTINGS_FILE, BLOCKS_FILE, INDEX_FILE] {
+            assert_eq!(
+                fs::read(root.join("memory/seg-000000").join(name)).unwrap(),
+                fs::read(root.join("spimi/seg-000000").join(name)).unwrap(),
+            );
+        }
+
+        // Runs are cleaned up once they've been merged.
+        assert_eq!(fs::read_dir(root.join("runs")).unwrap().count(), 0);
+    }
+}
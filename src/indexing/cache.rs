@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Rough cost, in bytes, of an entry on top of its value (the slot, and its
+// key in the map).
+const ENTRY_OVERHEAD: usize = 64;
+
+// Hit and miss counts, and current size, of a cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    // Number of values evicted to make room for others.
+    pub evictions: u64,
+    pub entries: usize,
+    // Approximate number of bytes held, out of `capacity`.
+    pub bytes: usize,
+    pub capacity: usize,
+}
+
+impl CacheStats {
+    // Fraction of lookups which were hits.
+    pub fn hit_rate(&self) -> f64 {
+        self.hits as f64 / (self.hits + self.misses).max(1) as f64
+    }
+}
+
+// A thread-safe cache of values which are expensive to decode, bounded by
+// their approximate size in bytes. Once full, values are evicted with the
+// CLOCK algorithm: a hand sweeps over the entries, evicting the first one
+// which hasn't been used since the hand last passed it. This approximates
+// LRU without reordering anything on a hit.
+//
+// Values are handed out as `Arc`s, so an evicted value stays alive for as
+// long as a search is still using it.
+pub struct Cache<K, V> {
+    capacity: usize,
+    clock: Mutex<Clock<K, V>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+struct Clock<K, V> {
+    // Key -> index into `slots`
+    map: HashMap<K, usize>,
+    slots: Vec<Option<Slot<K, V>>>,
+    // Indexes of the empty slots.
+    free: Vec<usize>,
+    hand: usize,
+    bytes: usize,
+}
+
+struct Slot<K, V> {
+    key: K,
+    value: Arc<V>,
+    size: usize,
+    referenced: bool,
+}
+
+impl<K: Hash + Eq + Clone, V> Cache<K, V> {
+    // Creates a cache holding roughly `capacity` bytes of values. A capacity
+    // of 0 caches nothing.
+    pub fn new(capacity: usize) -> Self {
+        Cache {
+            capacity,
+            clock: Mutex::new(Clock {
+                map: HashMap::new(),
+                slots: Vec::new(),
+                free: Vec::new(),
+                hand: 0,
+                bytes: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    // Returns the value cached under `key`, or decodes it with `load` and
+    // caches it. `load` gives the value and its size in bytes. The lock is
+    // not held while loading, so two threads missing on the same key at
+    // once both decode it, and the second copy replaces the first.
+    pub fn get_or_load<F>(&self, key: &K, load: F) -> std::io::Result<Arc<V>>
+    where
+        F: FnOnce() -> std::io::Result<(V, usize)>,
+    {
+        if let Some(value) = self.get(key) {
+            return Ok(value);
+        }
+
+        let (value, size) = load()?;
+        let value = Arc::new(value);
+        self.insert(key.clone(), value.clone(), size);
+
+        Ok(value)
+    }
+
+    fn get(&self, key: &K) -> Option<Arc<V>> {
+        let mut clock = self.clock.lock().unwrap();
+
+        match clock.map.get(key).copied() {
+            Some(i) => {
+                let slot = clock.slots[i].as_mut().unwrap();
+                slot.referenced = true;
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(slot.value.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    fn insert(&self, key: K, value: Arc<V>, size: usize) {
+        let size = size + ENTRY_OVERHEAD;
+
+        // A value which would push everything else out isn't kept at all.
+        if size > self.capacity {
+            return;
+        }
+
+        let mut clock = self.clock.lock().unwrap();
+        if let Some(i) = clock.map.remove(&key) {
+            clock.remove(i);
+        }
+
+        while clock.bytes + size > self.capacity {
+            clock.evict();
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let slot = Slot {
+            key: key.clone(),
+            value,
+            size,
+            // New entries survive one sweep of the hand, so a value which is
+            // used again soon after it is loaded isn't evicted straight away.
+            referenced: true,
+        };
+
+        let i = match clock.free.pop() {
+            Some(i) => {
+                clock.slots[i] = Some(slot);
+                i
+            }
+            None => {
+                clock.slots.push(Some(slot));
+                clock.slots.len() - 1
+            }
+        };
+
+        clock.map.insert(key, i);
+        clock.bytes += size;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let clock = self.clock.lock().unwrap();
+
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries: clock.map.len(),
+            bytes: clock.bytes,
+            capacity: self.capacity,
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> Clock<K, V> {
+    // Evicts the first entry from the hand on which hasn't been used since
+    // the hand last passed it, clearing the mark on those which have. Only
+    // called while the cache holds something.
+    fn evict(&mut self) {
+        loop {
+            if self.hand >= self.slots.len() {
+                self.hand = 0;
+            }
+
+            let i = self.hand;
+            self.hand += 1;
+
+            if let Some(slot) = self.slots[i].as_mut() {
+                if slot.referenced {
+                    slot.referenced = false;
+                } else {
+                    let key = &self.slots[i].as_ref().unwrap().key;
+                    self.map.remove(key);
+                    self.remove(i);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, i: usize) {
+        if let Some(slot) = self.slots[i].take() {
+            self.bytes -= slot.size;
+            self.free.push(i);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::builder::IndexBuilder;
+    use crate::indexing::testing::{index, TempDir};
+
+    fn load(value: u64, size: usize) -> impl FnOnce() -> std::io::Result<(u64, usize)> {
+        move || Ok((value, size))
+    }
+
+    #[test]
+    fn clock_eviction() {
+        // Room for three entries of 100 bytes.
+        let cache = Cache::new(3 * (100 + ENTRY_OVERHEAD));
+
+        for key in 0..3 {
+            assert_eq!(
+                *cache.get_or_load(&key, load(key * 10, 100)).unwrap(),
+                key * 10
+            );
+        }
+        assert_eq!(*cache.get_or_load(&1, load(0, 100)).unwrap(), 10);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 3, 3));
+
+        // Every entry is marked as used, so the hand clears them all and
+        // comes back round to the oldest.
+        cache.get_or_load(&3, load(30, 100)).unwrap();
+        assert_eq!(cache.stats().evictions, 1);
+
+        // 1 and 2 were passed over once already, and 1 is used again.
+        cache.get_or_load(&1, load(0, 100)).unwrap();
+        cache.get_or_load(&4, load(40, 100)).unwrap();
+        assert_eq!(*cache.get_or_load(&1, load(0, 100)).unwrap(), 10);
+        assert_eq!(*cache.get_or_load(&2, load(20, 100)).unwrap(), 20);
+        assert_eq!(cache.stats().misses, 6);
+
+        // Values larger than the whole cache aren't kept, and a failed load
+        // caches nothing.
+        cache.get_or_load(&5, load(50, 1000)).unwrap();
+        assert!(cache
+            .get_or_load(&6, || Err(std::io::ErrorKind::Other.into()))
+            .is_err());
+
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 3);
+        assert!(stats.bytes <= stats.capacity);
+
+        let empty = Cache::new(0);
+        empty.get_or_load(&0, load(0, 0)).unwrap();
+        assert_eq!(empty.stats().entries, 0);
+    }
+
+    #[test]
+    fn index_caches() {
+        let dir = TempDir::new("cache");
+        let docs = [("a", "wall street journal"), ("b", "wall wall paper")];
+        let mut index = index(&dir, IndexBuilder::new(), &docs);
+        assert_eq!(index.postings("wall").unwrap().len(), 2);
+
+        // The second search for "wall" finds its postings cached.
+        index.search("wall paper").unwrap().for_each(drop);
+        let stats = index.postings_cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 2));
+        assert!(index.block_cache_stats().hits >= 2);
+
+        index.set_postings_cache(0);
+        assert_eq!(index.postings("wall").unwrap().len(), 2);
+        assert_eq!(index.postings_cache_stats().entries, 0);
+    }
+}
@@ -0,0 +1,695 @@
+use crate::indexing::index::Posting;
+use crate::indexing::postings::POSTINGS_BLOCK_SIZE;
+use crate::indexing::varint::{read_varint, write_varint};
+use std::io::{Error, ErrorKind};
+
+use smallvec::SmallVec;
+
+// A block's worth of integers, kept off the heap while decoding.
+type Integers = SmallVec<[u64; POSTINGS_BLOCK_SIZE]>;
+
+// How the postings in each block of a postings list are compressed. An index
+// uses the same codec for every block, and records it in its manifest.
+//
+// Every codec but `Varint` stores the document gaps of a block followed by
+// its frequencies, each as an array of integers. `Varint` interleaves them
+// instead, as indexes always have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    // One varint after another, seven bits to a byte.
+    #[default]
+    Varint,
+    // Stream VByte (Lemire et al. 2017). Two bit lengths for four integers
+    // at a time are packed into a control byte, ahead of the integers'
+    // bytes, so a decoder can work out where every integer is up front.
+    StreamVByte,
+    // OptPFor (Yan et al. 2009). Integers are bit packed at the width which
+    // makes the block smallest, and the high bits of any which don't fit
+    // are stored separately as exceptions.
+    OptPfor,
+    // Simple-8b (Anh and Moffat 2010). As many integers as fit are packed
+    // into each 64 bit word, with a 4 bit selector saying how.
+    Simple8b,
+    // Elias-Fano. The documents, and the running total of the frequencies,
+    // are stored as monotone sequences split into packed low bits and unary
+    // coded high bits.
+    EliasFano,
+    // Every integer packed at the width of the largest.
+    BitPacking,
+}
+
+impl Codec {
+    pub const ALL: [Codec; 6] = [
+        Codec::Varint,
+        Codec::StreamVByte,
+        Codec::OptPfor,
+        Codec::Simple8b,
+        Codec::EliasFano,
+        Codec::BitPacking,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Varint => "varint",
+            Codec::StreamVByte => "stream-vbyte",
+            Codec::OptPfor => "opt-pfor",
+            Codec::Simple8b => "simple-8b",
+            Codec::EliasFano => "elias-fano",
+            Codec::BitPacking => "bit-packing",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Codec> {
+        Codec::ALL.iter().copied().find(|c| c.name() == name)
+    }
+
+    // Appends a block of `postings` to `out`. Documents are stored relative
+    // to `previous`, the last document of the block before.
+    pub fn encode_block(
+        self,
+        postings: &[Posting],
+        previous: u64,
+        out: &mut Vec<u8>,
+    ) -> std::io::Result<()> {
+        if self == Codec::Varint {
+            let mut previous = previous;
+            for posting in postings {
+                write_varint(out, posting.document - previous)?;
+                write_varint(out, posting.frequency)?;
+                previous = posting.document;
+            }
+            return Ok(());
+        }
+
+        if self == Codec::EliasFano {
+            let documents = postings
+                .iter()
+                .map(|p| p.document - previous)
+                .collect::<Vec<_>>();
+            let frequencies = postings
+                .iter()
+                .scan(0, |total, p| {
+                    *total += p.frequency;
+                    Some(*total)
+                })
+                .collect::<Vec<_>>();
+
+            encode_elias_fano(&documents, out);
+            encode_elias_fano(&frequencies, out);
+            return Ok(());
+        }
+
+        let mut gaps = Vec::with_capacity(postings.len());
+        let mut last = previous;
+        for posting in postings {
+            gaps.push(posting.document - last);
+            last = posting.document;
+        }
+        let frequencies = postings.iter().map(|p| p.frequency).collect::<Vec<_>>();
+
+        self.encode_integers(&gaps, out)?;
+        self.encode_integers(&frequencies, out)
+    }
+
+    // Decodes a block of `count` postings from `bytes`, appending them to
+    // `out`. `previous` is the last document of the block before.
+    pub fn decode_block(
+        self,
+        mut bytes: &[u8],
+        count: usize,
+        previous: u64,
+        out: &mut Vec<Posting>,
+    ) -> std::io::Result<()> {
+        if self == Codec::Varint {
+            let mut previous = previous;
+            for _ in 0..count {
+                let (diff, _) = read_varint(&mut bytes)?;
+                let (frequency, _) = read_varint(&mut bytes)?;
+
+                previous += diff;
+                out.push(Posting {
+                    document: previous,
+                    frequency,
+                });
+            }
+            return Ok(());
+        }
+
+        let mut documents = Integers::new();
+        let mut frequencies = Integers::new();
+
+        if self == Codec::EliasFano {
+            decode_elias_fano(&mut bytes, count, &mut documents)?;
+            decode_elias_fano(&mut bytes, count, &mut frequencies)?;
+
+            let mut total = 0;
+            out.extend(documents.iter().zip(&frequencies).map(|(&d, &f)| {
+                let frequency = f - total;
+                total = f;
+                Posting {
+                    document: previous + d,
+                    frequency,
+                }
+            }));
+            return Ok(());
+        }
+
+        self.decode_integers(&mut bytes, count, &mut documents)?;
+        self.decode_integers(&mut bytes, count, &mut frequencies)?;
+
+        let mut document = previous;
+        out.extend(
+            documents
+                .iter()
+                .zip(&frequencies)
+                .map(|(&gap, &frequency)| {
+                    document += gap;
+                    Posting {
+                        document,
+                        frequency,
+                    }
+                }),
+        );
+
+        Ok(())
+    }
+
+    fn encode_integers(self, values: &[u64], out: &mut Vec<u8>) -> std::io::Result<()> {
+        match self {
+            Codec::StreamVByte => encode_stream_vbyte(values, out),
+            Codec::OptPfor => {
+                encode_opt_pfor(values, out);
+                Ok(())
+            }
+            Codec::Simple8b => encode_simple8b(values, out),
+            Codec::BitPacking => {
+                let width = bit_width(values.iter().copied().max().unwrap_or(0));
+                out.push(width as u8);
+                pack(values.iter().copied(), width, out);
+                Ok(())
+            }
+            Codec::Varint | Codec::EliasFano => unreachable!(),
+        }
+    }
+
+    fn decode_integers(
+        self,
+        bytes: &mut &[u8],
+        count: usize,
+        out: &mut Integers,
+    ) -> std::io::Result<()> {
+        match self {
+            Codec::StreamVByte => decode_stream_vbyte(bytes, count, out),
+            Codec::OptPfor => decode_opt_pfor(bytes, count, out),
+            Codec::Simple8b => decode_simple8b(bytes, count, out),
+            Codec::BitPacking => {
+                let width = take(bytes, 1)?[0] as u32;
+                unpack(bytes, count, width, out)
+            }
+            Codec::Varint | Codec::EliasFano => unreachable!(),
+        }
+    }
+}
+
+fn truncated() -> Error {
+    Error::new(ErrorKind::UnexpectedEof, "postings block is truncated")
+}
+
+fn too_large(value: u64, codec: Codec) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("{} is too large for {}", value, codec.name()),
+    )
+}
+
+// Splits the first `n` bytes off `bytes`.
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> std::io::Result<&'a [u8]> {
+    if bytes.len() < n {
+        return Err(truncated());
+    }
+
+    let (head, tail) = bytes.split_at(n);
+    *bytes = tail;
+    Ok(head)
+}
+
+// Number of bits needed to store `value`.
+fn bit_width(value: u64) -> u32 {
+    64 - value.leading_zeros()
+}
+
+fn varint_len(value: u64) -> usize {
+    (bit_width(value).max(1) as usize).div_ceil(7)
+}
+
+fn mask(width: u32) -> u64 {
+    if width == 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    }
+}
+
+// Packs the low `width` bits of each value, least significant bits first.
+fn pack<I: Iterator<Item = u64>>(values: I, width: u32, out: &mut Vec<u8>) {
+    let mut buffer: u128 = 0;
+    let mut bits = 0;
+
+    for value in values {
+        buffer |= ((value & mask(width)) as u128) << bits;
+        bits += width;
+
+        while bits >= 8 {
+            out.push(buffer as u8);
+            buffer >>= 8;
+            bits -= 8;
+        }
+    }
+
+    if bits > 0 {
+        out.push(buffer as u8);
+    }
+}
+
+fn unpack(bytes: &mut &[u8], count: usize, width: u32, out: &mut Integers) -> std::io::Result<()> {
+    let mut data = take(bytes, (count * width as usize).div_ceil(8))?.iter();
+    let mut buffer: u128 = 0;
+    let mut bits = 0;
+
+    for _ in 0..count {
+        while bits < width {
+            buffer |= (*data.next().unwrap() as u128) << bits;
+            bits += 8;
+        }
+
+        out.push(buffer as u64 & mask(width));
+        buffer >>= width;
+        bits -= width;
+    }
+
+    Ok(())
+}
+
+// +----------------------------+-----------------------------------+
+// | Control Bytes (count / 4)  | Integers (1 to 4 bytes each, LE)  |
+// +----------------------------+-----------------------------------+
+//
+// Each control byte holds the lengths, less one, of four integers, two bits
+// each starting from the low bits.
+fn encode_stream_vbyte(values: &[u64], out: &mut Vec<u8>) -> std::io::Result<()> {
+    let start = out.len();
+    out.resize(start + values.len().div_ceil(4), 0);
+
+    for (i, &value) in values.iter().enumerate() {
+        if value > u32::MAX as u64 {
+            return Err(too_large(value, Codec::StreamVByte));
+        }
+
+        let length = (bit_width(value).max(1) as usize).div_ceil(8);
+        out[start + i / 4] |= ((length - 1) << (2 * (i % 4))) as u8;
+        out.extend_from_slice(&(value as u32).to_le_bytes()[..length]);
+    }
+
+    Ok(())
+}
+
+fn decode_stream_vbyte(bytes: &mut &[u8], count: usize, out: &mut Integers) -> std::io::Result<()> {
+    let controls = take(bytes, count.div_ceil(4))?;
+
+    // The lengths of every integer are known before any is decoded. Unused
+    // lengths in the last control byte are left as 0, which stands for 1.
+    let total = controls
+        .iter()
+        .map(|&c| ((c & 0x55).count_ones() + 2 * (c & 0xaa).count_ones() + 4) as usize)
+        .sum::<usize>()
+        - (controls.len() * 4 - count);
+    let mut data = take(bytes, total)?;
+
+    for (i, &control) in controls.iter().enumerate() {
+        for k in 0..(count - i * 4).min(4) {
+            let length = ((control >> (2 * k)) & 3) as usize + 1;
+
+            // Loading four bytes and masking off the extra is quicker than
+            // copying a variable number, as long as there are four left.
+            let value = match data.get(..4) {
+                Some(le) => {
+                    let value = u32::from_le_bytes([le[0], le[1], le[2], le[3]]) as u64;
+                    value & mask(8 * length as u32)
+                }
+                None => {
+                    let mut le = [0; 4];
+                    le[..length].copy_from_slice(&data[..length]);
+                    u32::from_le_bytes(le) as u64
+                }
+            };
+            out.push(value);
+            data = &data[length..];
+        }
+    }
+
+    Ok(())
+}
+
+// +--------------+-----------------------------+-----------------------+
+// | Width (byte) | Exception Count (varint)    | Low Bits (packed)     |
+// +--------------+-----------------------------+-----------------------+
+// +------------------------------+-------------------------+
+// | Exception Index Diff (varint)| High Bits (varint)      | ..
+// +------------------------------+-------------------------+
+// (Exception Count times)
+//
+// The width is chosen by trying every one and keeping whichever makes the
+// block smallest, which is what sets OptPFor apart from PForDelta's fixed
+// share of exceptions.
+fn encode_opt_pfor(values: &[u64], out: &mut Vec<u8>) {
+    let cost = |width: u32| {
+        let mut bytes = (values.len() * width as usize).div_ceil(8);
+        let mut exceptions = 0;
+        let mut last = 0;
+
+        for (i, &value) in values.iter().enumerate() {
+            if width < 64 && value >> width != 0 {
+                bytes += varint_len((i - last) as u64) + varint_len(value >> width);
+                exceptions += 1;
+                last = i;
+            }
+        }
+
+        bytes + varint_len(exceptions)
+    };
+
+    let width = (0..=64).min_by_key(|&w| cost(w)).unwrap();
+    let exceptions = values
+        .iter()
+        .enumerate()
+        .filter(|&(_, &v)| width < 64 && v >> width != 0)
+        .collect::<Vec<_>>();
+
+    out.push(width as u8);
+    write_varint(out, exceptions.len() as u64).unwrap();
+    pack(values.iter().copied(), width, out);
+
+    let mut last = 0;
+    for (i, &value) in exceptions {
+        write_varint(out, (i - last) as u64).unwrap();
+        write_varint(out, value >> width).unwrap();
+        last = i;
+    }
+}
+
+fn decode_opt_pfor(bytes: &mut &[u8], count: usize, out: &mut Integers) -> std::io::Result<()> {
+    let width = take(bytes, 1)?[0] as u32;
+    let (exceptions, _) = read_varint(bytes)?;
+
+    let start = out.len();
+    unpack(bytes, count, width, out)?;
+
+    let mut index = 0;
+    for _ in 0..exceptions {
+        index += read_varint(bytes)?.0 as usize;
+        let (high, _) = read_varint(bytes)?;
+
+        let value = out.get_mut(start + index).ok_or_else(truncated)?;
+        *value |= high << width;
+    }
+
+    Ok(())
+}
+
+// The number of integers, and their width, of each Simple-8b selector. The
+// first two stand for runs of ones, which need no bits at all.
+const SELECTORS: [(usize, u32); 16] = [
+    (240, 0),
+    (120, 0),
+    (60, 1),
+    (30, 2),
+    (20, 3),
+    (15, 4),
+    (12, 5),
+    (10, 6),
+    (8, 7),
+    (7, 8),
+    (6, 10),
+    (5, 12),
+    (4, 15),
+    (3, 20),
+    (2, 30),
+    (1, 60),
+];
+
+// Each word is written little endian, with the selector in its top 4 bits
+// and the integers packed below, least significant bits first.
+fn encode_simple8b(values: &[u64], out: &mut Vec<u8>) -> std::io::Result<()> {
+    let mut i = 0;
+
+    while i < values.len() {
+        let remaining = &values[i..];
+        let (selector, &(count, width)) = SELECTORS
+            .iter()
+            .enumerate()
+            .find(|&(_, &(count, width))| {
+                count <= remaining.len()
+                    && remaining[..count].iter().all(|&v| match width {
+                        0 => v == 1,
+                        _ => v >> width == 0,
+                    })
+            })
+            .ok_or_else(|| too_large(remaining[0], Codec::Simple8b))?;
+
+        let mut word = (selector as u64) << 60;
+        if width > 0 {
+            for (j, &value) in remaining[..count].iter().enumerate() {
+                word |= value << (j as u32 * width);
+            }
+        }
+
+        out.extend_from_slice(&word.to_le_bytes());
+        i += count;
+    }
+
+    Ok(())
+}
+
+fn decode_simple8b(bytes: &mut &[u8], count: usize, out: &mut Integers) -> std::io::Result<()> {
+    let mut decoded = 0;
+
+    while decoded < count {
+        let mut le = [0; 8];
+        le.copy_from_slice(take(bytes, 8)?);
+        let word = u64::from_le_bytes(le);
+
+        let (n, width) = SELECTORS[(word >> 60) as usize];
+        if width == 0 {
+            out.extend(std::iter::repeat_n(1, n));
+        } else {
+            out.extend((0..n as u32).map(|j| (word >> (j * width)) & mask(width)));
+        }
+        decoded += n;
+    }
+
+    if decoded != count {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "simple-8b block holds the wrong number of integers",
+        ));
+    }
+
+    Ok(())
+}
+
+// +------------------+-----------------------+------------------------+
+// | Low Width (byte) | Low Bits (packed)     | High Bits (unary)      |
+// +------------------+-----------------------+------------------------+
+//
+// `values` must be non-decreasing. The high bits of value `i` set bit
+// `(value >> width) + i` of the high bit array, so a decoder counts the
+// unset bits before each set bit to recover them.
+fn encode_elias_fano(values: &[u64], out: &mut Vec<u8>) {
+    let last = match values.last() {
+        Some(&last) => last,
+        None => return,
+    };
+
+    let n = values.len() as u64;
+    let universe = last + 1;
+    let width = if universe > n {
+        bit_width(universe / n) - 1
+    } else {
+        0
+    };
+
+    out.push(width as u8);
+    pack(values.iter().copied(), width, out);
+
+    let start = out.len();
+    out.resize(start + ((n + (last >> width)) as usize).div_ceil(8), 0);
+    for (i, &value) in values.iter().enumerate() {
+        let bit = (value >> width) as usize + i;
+        out[start + bit / 8] |= 1 << (bit % 8);
+    }
+}
+
+fn decode_elias_fano(bytes: &mut &[u8], count: usize, out: &mut Integers) -> std::io::Result<()> {
+    if count == 0 {
+        return Ok(());
+    }
+
+    let width = take(bytes, 1)?[0] as u32;
+    let start = out.len();
+    unpack(bytes, count, width, out)?;
+
+    let mut i = 0;
+    let mut used = 0;
+    for (byte, &bits) in bytes.iter().enumerate() {
+        let mut bits = bits;
+        while bits != 0 && i < count {
+            let bit = byte * 8 + bits.trailing_zeros() as usize;
+            out[start + i] |= ((bit - i) as u64) << width;
+            bits &= bits - 1;
+            i += 1;
+        }
+
+        if i == count {
+            used = byte + 1;
+            break;
+        }
+    }
+
+    if i < count {
+        return Err(truncated());
+    }
+
+    take(bytes, used)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::builder::IndexBuilder;
+    use crate::indexing::index::DiskIndex;
+    use crate::indexing::manifest::Manifest;
+    use crate::indexing::merge::force_merge;
+    use crate::indexing::testing::TempDir;
+    use std::path::Path;
+
+    #[test]
+    fn round_trip() {
+        let blocks = vec![
+            vec![(0, 1), (1, 1), (2, 3), (10, 1)],
+            (0..128).map(|i| (i * i * 37 + 5, 1 + i % 3)).collect(),
+            vec![(7, 1); 1],
+            vec![(3, 200), (70000, 1), (70001, 123456), (4000000, 2)],
+            // Long runs of ones, and an outlier for PFor.
+            (1..=128)
+                .map(|i| (i, if i == 64 { 1 << 20 } else { 1 }))
+                .collect(),
+        ];
+
+        for codec in Codec::ALL {
+            assert_eq!(Codec::from_name(codec.name()), Some(codec));
+
+            for block in &blocks {
+                let postings = block
+                    .iter()
+                    .map(|&(document, frequency)| Posting {
+                        document: document + 100,
+                        frequency,
+                    })
+                    .collect::<Vec<_>>();
+
+                let mut bytes = Vec::new();
+                codec.encode_block(&postings, 100, &mut bytes).unwrap();
+
+                let mut decoded = Vec::new();
+                codec
+                    .decode_block(&bytes, postings.len(), 100, &mut decoded)
+                    .unwrap();
+                assert_eq!(decoded, postings, "{}", codec.name());
+
+                // Truncated blocks are errors, rather than panics.
+                let mut decoded = Vec::new();
+                let truncated = &bytes[..bytes.len() - 1];
+                assert!(codec
+                    .decode_block(truncated, postings.len(), 100, &mut decoded)
+                    .is_err());
+            }
+        }
+
+        assert_eq!(Codec::from_name("gzip"), None);
+
+        let huge = [Posting {
+            document: 1 << 40,
+            frequency: 1,
+        }];
+        assert!(Codec::StreamVByte
+            .encode_block(&huge, 0, &mut Vec::new())
+            .is_err());
+    }
+
+    #[test]
+    fn indexes() {
+        let root = TempDir::new("codecs");
+
+        // Enough documents that common terms span several blocks.
+        let docs = (0..700u64)
+            .map(|n| {
+                let mut terms = vec!["every".to_string(), format!("t{}", n % 11)];
+                terms.extend((0..n % 5).map(|t| format!("t{}", (n * 7 + t) % 53)));
+                terms.push("every".to_string());
+                (format!("doc{}", n), terms)
+            })
+            .collect::<Vec<_>>();
+
+        let build = |codec, dir: &Path| {
+            let (first, second) = docs.split_at(400);
+            for (i, half) in [first, second].iter().enumerate() {
+                let mut builder = IndexBuilder::new().positions(true).codec(codec);
+                for (name, terms) in half.iter() {
+                    builder.add_document(name, terms).unwrap();
+                }
+                if i == 0 {
+                    builder.write(dir).unwrap();
+                } else {
+                    builder.write_segment(dir).unwrap();
+                }
+            }
+        };
+
+        build(Codec::Varint, &root.join("varint"));
+        let expected = DiskIndex::from_disk(root.join("varint")).unwrap();
+
+        for codec in Codec::ALL {
+            let dir = root.join(codec.name());
+            build(codec, &dir);
+            force_merge(&dir).unwrap();
+            assert_eq!(Manifest::read(&dir).unwrap().codec, codec);
+
+            let index = DiskIndex::from_disk(&dir).unwrap();
+            for term in ["every", "t3", "t52", "missing"] {
+                assert_eq!(
+                    index.postings(term).unwrap(),
+                    expected.postings(term).unwrap(),
+                    "{} {}",
+                    codec.name(),
+                    term
+                );
+            }
+
+            let phrase = ["t3".to_string(), "every".to_string()];
+            assert_eq!(
+                index.phrase(&phrase).unwrap(),
+                expected.phrase(&phrase).unwrap()
+            );
+        }
+
+        // Appending keeps the codec the index was created with.
+        let dir = root.join("append");
+        build(Codec::EliasFano, &dir);
+        IndexBuilder::new()
+            .codec(Codec::Simple8b)
+            .write_segment(&dir)
+            .unwrap();
+        assert_eq!(Manifest::read(&dir).unwrap().codec, Codec::EliasFano);
+    }
+}
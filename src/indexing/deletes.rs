@@ -0,0 +1,189 @@
+use crate::indexing::manifest::Manifest;
+use crate::indexing::segment::read_segment_documents;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+// The documents deleted from a single segment, as a bitmap with one bit per
+// document of the segment (the lowest bit of the first byte is document 0).
+//
+// Segments are never rewritten to drop a document. Instead, a new bitmap is
+// written alongside the segment and the manifest is pointed at it, and search
+// skips the documents it marks. Their postings are only reclaimed once the
+// segment is merged.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Deletes {
+    bits: Vec<u8>,
+    count: u64,
+}
+
+impl Deletes {
+    // An empty bitmap for a segment of `documents` documents.
+    pub fn new(documents: u64) -> Self {
+        Deletes {
+            bits: vec![0; documents.div_ceil(8) as usize],
+            count: 0,
+        }
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> std::io::Result<Deletes> {
+        let bits = fs::read(path)?;
+        let count = bits.iter().map(|b| b.count_ones() as u64).sum();
+
+        Ok(Deletes { bits, count })
+    }
+
+    // Reads the bitmap the manifest lists for segment `segment`, or an empty
+    // one if nothing in it has been deleted.
+    pub fn for_segment(
+        dir: &Path,
+        manifest: &Manifest,
+        segment: usize,
+    ) -> std::io::Result<Deletes> {
+        let info = &manifest.segments[segment];
+
+        match &info.deletes {
+            Some(file) => Deletes::read(dir.join(&info.name).join(file)),
+            None => Ok(Deletes::new(info.documents)),
+        }
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        fs::write(path, &self.bits)
+    }
+
+    // Marks document `doc` as deleted. Returns false if it already was.
+    pub fn delete(&mut self, doc: u64) -> bool {
+        let (byte, bit) = ((doc / 8) as usize, doc % 8);
+
+        if self.bits[byte] & (1 << bit) != 0 {
+            return false;
+        }
+
+        self.bits[byte] |= 1 << bit;
+        self.count += 1;
+        true
+    }
+
+    pub fn contains(&self, doc: u64) -> bool {
+        self.bits
+            .get((doc / 8) as usize)
+            .is_some_and(|b| b & (1 << (doc % 8)) != 0)
+    }
+
+    // Number of deleted documents.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
+// Deletes every document named in `names` from the index in `dir`. Returns
+// the number of documents deleted.
+pub fn delete_documents<P, I, S>(dir: P, names: I) -> std::io::Result<usize>
+where
+    P: AsRef<Path>,
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    let dir = dir.as_ref();
+    let names = names.into_iter().map(Into::into).collect::<HashSet<_>>();
+
+    let mut manifest = Manifest::read(dir)?;
+    manifest.verify(dir)?;
+
+    let (deleted, stale) = mark_deleted(dir, &mut manifest, &names)?;
+    if deleted > 0 {
+        manifest.write(dir)?;
+        remove_stale(dir, stale)?;
+    }
+
+    Ok(deleted)
+}
+
+// Writes new bitmaps for every segment of `manifest` holding a document named
+// in `names`, and points the manifest at them. Nothing changes on disk until
+// the manifest is written, after which the bitmaps it no longer refers to
+// (returned as segment and file name pairs) can be removed.
+pub(crate) fn mark_deleted(
+    dir: &Path,
+    manifest: &mut Manifest,
+    names: &HashSet<String>,
+) -> std::io::Result<(usize, Vec<(String, String)>)> {
+    let mut deleted = 0;
+    let mut stale = Vec::new();
+
+    if names.is_empty() {
+        return Ok((deleted, stale));
+    }
+
+    for i in 0..manifest.segments.len() {
+        let mut deletes = Deletes::for_segment(dir, manifest, i)?;
+        let segment_dir = dir.join(&manifest.segments[i].name);
+        let before = deletes.count();
+
+        for (doc, document) in read_segment_documents(&segment_dir)?.iter().enumerate() {
+            if names.contains(&document.name) {
+                deletes.delete(doc as u64);
+            }
+        }
+
+        if deletes.count() == before {
+            continue;
+        }
+        deleted += (deletes.count() - before) as usize;
+
+        let file = format!("deletes-{:06}.bin", manifest.next_generation());
+        deletes.write(segment_dir.join(&file))?;
+
+        let info = &mut manifest.segments[i];
+        if let Some(old) = info.deletes.replace(file.clone()) {
+            info.files.retain(|(name, _)| *name != old);
+            stale.push((info.name.clone(), old));
+        }
+        info.deleted = deletes.count();
+        info.add(dir, &file)?;
+    }
+
+    Ok((deleted, stale))
+}
+
+// Removes bitmaps replaced by `mark_deleted`.
+pub(crate) fn remove_stale(dir: &Path, stale: Vec<(String, String)>) -> std::io::Result<()> {
+    for (segment, file) in stale {
+        fs::remove_file(dir.join(segment).join(file))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::testing::TempDir;
+
+    #[test]
+    fn bitmap() {
+        let dir = TempDir::new("bitmap");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("deletes.bin");
+
+        let mut deletes = Deletes::new(20);
+        assert!(deletes.delete(0));
+        assert!(deletes.delete(9));
+        assert!(deletes.delete(19));
+        assert!(!deletes.delete(9));
+        assert_eq!(deletes.count(), 3);
+
+        deletes.write(&path).unwrap();
+        let read = Deletes::read(&path).unwrap();
+        assert_eq!(read, deletes);
+        assert_eq!(
+            (0..20).filter(|&d| read.contains(d)).collect::<Vec<_>>(),
+            vec![0, 9, 19]
+        );
+        assert!(!read.contains(100));
+    }
+}
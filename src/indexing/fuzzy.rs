@@ -0,0 +1,235 @@
+// Fuzzy matching of terms. A Levenshtein automaton accepts the strings within
+// a number of edits (insertions, deletions and substitutions of a character)
+// of a term. Its states are rows of the edit distance table between the term
+// and the characters read so far, capped just past the distance allowed, so
+// a state whose every entry is past it can never lead to a match.
+pub struct Levenshtein {
+    term: Vec<char>,
+    distance: u32,
+}
+
+impl Levenshtein {
+    pub fn new(term: &str, distance: u32) -> Levenshtein {
+        Levenshtein {
+            term: term.chars().collect(),
+            distance,
+        }
+    }
+
+    fn start(&self) -> Vec<u32> {
+        (0..=self.term.len() as u32)
+            .map(|i| i.min(self.distance + 1))
+            .collect()
+    }
+
+    fn step(&self, state: &[u32], c: char) -> Vec<u32> {
+        let mut next = Vec::with_capacity(state.len());
+        next.push((state[0] + 1).min(self.distance + 1));
+
+        for (i, &t) in self.term.iter().enumerate() {
+            let substitute = state[i] + if t == c { 0 } else { 1 };
+            let edits = substitute.min(state[i + 1] + 1).min(next[i] + 1);
+            next.push(edits.min(self.distance + 1));
+        }
+
+        next
+    }
+
+    fn can_match(&self, state: &[u32]) -> bool {
+        state.iter().any(|&edits| edits <= self.distance)
+    }
+
+    // Walks the automaton over terms given in sorted order.
+    pub fn walker(&self) -> Walker<'_> {
+        Walker {
+            automaton: self,
+            previous: Vec::new(),
+            states: vec![self.start()],
+        }
+    }
+}
+
+// Runs a Levenshtein automaton over a sorted list of terms. Neighbouring
+// terms share long prefixes, so the state reached after each character of
+// the previous term is kept, and only the characters after the prefix the
+// two terms share are fed to the automaton. Once a prefix leads to a state
+// which can't match, every following term starting with it is rejected
+// without stepping at all.
+pub struct Walker<'a> {
+    automaton: &'a Levenshtein,
+    previous: Vec<char>,
+    // The state after each character of `previous`, starting with the start
+    // state. Stops early at a state which can't match.
+    states: Vec<Vec<u32>>,
+}
+
+impl Walker<'_> {
+    // Returns the number of edits between `term` and the automaton's term,
+    // if it's within the distance allowed.
+    pub fn edits(&mut self, term: &str) -> Option<u32> {
+        let chars = term.chars().collect::<Vec<_>>();
+        let common = self
+            .previous
+            .iter()
+            .zip(&chars)
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        self.states.truncate(common + 1);
+        self.previous = chars;
+
+        loop {
+            let state = self.states.last().unwrap();
+            if !self.automaton.can_match(state) {
+                return None;
+            }
+
+            let read = self.states.len() - 1;
+            if read == self.previous.len() {
+                let edits = *state.last().unwrap();
+                return Some(edits).filter(|&edits| edits <= self.automaton.distance);
+            }
+
+            let next = self.automaton.step(state, self.previous[read]);
+            self.states.push(next);
+        }
+    }
+}
+
+// The weight of a term found `edits` edits away from `term`, which falls
+// from 1 for the term itself towards 0 as the edits make up more of the
+// shorter of the two.
+pub fn penalty(term: &str, found: &str, edits: u32) -> f32 {
+    let length = term.chars().count().min(found.chars().count());
+    1.0 - edits as f32 / length.max(1) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::builder::IndexBuilder;
+    use crate::indexing::deletes::delete_documents;
+    use crate::indexing::index::DiskIndex;
+    use crate::indexing::testing::{ranked, TempDir};
+    use crate::searching::query::Fuzzy;
+
+    #[test]
+    fn walk() {
+        let automaton = Levenshtein::new("merger", 2);
+        let mut walker = automaton.walker();
+
+        let mut terms = vec![
+            "emerge",
+            "margin",
+            "marker",
+            "merge",
+            "merged",
+            "merger",
+            "mergers",
+            "mrgr",
+            "verger",
+            "xylophone",
+            "xylophones",
+        ];
+        terms.sort();
+
+        let found = terms
+            .iter()
+            .filter_map(|term| walker.edits(term).map(|edits| (*term, edits)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                ("emerge", 2),
+                ("marker", 2),
+                ("merge", 1),
+                ("merged", 1),
+                ("merger", 0),
+                ("mergers", 1),
+                ("mrgr", 2),
+                ("verger", 1),
+            ]
+        );
+
+        // The same automaton, walked afresh, with one edit allowed.
+        let automaton = Levenshtein::new("merger", 1);
+        let mut walker = automaton.walker();
+        assert_eq!(walker.edits("emerge"), None);
+        assert_eq!(walker.edits("mergers"), Some(1));
+        assert_eq!(walker.edits("mergr"), Some(1));
+
+        assert_eq!(penalty("merger", "merger", 0), 1.0);
+        assert_eq!(penalty("merger", "merge", 1), 0.8);
+    }
+
+    #[test]
+    fn fuzzy_queries() {
+        let dir = TempDir::new("fuzzy");
+
+        let mut builder = IndexBuilder::new();
+        builder.add_document("a", ["mitsubishi", "motors"]).unwrap();
+        builder.add_document("b", ["mitsubshi", "bank"]).unwrap();
+        builder.add_document("c", ["mitsui", "trading"]).unwrap();
+        builder.add_document("d", ["mitsubishi", "estate"]).unwrap();
+        builder.write(&dir).unwrap();
+
+        let mut index = DiskIndex::from_disk(&dir).unwrap();
+        let fuzzy = |term: &str, distance| Fuzzy {
+            term: term.to_string(),
+            distance,
+        };
+        assert_eq!(
+            index.fuzzy(&fuzzy("mitsubishi", 1)).unwrap(),
+            vec![("mitsubishi".to_string(), 0), ("mitsubshi".to_string(), 1)]
+        );
+        assert_eq!(
+            index.fuzzy(&fuzzy("mitsu", 2)).unwrap(),
+            vec![("mitsui".to_string(), 1)]
+        );
+
+        // The misspelling matches, but scores less than the term itself,
+        // even though it's rarer.
+        assert_eq!(ranked(&index, "mitsubishi~1"), vec!["a", "d", "b"]);
+        assert_eq!(
+            ranked(&index, "mitsubishi~1 AND NOT motors"),
+            vec!["d", "b"]
+        );
+
+        index.set_limit(Some(3));
+        assert_eq!(ranked(&index, "mitsubshi~"), vec!["b", "a", "d"]);
+
+        index.set_expansions(1);
+        assert_eq!(ranked(&index, "mitsubishi~1"), vec!["a", "d"]);
+    }
+
+    #[test]
+    fn deleted_documents() {
+        let dir = TempDir::new("fuzzy-deletes");
+
+        let mut builder = IndexBuilder::new();
+        builder.add_document("p", ["toyota"]).unwrap();
+        builder.add_document("q", ["toyota"]).unwrap();
+        builder.add_document("r", ["toyote"]).unwrap();
+        builder.write(&dir).unwrap();
+
+        let fuzzy = Fuzzy {
+            term: "toyotx".to_string(),
+            distance: 1,
+        };
+        let mut index = DiskIndex::from_disk(&dir).unwrap();
+        index.set_expansions(1);
+        assert_eq!(
+            index.fuzzy(&fuzzy).unwrap(),
+            vec![("toyota".to_string(), 1)]
+        );
+
+        // Deleted documents don't count, so "toyote" is now held by more.
+        delete_documents(&dir, ["p", "q"]).unwrap();
+        let mut index = DiskIndex::from_disk(&dir).unwrap();
+        index.set_expansions(1);
+        assert_eq!(
+            index.fuzzy(&fuzzy).unwrap(),
+            vec![("toyote".to_string(), 1)]
+        );
+    }
+}
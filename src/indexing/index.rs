@@ -1,121 +1,1486 @@
-use super::super::parsing::terms::Terms;
+use crate::indexing::cache::{Cache, CacheStats};
+use crate::indexing::codec::Codec;
+use crate::indexing::deletes::Deletes;
+use crate::indexing::fuzzy::{penalty, Levenshtein};
+use crate::indexing::manifest::Manifest;
+use crate::indexing::postings::{
+    block_count, Cursor, DecodedCursor, PostingsCursor, POSTINGS_BLOCK_SIZE,
+};
+use crate::indexing::segment::BLOCK_SIZE;
+use crate::indexing::varint::{read_varint, write_varint};
+use crate::indexing::wildcard::{self, KGrams};
+use crate::parsing::analyzer::Analyzer;
+use crate::parsing::field::Field;
+use crate::searching::bm25f::Bm25f;
+use crate::searching::options::SearchOptions;
+use crate::searching::proximity::{term_proximity, window_matches};
+use crate::searching::query::{Expr, Fuzzy, Query, Window};
+use crate::searching::scorer::{Bm25, Collection, Idf, Scorer, TermStats};
+use crate::searching::synonyms::Thesaurus;
+use crate::searching::topk::{max_score, TopK};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use memmap2::Mmap;
+use smallvec::SmallVec;
+
+// Component files of an index directory.
+pub const DOCUMENTS_FILE: &str = "documents.bin";
+pub const POSTINGS_FILE: &str = "postings.bin";
+pub const BLOCKS_FILE: &str = "blocks.bin";
+pub const INDEX_FILE: &str = "index.bin";
+// Only written when positions are kept.
+pub const POSITIONS_FILE: &str = "positions.bin";
+// Only written when fields are kept.
+pub const FIELDS_FILE: &str = "fields.bin";
+
+// Default capacity, in bytes, of the cache of dictionary blocks and of the
+// cache of postings lists.
+pub const DEFAULT_BLOCK_CACHE: usize = 64 * 1024 * 1024;
+pub const DEFAULT_POSTINGS_CACHE: usize = 64 * 1024 * 1024;
+
+// Default number of terms a wildcard pattern expands to, at most.
+pub const DEFAULT_EXPANSIONS: usize = 128;
+
+// Suggested corrections are at most this many edits away from the term.
+const SUGGESTION_EDITS: u32 = 2;
+
+// A term is only corrected to one held by more than this many times as many
+// documents, so a rare but correctly spelled term is left alone unless
+// something very like it is far more common.
+const SUGGESTION_RATIO: u64 = 10;
+
+pub(crate) struct DiskDocument {
+    pub(crate) term_count: u64,
+    pub(crate) name: SmallVec<[u8; 32]>,
+}
+
+pub struct DiskIndex {
+    segments: Vec<Segment>,
+
+    // Loaded from disk immediately
+    // Documents of every segment, in global document order.
+    docs: Vec<DiskDocument>,
+    // Number of documents which haven't been deleted.
+    live: usize,
+    avg_dl: f32,
+    // Total length of the live documents.
+    total_dl: u64,
+    // Only present if every segment kept fields.
+    fields: Option<FieldLengths>,
+
+    // Decoded on an as-needed basis during search, and shared by every
+    // thread searching the index. Keyed by segment, and then by root entry
+    // or by the offset of the postings.
+    blocks: BlockCache,
+    lists: Cache<(usize, u64), Vec<Posting>>,
+
+    scorer: Box<dyn Scorer>,
+    proximity: bool,
+    bm25f: Option<Bm25f>,
+    // Number of documents returned by a search, if limited.
+    limit: Option<usize>,
+    // Number of terms a wildcard pattern expands to, at most.
+    expansions: usize,
+    // Expands the words and phrases of queries, if set.
+    synonyms: Option<Thesaurus>,
+}
+
+// The length of each field of every document, for BM25F.
+struct FieldLengths {
+    // Indexed by global document ID.
+    lengths: Vec<[u64; Field::COUNT]>,
+    // Taken over live documents, like `avg_dl`.
+    averages: [f32; Field::COUNT],
+}
+
+// A single segment of the index. Its postings refer to documents by their
+// position within the segment, so `base` is added to turn them into global
+// document IDs.
+struct Segment {
+    // Mapped into memory, so postings and dictionary blocks are decoded
+    // straight out of the page cache.
+    post_map: Mmap,
+    blocks_map: Mmap,
+    positions_map: Option<Mmap>,
+    codec: Codec,
+    root: Vec<(String, u64)>,
+    // Position of the segment within the index, which tells its cache
+    // entries apart from those of other segments.
+    id: usize,
+    base: u64,
+    documents: usize,
+    deletes: Deletes,
+    // Built from the dictionary the first time a wildcard needs it.
+    kgrams: OnceLock<KGrams>,
+}
+
+// A block of the dictionary: each term, the offset of its postings and its
+// bound.
+type DictionaryBlock = Vec<(String, u64, TermBound)>;
+
+type BlockCache = Cache<(usize, usize), DictionaryBlock>;
+
+// Statistics kept alongside each term in the dictionary, which bound the
+// score of any of the term's postings under a scorer which grows with the
+// frequency of a term and shrinks with the length of a document.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TermBound {
+    // The highest frequency of the term in any document.
+    pub max_frequency: u64,
+    // The length of the shortest document holding the term.
+    pub min_length: u64,
+}
+
+impl TermBound {
+    // Bounds a whole postings list, given the length of each document.
+    pub fn of(postings: &[Posting], lengths: impl Fn(u64) -> u64) -> TermBound {
+        TermBound {
+            max_frequency: postings.iter().map(|p| p.frequency).max().unwrap_or(0),
+            min_length: postings
+                .iter()
+                .map(|p| lengths(p.document))
+                .min()
+                .unwrap_or(0),
+        }
+    }
+
+    // The looser bound of the two.
+    pub fn union(self, other: TermBound) -> TermBound {
+        TermBound {
+            max_frequency: self.max_frequency.max(other.max_frequency),
+            min_length: self.min_length.min(other.min_length),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Posting {
+    pub document: u64,
+    pub frequency: u64,
+}
+
+pub struct Document {
+    pub term_count: u64,
+    pub name: String,
+    // Number of terms within each field, in `Field::ALL` order. Left empty
+    // unless fields are kept.
+    pub fields: Vec<u64>,
+}
+
+impl DiskIndex {
+    // Opens the index stored in the directory `dir`. The manifest is checked
+    // first so that a partially written index is rejected up front.
+    pub fn from_disk<P: AsRef<Path>>(dir: P) -> std::io::Result<DiskIndex> {
+        let dir = dir.as_ref();
+
+        let manifest = Manifest::read(dir)?;
+        manifest.verify(dir)?;
+
+        let mut docs = Vec::with_capacity(manifest.documents() as usize);
+        let mut segments = Vec::with_capacity(manifest.segments.len());
+
+        for (i, info) in manifest.segments.iter().enumerate() {
+            let base = docs.len() as u64;
+            let deletes = Deletes::for_segment(dir, &manifest, i)?;
+            segments.push(Segment::open(
+                dir.join(&info.name),
+                i,
+                base,
+                deletes,
+                manifest.codec,
+                &mut docs,
+            )?);
+        }
+
+        // The average document length is taken over the whole collection
+        // rather than per segment, and deleted documents don't count towards
+        // it. Scores are then the same before and after the deleted documents
+        // are merged away.
+        let mut live = 0;
+        let mut total = 0;
+        for segment in &segments {
+            let end = segment.base as usize + segment.documents;
+            for (doc, document) in docs[segment.base as usize..end].iter().enumerate() {
+                if !segment.deletes.contains(doc as u64) {
+                    live += 1;
+                    total += document.term_count;
+                }
+            }
+        }
+
+        let avg_dl = if live == 0 {
+            0.0
+        } else {
+            total as f32 / live as f32
+        };
+
+        let fields = FieldLengths::read(dir, &manifest, &segments)?;
+
+        Ok(DiskIndex {
+            segments,
+            docs,
+            live,
+            avg_dl,
+            total_dl: total,
+            fields,
+            blocks: Cache::new(DEFAULT_BLOCK_CACHE),
+            lists: Cache::new(DEFAULT_POSTINGS_CACHE),
+            scorer: Box::new(Bm25::default()),
+            proximity: false,
+            bm25f: None,
+            limit: None,
+            expansions: DEFAULT_EXPANSIONS,
+            synonyms: None,
+        })
+    }
+
+    // Returns the set of postings for a given `term`, across every segment.
+    // Deleted documents are left out. Each segment's postings are decoded
+    // once and then kept in the postings cache, for as long as it has room.
+    pub fn postings(&self, term: &str) -> std::io::Result<Vec<Posting>> {
+        let mut postings = Vec::new();
+
+        for segment in self.segments.iter() {
+            let ptr = match segment.lookup(term, &self.blocks)? {
+                Some((ptr, _)) => ptr,
+                None => continue,
+            };
+
+            let segment_postings = self.lists.get_or_load(&(segment.id, ptr), || {
+                let mut postings = Vec::new();
+                segment.postings(ptr, &mut postings, None)?;
+
+                let size = postings.len() * std::mem::size_of::<Posting>();
+                Ok((postings, size))
+            })?;
+
+            postings.extend(
+                segment_postings
+                    .iter()
+                    .filter(|p| !segment.deletes.contains(p.document))
+                    .map(|p| Posting {
+                        document: p.document + segment.base,
+                        frequency: p.frequency,
+                    }),
+            );
+        }
+
+        Ok(postings)
+    }
+
+    // Returns the bound of `term` across every segment, or None if no segment
+    // holds it. Deleted documents still count towards the bound.
+    pub fn bound(&self, term: &str) -> std::io::Result<Option<TermBound>> {
+        let mut bound: Option<TermBound> = None;
+
+        for segment in self.segments.iter() {
+            if let Some((_, b)) = segment.lookup(term, &self.blocks)? {
+                bound = Some(bound.map_or(b, |bound| bound.union(b)));
+            }
+        }
+
+        Ok(bound)
+    }
+
+    // Expands the wildcard `pattern` into the terms it matches in any
+    // segment, in order. Only the `expansions` terms held by the most live
+    // documents are kept.
+    pub fn expand(&self, pattern: &str) -> std::io::Result<Vec<String>> {
+        // Term -> number of live documents holding it
+        let mut found: BTreeMap<String, u64> = BTreeMap::new();
+
+        for segment in self.segments.iter() {
+            for (term, ptr) in segment.wildcard(pattern, &self.blocks)? {
+                *found.entry(term).or_insert(0) += segment.stats(ptr)?.documents;
+            }
+        }
+
+        let mut terms = found.into_iter().collect::<Vec<_>>();
+        if terms.len() > self.expansions {
+            terms.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            terms.truncate(self.expansions);
+            terms.sort();
+        }
+
+        Ok(terms.into_iter().map(|(term, _)| term).collect())
+    }
+
+    // Returns a posting for every document holding any of the terms
+    // `pattern` expands to, with their frequencies summed, so the pattern can
+    // be scored as a single term.
+    pub fn pattern(&self, pattern: &str) -> std::io::Result<Vec<Posting>> {
+        let mut postings = Vec::new();
+        for term in self.expand(pattern)? {
+            postings.extend(self.postings(&term)?);
+        }
+        postings.sort_by_key(|p| p.document);
+
+        let mut merged: Vec<Posting> = Vec::with_capacity(postings.len());
+        for posting in postings {
+            match merged.last_mut() {
+                Some(last) if last.document == posting.document => {
+                    last.frequency += posting.frequency
+                }
+                _ => merged.push(posting),
+            }
+        }
+
+        Ok(merged)
+    }
+
+    // Returns the terms within `fuzzy.distance` edits of `fuzzy.term` in any
+    // segment, in order, with their number of edits. Terms so short that the
+    // edits would make up all of them are left out. Only the `expansions`
+    // closest terms are kept, and of those equally close, the ones held by
+    // the most live documents.
+    pub fn fuzzy(&self, fuzzy: &Fuzzy) -> std::io::Result<Vec<(String, u32)>> {
+        // Term -> edits, number of live documents holding it
+        let mut found: BTreeMap<String, (u32, u64)> = BTreeMap::new();
+
+        for segment in self.segments.iter() {
+            for (term, ptr, edits) in segment.fuzzy(&fuzzy.term, fuzzy.distance, &self.blocks)? {
+                if penalty(&fuzzy.term, &term, edits) <= 0.0 {
+                    continue;
+                }
+
+                found.entry(term).or_insert((edits, 0)).1 += segment.stats(ptr)?.documents;
+            }
+        }
+
+        let mut terms = found.into_iter().collect::<Vec<_>>();
+        if terms.len() > self.expansions {
+            terms.sort_by(|a, b| {
+                (a.1 .0)
+                    .cmp(&b.1 .0)
+                    .then(b.1 .1.cmp(&a.1 .1))
+                    .then_with(|| a.0.cmp(&b.0))
+            });
+            terms.truncate(self.expansions);
+            terms.sort();
+        }
+
+        Ok(terms
+            .into_iter()
+            .map(|(term, (edits, _))| (term, edits))
+            .collect())
+    }
+
+    // Number of live documents holding `term`.
+    pub fn document_frequency(&self, term: &str) -> std::io::Result<u64> {
+        Ok(self.term_stats(term)?.documents)
+    }
+
+    // Suggests a correction for a term which few or no live documents hold:
+    // the closest term within a couple of edits held by far more of them,
+    // and of those equally close, the one held by the most. Candidates are
+    // found with the k-gram index of each segment. A term scoped to a field
+    // is only corrected to another term of the field.
+    pub fn suggest(&self, term: &str) -> std::io::Result<Option<String>> {
+        let held = self.document_frequency(term)?;
+
+        // Term -> edits, number of live documents holding it
+        let mut found: BTreeMap<String, (u32, u64)> = BTreeMap::new();
+
+        for segment in self.segments.iter() {
+            for (similar, ptr, edits) in segment.similar(term, SUGGESTION_EDITS, &self.blocks)? {
+                if edits == 0 || penalty(term, &similar, edits) <= 0.0 {
+                    continue;
+                }
+
+                found.entry(similar).or_insert((edits, 0)).1 += segment.stats(ptr)?.documents;
+            }
+        }
+
+        // Ties go to the first term in order.
+        Ok(found
+            .into_iter()
+            .filter(|(_, (_, documents))| *documents > held * SUGGESTION_RATIO)
+            .min_by(|a, b| (a.1 .0).cmp(&b.1 .0).then(b.1 .1.cmp(&a.1 .1)))
+            .map(|(term, _)| term))
+    }
+
+    // Replaces the fuzzy terms of `query` with the terms they match, each
+    // weighted down by how many edits away it is. The terms a fuzzy term
+    // matches are all scored with the statistics of the one held by the most
+    // documents, as otherwise the rarest would score the highest.
+    //
+    // Unless the query is verbatim, each term and phrase is then joined by
+    // its synonyms, weighted by the thesaurus. A synonym of several terms is
+    // matched as a phrase, or as a bag of its terms if the index has no
+    // positions.
+    fn resolve(&self, query: &Query) -> std::io::Result<Query> {
+        let mut resolved = Query {
+            fuzzy: Vec::new(),
+            ..query.clone()
+        };
+        resolved.boosts.resize(resolved.terms.len(), 1.0);
+        resolved.stats.resize(resolved.terms.len(), None);
+
+        for fuzzy in &query.fuzzy {
+            let terms = self.fuzzy(fuzzy)?;
+
+            let mut stats = TermStats {
+                documents: 0,
+                frequency: 0,
+            };
+            for (term, _) in &terms {
+                let s = self.term_stats(term)?;
+                if s.documents > stats.documents {
+                    stats = s;
+                }
+            }
+
+            for (term, edits) in terms {
+                resolved.boosts.push(penalty(&fuzzy.term, &term, edits));
+                resolved.stats.push(Some(stats));
+                resolved.terms.push(term);
+            }
+        }
+
+        let thesaurus = match &self.synonyms {
+            Some(thesaurus) if !query.verbatim => thesaurus,
+            _ => return Ok(resolved),
+        };
+        let weight = thesaurus.synonym_weight();
+
+        let originals = query
+            .terms
+            .iter()
+            .map(std::slice::from_ref)
+            .chain(query.phrases.iter().map(Vec::as_slice));
+
+        // Phrases go after every term, as that's the order of their postings.
+        let mut phrases = Vec::new();
+        for original in originals {
+            for synonym in synonyms(thesaurus, original) {
+                if synonym.len() > 1 && self.has_positions() {
+                    phrases.push(synonym);
+                    continue;
+                }
+                for term in synonym {
+                    resolved.boosts.push(weight);
+                    resolved.stats.push(None);
+                    resolved.terms.push(term);
+                }
+            }
+        }
+
+        resolved
+            .boosts
+            .resize(resolved.terms.len() + resolved.phrases.len(), 1.0);
+        for phrase in phrases {
+            resolved.boosts.push(weight);
+            resolved.phrases.push(phrase);
+        }
+
+        resolved.filter = query
+            .filter
+            .as_ref()
+            .map(|filter| self.expand_synonyms(thesaurus, filter));
+
+        Ok(resolved)
+    }
+
+    // Replaces each term and phrase of `expr` with the group of it or any of
+    // its synonyms.
+    fn expand_synonyms(&self, thesaurus: &Thesaurus, expr: &Expr) -> Expr {
+        let group = |terms: &[String]| {
+            let mut group = vec![expr.clone()];
+            for synonym in synonyms(thesaurus, terms) {
+                group.push(match synonym.len() {
+                    1 => Expr::Term(synonym.into_iter().next().unwrap()),
+                    _ if self.has_positions() => Expr::Phrase(synonym),
+                    _ => Expr::And(synonym.into_iter().map(Expr::Term).collect()),
+                });
+            }
+
+            if group.len() == 1 {
+                expr.clone()
+            } else {
+                Expr::Or(group)
+            }
+        };
+        let each = |exprs: &[Expr]| {
+            exprs
+                .iter()
+                .map(|e| self.expand_synonyms(thesaurus, e))
+                .collect()
+        };
+
+        match expr {
+            Expr::Term(term) => group(std::slice::from_ref(term)),
+            Expr::Phrase(terms) => group(terms),
+            Expr::And(exprs) => Expr::And(each(exprs)),
+            Expr::Or(exprs) => Expr::Or(each(exprs)),
+            Expr::Not(e) => Expr::Not(Box::new(self.expand_synonyms(thesaurus, e))),
+            _ => expr.clone(),
+        }
+    }
+
+    // Returns true if every segment of the index kept term positions.
+    pub fn has_positions(&self) -> bool {
+        self.segments.iter().all(|s| s.positions_map.is_some())
+    }
+
+    // Returns true if every segment of the index kept fields.
+    pub fn has_fields(&self) -> bool {
+        self.fields.is_some()
+    }
+
+    // Returns the postings for a given `term` alongside the positions of the
+    // term within each document. Posting `i` has `frequency` positions,
+    // which follow those of posting `i - 1`.
+    pub fn positions(&self, term: &str) -> std::io::Result<(Vec<Posting>, Vec<u64>)> {
+        let mut postings = Vec::new();
+        let mut positions = Vec::new();
+        let mut segment_postings = Vec::new();
+        let mut segment_positions = Vec::new();
+
+        for segment in self.segments.iter() {
+            let ptr = match segment.lookup(term, &self.blocks)? {
+                Some((ptr, _)) => ptr,
+                None => continue,
+            };
+
+            segment_postings.clear();
+            segment_positions.clear();
+            segment.postings(ptr, &mut segment_postings, Some(&mut segment_positions))?;
+
+            let mut start = 0;
+            for p in &segment_postings {
+                let end = start + p.frequency as usize;
+
+                if !segment.deletes.contains(p.document) {
+                    postings.push(Posting {
+                        document: p.document + segment.base,
+                        frequency: p.frequency,
+                    });
+                    positions.extend_from_slice(&segment_positions[start..end]);
+                }
+
+                start = end;
+            }
+        }
+
+        Ok((postings, positions))
+    }
+
+    // Returns a posting for every document holding `terms` next to each
+    // other and in order, with the number of times the phrase occurs as the
+    // frequency.
+    pub fn phrase(&self, terms: &[String]) -> std::io::Result<Vec<Posting>> {
+        self.matches(terms, |lists| {
+            // Positions within a document are sorted, so a binary search
+            // finds each following term.
+            lists[0]
+                .iter()
+                .filter(|&&position| {
+                    lists[1..]
+                        .iter()
+                        .enumerate()
+                        .all(|(i, list)| list.binary_search(&(position + i as u64 + 1)).is_ok())
+                })
+                .count() as u64
+        })
+    }
+
+    // Returns a posting for every document holding the terms of `window`
+    // close enough together, with the number of matches as the frequency.
+    pub fn window(&self, window: &Window) -> std::io::Result<Vec<Posting>> {
+        let mut terms = window.terms.clone();
+        if !window.ordered {
+            let mut seen = HashSet::new();
+            terms.retain(|t| seen.insert(t.clone()));
+        }
+
+        self.matches(&terms, |lists| {
+            window_matches(lists, window.width, window.ordered)
+        })
+    }
+
+    // Finds the documents holding every one of `terms`, and uses `count` to
+    // count the matches within each from the positions of the terms.
+    fn matches<F>(&self, terms: &[String], count: F) -> std::io::Result<Vec<Posting>>
+    where
+        F: Fn(&[&[u64]]) -> u64,
+    {
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        if !self.has_positions() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "phrase and proximity queries need an index built with positions",
+            ));
+        }
+
+        let mut matches = Vec::new();
+        for segment in self.segments.iter() {
+            segment.matches(terms, &self.blocks, &count, &mut matches)?;
+        }
+
+        Ok(matches)
+    }
+
+    // Number of documents in the index, not counting deleted ones.
+    pub fn len(&self) -> usize {
+        self.live
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live == 0
+    }
+
+    // Returns the document name associated with the document index `doc`.
+    pub fn document(&self, doc: u64) -> &str {
+        std::str::from_utf8(self.docs[doc as usize].name.as_slice()).unwrap()
+    }
+
+    pub fn search(&self, query: &str) -> std::io::Result<impl Iterator<Item = (f32, u64)>> {
+        self.search_query(&Query::parse(query, &Analyzer::new()))
+    }
+
+    // Ranks documents against a set of already analyzed query terms.
+    pub fn search_terms(
+        &self,
+        terms: &[String],
+    ) -> std::io::Result<impl Iterator<Item = (f32, u64)>> {
+        let lists = terms
+            .iter()
+            .map(|term| self.postings(term))
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        Ok(ranked(self.weights(&lists, &Query::default()), self.limit).into_iter())
+    }
+
+    // Ranks documents against a parsed query. Each phrase and window is
+    // scored as if it were a single term, occurring wherever the whole
+    // phrase or window does. A query with a boolean filter returns every
+    // document the filter matches, even those which score nothing.
+    pub fn search_query(&self, query: &Query) -> std::io::Result<impl Iterator<Item = (f32, u64)>> {
+        let resolved;
+        let expands = self.synonyms.is_some() && !query.verbatim;
+        let query = if query.fuzzy.is_empty() && !expands {
+            query
+        } else {
+            resolved = self.resolve(query)?;
+            &resolved
+        };
+
+        // Only plain scoring is evaluated a document at a time.
+        if let (None, false, Some(k), None) =
+            (&self.bm25f, self.proximity, self.limit, &query.filter)
+        {
+            return Ok(self.top_k(query, k)?.into_iter());
+        }
+
+        let mut weights = match self.bm25f.clone() {
+            Some(params) => self.field_weights(query, &params)?,
+            None => {
+                let lists = self.query_postings(query, None)?;
+                self.weights(&lists, query)
+            }
+        };
+
+        if self.proximity {
+            let (lists, positions): (Vec<_>, Vec<_>) = query
+                .terms
+                .iter()
+                .map(|term| self.positions(term))
+                .collect::<std::io::Result<Vec<_>>>()?
+                .into_iter()
+                .unzip();
+            self.add_proximity(&lists, &positions, &mut weights);
+        }
+
+        if let Some(filter) = &query.filter {
+            weights = self
+                .matching(filter)?
+                .into_iter()
+                .map(|doc| (doc, weights.get(&doc).copied().unwrap_or(0.0)))
+                .collect();
+        }
+
+        Ok(ranked(weights, self.limit).into_iter())
+    }
+
+    // Returns the live documents matching `expr`, in order.
+    pub fn matching(&self, expr: &Expr) -> std::io::Result<Vec<u64>> {
+        let documents = |postings: Vec<Posting>| postings.iter().map(|p| p.document).collect();
+
+        Ok(match expr {
+            Expr::Term(term) => documents(self.postings(term)?),
+            Expr::Phrase(terms) => documents(self.phrase(terms)?),
+            Expr::Window(window) => documents(self.window(window)?),
+            Expr::Pattern(pattern) => documents(self.pattern(pattern)?),
+            Expr::Fuzzy(fuzzy) => {
+                let mut matched = Vec::new();
+                for (term, _) in self.fuzzy(fuzzy)? {
+                    matched = union(&matched, &documents(self.postings(&term)?));
+                }
+                matched
+            }
+            Expr::And(exprs) => {
+                // Excluded documents are taken away from what the rest
+                // match, or from every document if nothing else is given.
+                let mut required = Vec::new();
+                let mut excluded = Vec::new();
+                for expr in exprs {
+                    match expr {
+                        Expr::Not(expr) => excluded.push(self.cursor(expr)?),
+                        expr => required.push(self.cursor(expr)?),
+                    }
+                }
+                if required.is_empty() {
+                    required.push(Box::new(DecodedCursor::documents(&self.live_documents())));
+                }
+
+                conjunction(&mut required, &mut excluded)?
+            }
+            Expr::Or(exprs) => {
+                let mut matched = Vec::new();
+                for expr in exprs {
+                    matched = union(&matched, &self.matching(expr)?);
+                }
+                matched
+            }
+            Expr::Not(expr) => conjunction(
+                &mut [Box::new(DecodedCursor::documents(&self.live_documents()))],
+                &mut [self.cursor(expr)?],
+            )?,
+        })
+    }
+
+    // A cursor over the live documents matching `expr`. A term's postings
+    // are walked straight out of each segment, so the blocks a conjunction
+    // skips over are never decoded; anything else is matched up front.
+    fn cursor(&self, expr: &Expr) -> std::io::Result<Box<dyn Cursor + '_>> {
+        Ok(match expr {
+            Expr::Term(term) => Box::new(self.term_cursor(term)?),
+            Expr::Phrase(terms) => Box::new(DecodedCursor::new(self.phrase(terms)?)),
+            Expr::Window(window) => Box::new(DecodedCursor::new(self.window(window)?)),
+            Expr::Pattern(pattern) => Box::new(DecodedCursor::new(self.pattern(pattern)?)),
+            expr => Box::new(DecodedCursor::documents(&self.matching(expr)?)),
+        })
+    }
+
+    // A cursor over the live postings of `term` in every segment.
+    fn term_cursor(&self, term: &str) -> std::io::Result<TermCursor<'_>> {
+        let mut segments = Vec::new();
+        for segment in self.segments.iter() {
+            if let Some((ptr, _)) = segment.lookup(term, &self.blocks)? {
+                segments.push((segment, segment.cursor(ptr)?.0));
+            }
+        }
+
+        TermCursor::new(segments)
+    }
+
+    // Every document which hasn't been deleted, in order.
+    fn live_documents(&self) -> Vec<u64> {
+        self.segments
+            .iter()
+            .flat_map(|segment| {
+                (0..segment.documents as u64)
+                    .filter(move |&doc| !segment.deletes.contains(doc))
+                    .map(move |doc| doc + segment.base)
+            })
+            .collect()
+    }
+
+    // Finds the best `k` documents for `query` a document at a time, with
+    // Block-Max MaxScore. Terms are walked with cursors, bounded by their
+    // bounds in the dictionary and by those of each block, so their blocks
+    // are only decoded where a document could make the top k. Phrases,
+    // windows and patterns are matched up front, and bounded by their
+    // postings.
+    fn top_k(&self, query: &Query, k: usize) -> std::io::Result<Vec<(f32, u64)>> {
+        let collection = self.collection();
+
+        // Leaves room for rounding, as the scores of a document's terms are
+        // summed in a different order to their bounds.
+        let upper = |i: usize, term: &TermStats, bound: TermBound| {
+            self.scorer
+                .upper_bound(&collection, term, bound.max_frequency, bound.min_length)
+                * query.boost(i)
+                * (1.0 + 1e-5)
+        };
+
+        let mut cursors: Vec<Box<dyn Cursor + '_>> = Vec::new();
+        let mut stats = Vec::new();
+        let mut bounds = Vec::new();
+
+        for (i, t) in query.terms.iter().enumerate() {
+            let cursor = self.term_cursor(t)?;
+            let term = match query.stats(i) {
+                Some(term) => term,
+                None => self.term_stats(t)?,
+            };
+
+            bounds.push(match cursor.posting() {
+                None => 0.0,
+                Some(_) => upper(i, &term, self.bound(t)?.unwrap()),
+            });
+            stats.push(term);
+            cursors.push(Box::new(cursor));
+        }
+
+        let rest = Query {
+            terms: Vec::new(),
+            ..query.clone()
+        };
+        for (j, postings) in self.query_postings(&rest, None)?.into_iter().enumerate() {
+            let i = query.terms.len() + j;
+            let term = query.stats(i).unwrap_or(TermStats {
+                documents: postings.len() as u64,
+                frequency: postings.iter().map(|p| p.frequency).sum(),
+            });
+
+            bounds.push(if postings.is_empty() {
+                0.0
+            } else {
+                let bound = TermBound::of(&postings, |doc| self.docs[doc as usize].term_count);
+                upper(i, &term, bound)
+            });
+            stats.push(term);
+            cursors.push(Box::new(DecodedCursor::new(postings)));
+        }
+
+        let length = |doc: u64| self.docs[doc as usize].term_count;
+        max_score(
+            &mut cursors,
+            &bounds,
+            k,
+            |i, posting| {
+                self.scorer.score(
+                    &collection,
+                    &stats[i],
+                    posting.frequency,
+                    length(posting.document),
+                ) * query.boost(i)
+            },
+            |i, bound| upper(i, &stats[i], bound),
+            |doc| self.scorer.document(&collection, length(doc), stats.len()),
+        )
+    }
+
+    // The statistics of `term` over the live documents.
+    fn term_stats(&self, term: &str) -> std::io::Result<TermStats> {
+        let mut stats = TermStats {
+            documents: 0,
+            frequency: 0,
+        };
+
+        for segment in self.segments.iter() {
+            if let Some((ptr, _)) = segment.lookup(term, &self.blocks)? {
+                let segment_stats = segment.stats(ptr)?;
+                stats.documents += segment_stats.documents;
+                stats.frequency += segment_stats.frequency;
+            }
+        }
+
+        Ok(stats)
+    }
+
+    // Returns the postings of every term, phrase, window and pattern of
+    // `query`, in that order. If `field` is given, each is only matched
+    // within the field, and those scoped to a different field match nothing.
+    fn query_postings(
+        &self,
+        query: &Query,
+        field: Option<Field>,
+    ) -> std::io::Result<Vec<Vec<Posting>>> {
+        let scope = |terms: &[String]| match field {
+            Some(field) => within(field, terms),
+            None => Some(terms.to_vec()),
+        };
+
+        let mut lists = Vec::with_capacity(
+            query.terms.len() + query.phrases.len() + query.windows.len() + query.patterns.len(),
+        );
+
+        for term in &query.terms {
+            lists.push(match scope(std::slice::from_ref(term)) {
+                Some(terms) => self.postings(&terms[0])?,
+                None => Vec::new(),
+            });
+        }
+        for phrase in &query.phrases {
+            lists.push(match scope(phrase) {
+                Some(terms) => self.phrase(&terms)?,
+                None => Vec::new(),
+            });
+        }
+        for window in &query.windows {
+            lists.push(match scope(&window.terms) {
+                Some(terms) => self.window(&Window {
+                    terms,
+                    ..window.clone()
+                })?,
+                None => Vec::new(),
+            });
+        }
+        for pattern in &query.patterns {
+            lists.push(match scope(std::slice::from_ref(pattern)) {
+                Some(patterns) => self.pattern(&patterns[0])?,
+                None => Vec::new(),
+            });
+        }
+
+        Ok(lists)
+    }
+
+    // Adds a term proximity score to the BM25 score of every document, as
+    // BM25TP does. Query terms which occur close together in a document
+    // boost it. Only affects `search` and `search_query`, and needs an index
+    // built with positions.
+    pub fn set_proximity(&mut self, enabled: bool) {
+        self.proximity = enabled;
+    }
+
+    // Ranks documents with BM25F rather than BM25, given its parameters.
+    // Only affects `search` and `search_query`, and needs an index built
+    // with fields.
+    pub fn set_bm25f(&mut self, params: Option<Bm25f>) {
+        self.bm25f = params;
+    }
+
+    // Scores documents against a parsed query with BM25F. Every term, phrase
+    // and window is matched within each field in turn (using the field's
+    // copy of its terms), and the weighted, length normalised frequencies
+    // are summed. A document's terms outside any field aren't counted.
+    fn field_weights(&self, query: &Query, params: &Bm25f) -> std::io::Result<HashMap<u64, f32>> {
+        if self.fields.is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "BM25F needs an index built with fields",
+            ));
+        }
+
+        // Query term -> document -> pseudo frequency
+        let mut frequencies: Vec<HashMap<u64, f32>> = Vec::new();
+
+        for field in Field::ALL {
+            if params.weights[field.index()] == 0.0 {
+                continue;
+            }
+
+            let lists = self.query_postings(query, Some(field))?;
+            frequencies.resize_with(lists.len(), HashMap::new);
+
+            let fields = self.fields.as_ref().unwrap();
+            let average = fields.averages[field.index()];
+
+            for (postings, frequencies) in lists.iter().zip(frequencies.iter_mut()) {
+                for posting in postings {
+                    let length = fields.lengths[posting.document as usize][field.index()];
+                    *frequencies.entry(posting.document).or_insert(0.0) +=
+                        params.frequency(field, posting.frequency, length, average);
+                }
+            }
+        }
+
+        let mut weights: HashMap<u64, f32> = HashMap::new();
+        for (i, documents) in frequencies.into_iter().enumerate() {
+            // A document matching in any field counts towards n(q_i).
+            let n = query
+                .stats(i)
+                .map_or(documents.len() as u64, |s| s.documents);
+            let idf = Idf::Plain.idf(self.live as u64, n);
+            if idf == 0.0 {
+                continue;
+            }
+
+            for (document, tf) in documents {
+                *weights.entry(document).or_insert(0.0) += params.score(idf, tf) * query.boost(i);
+            }
+        }
+
+        Ok(weights)
+    }
+
+    // Ranks documents with `scorer` rather than BM25. Doesn't affect BM25F
+    // or the proximity score, which are BM25 based.
+    pub fn set_scorer(&mut self, scorer: Box<dyn Scorer>) {
+        self.scorer = scorer;
+    }
+
+    // Applies every ranking option at once.
+    pub fn set_options(&mut self, options: &SearchOptions) -> std::io::Result<()> {
+        self.set_scorer(options.build_scorer()?);
+        self.set_proximity(options.proximity);
+        self.set_bm25f(options.build_bm25f());
+        self.set_limit(options.limit);
+        self.set_expansions(options.expansions);
+        Ok(())
+    }
+
+    // Only returns the best `limit` documents of each search. Plain scoring
+    // (without BM25F or proximity) then skips documents which can't make it.
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    // Caps the number of terms a wildcard pattern expands to. The terms held
+    // by the most documents are kept.
+    pub fn set_expansions(&mut self, expansions: usize) {
+        self.expansions = expansions;
+    }
+
+    // Expands queries with the synonyms in `thesaurus`, or stops expanding
+    // them with None. Queries parsed as verbatim are never expanded.
+    pub fn set_synonyms(&mut self, thesaurus: Option<Thesaurus>) {
+        self.synonyms = thesaurus;
+    }
+
+    // Bounds the cache of dictionary blocks to roughly `bytes` bytes. The
+    // blocks cached so far are dropped.
+    pub fn set_block_cache(&mut self, bytes: usize) {
+        self.blocks = Cache::new(bytes);
+    }
+
+    // Bounds the cache of decoded postings lists to roughly `bytes` bytes,
+    // or turns it off with 0. The lists cached so far are dropped. Only the
+    // postings of plain terms are cached; phrases, windows and positions are
+    // always decoded afresh.
+    pub fn set_postings_cache(&mut self, bytes: usize) {
+        self.lists = Cache::new(bytes);
+    }
+
+    pub fn block_cache_stats(&self) -> CacheStats {
+        self.blocks.stats()
+    }
+
+    pub fn postings_cache_stats(&self) -> CacheStats {
+        self.lists.stats()
+    }
+
+    pub fn collection(&self) -> Collection {
+        Collection {
+            documents: self.live as u64,
+            avg_length: self.avg_dl,
+            total_length: self.total_dl,
+        }
+    }
+
+    // Scores documents against the postings of every query term, with the
+    // boosts and statistics `query` gives them, if any.
+    fn weights(&self, lists: &[Vec<Posting>], query: &Query) -> HashMap<u64, f32> {
+        // Document id -> w_dq
+        // We use a HashMap here instead of a Vec because (hopefully?) the
+        // weights will be sparse. That is, not many documents will have a
+        // positive score relative to the overall corpus size.
+        let mut weights: HashMap<u64, f32> = HashMap::new();
+        weights.reserve(self.live);
+
+        let collection = self.collection();
+
+        for (i, postings) in lists.iter().enumerate() {
+            let boost = query.boost(i);
+            let term = query.stats(i).unwrap_or(TermStats {
+                documents: postings.len() as u64,
+                frequency: postings.iter().map(|p| p.frequency).sum(),
+            });
+
+            for posting in postings {
+                let score_qt = self.scorer.score(
+                    &collection,
+                    &term,
+                    posting.frequency,
+                    self.docs[posting.document as usize].term_count,
+                ) * boost;
+
+                // A term which can't tell documents apart (such as one in
+                // every document, under BM25) doesn't make them match.
+                if score_qt == 0.0 {
+                    continue;
+                }
+
+                let w = weights.entry(posting.document).or_insert(0.0);
+                *w += score_qt;
+            }
+        }
+
+        for (&document, w) in weights.iter_mut() {
+            let length = self.docs[document as usize].term_count;
+            *w += self.scorer.document(&collection, length, lists.len());
+        }
+
+        weights
+    }
+
+    // (BM25TP)
+    // score(D,Q) += Sum{1..n}
+    // min(1, IDF(q_i)) * ( ( acc(q_i) * (k_1 + 1) ) / ( acc(q_i) + K ) )
+    // where acc(q_i) accumulates how close q_i occurs to the other query terms
+    // in D. `lists` and `positions` are the postings and positions of each
+    // query term.
+    fn add_proximity(
+        &self,
+        lists: &[Vec<Posting>],
+        positions: &[Vec<u64>],
+        weights: &mut HashMap<u64, f32>,
+    ) {
+        // Document -> [(query term, positions)]
+        let mut documents: HashMap<u64, Vec<(usize, &[u64])>> = HashMap::new();
+        for (i, (postings, positions)) in lists.iter().zip(positions).enumerate() {
+            for (document, list) in by_document(postings, positions) {
+                documents.entry(document).or_default().push((i, list));
+            }
+        }
+
+        let bm25 = Bm25::default();
+        let idfs = lists
+            .iter()
+            .map(|postings| {
+                Idf::Plain
+                    .idf(self.live as u64, postings.len() as u64)
+                    .min(1.0)
+            })
+            .collect::<Vec<_>>();
+
+        for (document, terms) in documents {
+            if terms.len() < 2 {
+                continue;
+            }
 
-use crate::indexing::varint::{read_varint, write_varint};
-use std::collections::{BTreeMap, HashMap};
-use std::fs::File;
-use std::io::{BufReader, Cursor, Read, Seek, SeekFrom, Write};
+            let term_lists = terms.iter().map(|&(_, list)| list).collect::<Vec<_>>();
+            let acc = term_proximity(&term_lists);
+            let k = bm25.k(self.docs[document as usize].term_count, self.avg_dl);
 
-use smallvec::SmallVec;
+            let score = terms
+                .iter()
+                .zip(acc)
+                .map(|(&(i, _), acc)| idfs[i] * ((acc * (bm25.k1 + 1.0)) / (acc + k)))
+                .sum::<f32>();
 
-struct DiskDocument {
-    term_count: u64,
-    name: SmallVec<[u8; 32]>,
+            if score > 0.0 {
+                *weights.entry(document).or_insert(0.0) += score;
+            }
+        }
+    }
 }
 
-pub struct DiskIndex {
-    post_file: File,
-    blocks_file: File,
+// Note: Don't use a B-Tree for this sorting operation.
+// f32 doesn't implement Ord, so can't be natively used. OrderedFloat
+// introduces non-determinism that hides some results.
+// Sinking to a vector and sorting the vector is a little slower, but
+// whatever.
+// Ties go to the lower document ID, as they do in `TopK`, so the ranking
+// doesn't depend on the order the HashMap happens to iterate in.
+// With a `limit`, only the best documents are kept on a heap instead.
+fn ranked(weights: HashMap<u64, f32>, limit: Option<usize>) -> Vec<(f32, u64)> {
+    if let Some(k) = limit {
+        let mut top = TopK::new(k);
+        for (doc, w) in weights {
+            top.push(w, doc);
+        }
+        return top.into_sorted();
+    }
 
-    // Loaded from disk immediately
-    docs: Vec<DiskDocument>,
-    avg_dl: f32,
-    root: Vec<(String, u64)>,
+    let mut res = weights
+        .into_iter()
+        .map(|(doc, w)| (w, doc))
+        .collect::<Vec<_>>();
+    res.sort_by(|&a, &b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+    res
+}
 
-    // Loaded on an as-needed basis during search
-    blocks: BTreeMap<u64, Block>,
+// The field name and colon a term is scoped to, or nothing.
+fn scope(term: &str) -> &str {
+    &term[..term.find(':').map_or(0, |i| i + 1)]
 }
 
-pub struct Posting {
-    pub document: u64,
-    pub frequency: u64,
+// The synonyms of `terms` in `thesaurus`, scoped to the same field as they
+// are. Terms scoped to different fields have none.
+fn synonyms(thesaurus: &Thesaurus, terms: &[String]) -> Vec<Vec<String>> {
+    let prefix = terms.first().map_or("", |term| scope(term));
+    if terms.iter().any(|term| scope(term) != prefix) {
+        return Vec::new();
+    }
+
+    let unscoped = terms
+        .iter()
+        .map(|term| term[prefix.len()..].to_string())
+        .collect::<Vec<_>>();
+    thesaurus
+        .synonyms(&unscoped)
+        .iter()
+        .map(|synonym| synonym.iter().map(|t| format!("{}{}", prefix, t)).collect())
+        .collect()
 }
 
-pub struct Document {
-    pub term_count: u64,
-    pub name: String,
+// Qualifies each of `terms` with `field`. Terms already scoped to `field` are
+// kept as they are, and if any is scoped to another field, nothing is
+// returned.
+fn within(field: Field, terms: &[String]) -> Option<Vec<String>> {
+    terms
+        .iter()
+        .map(|term| match Field::split(term) {
+            Some((scope, _)) if scope == field => Some(term.clone()),
+            Some(_) => None,
+            None => Some(field.qualify(term)),
+        })
+        .collect()
 }
 
-impl DiskIndex {
-    pub fn from_disk() -> std::io::Result<DiskIndex> {
-        let post_file = File::open("postings.bin")?;
-        let blocks_file = File::open("blocks.bin")?;
-        let mut documents_file = File::open("documents.bin")?;
-        let mut index_file = File::open("index.bin")?;
+impl FieldLengths {
+    // Reads the field lengths of every segment, unless any segment is
+    // missing them.
+    fn read(
+        dir: &Path,
+        manifest: &Manifest,
+        segments: &[Segment],
+    ) -> std::io::Result<Option<FieldLengths>> {
+        let mut lengths = Vec::new();
+        let mut totals = [0u64; Field::COUNT];
+        let mut live = 0;
+
+        for (info, segment) in manifest.segments.iter().zip(segments) {
+            let documents = match read_segment_fields(&dir.join(&info.name))? {
+                Some(documents) => documents,
+                None if segment.documents == 0 => continue,
+                None => return Ok(None),
+            };
+
+            if documents.len() != segment.documents {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "field lengths don't match the documents",
+                ));
+            }
+
+            for (doc, document) in documents.into_iter().enumerate() {
+                let document = <[u64; Field::COUNT]>::try_from(document).map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "unexpected number of fields",
+                    )
+                })?;
+
+                if !segment.deletes.contains(doc as u64) {
+                    live += 1;
+                    for (total, length) in totals.iter_mut().zip(document) {
+                        *total += length;
+                    }
+                }
+                lengths.push(document);
+            }
+        }
+
+        let mut averages = [0.0; Field::COUNT];
+        if live > 0 {
+            for (average, total) in averages.iter_mut().zip(totals) {
+                *average = total as f32 / live as f32;
+            }
+        }
+
+        Ok(Some(FieldLengths { lengths, averages }))
+    }
+}
+
+// The documents in both of the sorted lists.
+pub(crate) fn intersect(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut both = Vec::with_capacity(a.len().min(b.len()));
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                both.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+
+    both
+}
+
+// The documents in either of the sorted lists.
+fn union(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut either = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => {
+                either.push(a[i]);
+                i += 1;
+            }
+            std::cmp::Ordering::Greater => {
+                either.push(b[j]);
+                j += 1;
+            }
+            std::cmp::Ordering::Equal => {
+                either.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+
+    either.extend_from_slice(&a[i..]);
+    either.extend_from_slice(&b[j..]);
+    either
+}
+
+// The documents every one of `required` holds and none of `excluded` does,
+// in order. The cursor with the fewest postings leads, and the others skip
+// ahead to each of its documents, so a rare term keeps a common one from
+// being decoded any more than it has to be.
+fn conjunction(
+    required: &mut [Box<dyn Cursor + '_>],
+    excluded: &mut [Box<dyn Cursor + '_>],
+) -> std::io::Result<Vec<u64>> {
+    required.sort_by_key(|cursor| cursor.len());
+
+    let mut matched = Vec::new();
+    let mut document = 0;
+    'documents: loop {
+        for cursor in required.iter_mut() {
+            match cursor.next_geq(document)? {
+                None => break 'documents,
+                Some(p) if p.document > document => {
+                    document = p.document;
+                    continue 'documents;
+                }
+                Some(_) => {}
+            }
+        }
+
+        let mut held = false;
+        for cursor in excluded.iter_mut() {
+            if cursor.next_geq(document)?.map(|p| p.document) == Some(document) {
+                held = true;
+                break;
+            }
+        }
+        if !held {
+            matched.push(document);
+        }
+
+        document += 1;
+    }
+
+    Ok(matched)
+}
+
+// Splits flattened `positions` into the positions of each posting's
+// document.
+fn by_document<'a>(postings: &[Posting], positions: &'a [u64]) -> HashMap<u64, &'a [u64]> {
+    let mut documents = HashMap::with_capacity(postings.len());
+    let mut start = 0;
+
+    for p in postings {
+        let end = start + p.frequency as usize;
+        documents.insert(p.document, &positions[start..end]);
+        start = end;
+    }
+
+    documents
+}
+
+impl Segment {
+    // Opens the segment stored in `dir`, appending its documents to `docs`.
+    fn open(
+        dir: PathBuf,
+        id: usize,
+        base: u64,
+        deletes: Deletes,
+        codec: Codec,
+        docs: &mut Vec<DiskDocument>,
+    ) -> std::io::Result<Segment> {
+        let post_map = map(&dir.join(POSTINGS_FILE))?;
+        let blocks_map = map(&dir.join(BLOCKS_FILE))?;
+        let mut documents_file = File::open(dir.join(DOCUMENTS_FILE))?;
+        let mut index_file = File::open(dir.join(INDEX_FILE))?;
         let mut avg_dl = 0.0;
 
-        let docs = {
+        let positions_map = match map(&dir.join(POSITIONS_FILE)) {
+            Ok(map) => Some(map),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        {
             let mut bytes = Vec::with_capacity(8192);
             documents_file.read_to_end(&mut bytes)?;
 
-            let mut reader = Cursor::new(bytes);
-            let mut buffer = Vec::with_capacity(8192);
+            let mut reader = std::io::Cursor::new(bytes);
 
-            read_documents(&mut reader, &mut avg_dl, &mut buffer)?;
-            buffer
-        };
+            read_documents(&mut reader, &mut avg_dl, docs)?;
+        }
+        let documents = docs.len() - base as usize;
 
         let root = {
             let mut bytes = Vec::with_capacity(8192);
             index_file.read_to_end(&mut bytes)?;
 
-            let mut reader = Cursor::new(bytes);
+            let mut reader = std::io::Cursor::new(bytes);
             let mut buffer = Vec::with_capacity(8192);
 
             read_terms(&mut reader, &mut buffer)?;
             buffer
         };
 
-        // For each root element, create a leaf node that we haven't loaded
-        // from disk yet
-        let mut blocks = BTreeMap::new();
-        for (_, ptr) in &root {
-            blocks.insert(*ptr, Block::Unloaded);
-        }
-
-        Ok(DiskIndex {
-            post_file,
-            blocks_file,
-            docs,
-            avg_dl,
+        Ok(Segment {
+            post_map,
+            blocks_map,
+            positions_map,
+            codec,
             root,
-            blocks,
+            id,
+            base,
+            documents,
+            deletes,
+            kgrams: OnceLock::new(),
         })
     }
 
-    // Ensure that the block given by the file offset `ptr` is loaded in
-    // memory.
-    fn ensure_block_loaded(&mut self, ptr: u64) -> std::io::Result<()> {
-        // If the block is already loaded, no IO needs to be done.
-        if let Some(Block::Loaded { block: _ }) = self.blocks.get(&ptr) {
-            return Ok(());
-        }
-
-        // Seek to the offset given by `ptr` from the beginning of the file.
-        self.blocks_file.seek(SeekFrom::Start(ptr as u64))?;
-
-        let mut reader = BufReader::new(&mut self.blocks_file);
-        let mut rows = Vec::with_capacity(1000);
+    // Decodes the dictionary block for root entry `k`, returning it and its
+    // approximate size in bytes.
+    fn read_block(&self, k: usize) -> std::io::Result<(DictionaryBlock, usize)> {
+        let mut reader = at(&self.blocks_map, self.root[k].1)?;
+        let mut rows = Vec::with_capacity(BLOCK_SIZE);
+
+        // Every block except the last block is exactly `BLOCK_SIZE` elements,
+        // so size information is not necessary. The last block runs up to
+        // the end of the file.
+        for _ in 0..BLOCK_SIZE {
+            if reader.is_empty() {
+                break;
+            }
 
-        // Every block except the last block is exactly 1000 elements, so size
-        // information is not necessary. The last block will result in an EOF
-        // IO error which we can use to break early.
-        for _ in 0..1000 {
-            let term = match read_term(&mut reader) {
-                Ok(t) => t,
-                Err(_) => break,
-            };
-            rows.push(term);
+            let (term, ptr) = read_term(&mut reader)?;
+            rows.push((term, ptr, read_bound(&mut reader)?));
         }
 
-        // Insert the block for any subsequent calls.
-        self.blocks.insert(ptr, Block::Loaded { block: rows });
+        let size = rows
+            .iter()
+            .map(|(term, _, _)| term.len() + std::mem::size_of::<(String, u64, TermBound)>())
+            .sum();
 
-        Ok(())
+        Ok((rows, size))
     }
 
-    // Returns the set of postings for a given `term`. This function results
-    // in a disk read in the postings file.
-    pub fn postings(&mut self, term: &str) -> std::io::Result<Vec<Posting>> {
+    // Looks `term` up in the dictionary, returning the offset of its
+    // postings in the postings file and its bound. Dictionary blocks are
+    // kept in `blocks`.
+    fn lookup(&self, term: &str, blocks: &BlockCache) -> std::io::Result<Option<(u64, TermBound)>> {
+        // An empty segment has no dictionary at all.
+        if self.root.is_empty() {
+            return Ok(None);
+        }
+
         // Binary search the root index for `term`.
         // Note that because the root index is incomplete, it's likely that the
         // term isn't in the root index.
@@ -131,121 +1496,455 @@ impl DiskIndex {
         //  based on the top-to-bottom declaration order of the struct's
         //  members."
         let ind = match self.root.binary_search_by_key(&term, |(a, _)| a) {
-            Ok(k) => self.root[k].1,
-            Err(k) => {
-                if k > 0 {
-                    self.root[k - 1].1
-                } else {
-                    self.root[0].1
-                }
-            }
+            Ok(k) => k,
+            Err(k) => k.saturating_sub(1),
         };
 
         // The given block needs to be loaded before we use it. We don't
-        // necessarily need to read the block from disk -- for instance if the
-        // block was previously loaded it will already be present.
-        self.ensure_block_loaded(ind)?;
-
-        if let Block::Loaded { block } = &self.blocks[&ind] {
-            // Binary search within the block to find the term.
-            // If the term isn't present, we definitely don't have any postings
-            // for the term and can return early.
-            let ptr = match block.binary_search_by_key(&term, |(a, _)| a) {
-                Ok(k) => block[k].1,
-                Err(_) => return Ok(Vec::new()),
-            };
+        // necessarily need to decode the block -- for instance if the block
+        // was previously loaded it may still be cached.
+        let block = blocks.get_or_load(&(self.id, ind), || self.read_block(ind))?;
+
+        // Binary search within the block to find the term.
+        // If the term isn't present, we definitely don't have any postings
+        // for the term.
+        match block.binary_search_by_key(&term, |(a, _, _)| a) {
+            Ok(k) => Ok(Some((block[k].1, block[k].2))),
+            Err(_) => Ok(None),
+        }
+    }
 
-            // Seek in the postings file using `ptr` as the offset from the
-            // beginning of the file.
-            self.post_file.seek(SeekFrom::Start(ptr as u64))?;
+    // Calls `visit` with every term of the segment starting with `prefix`,
+    // in order, and the offset of its postings. The dictionary is sorted, so
+    // they're found by scanning forward from the block `prefix` would be in.
+    fn scan<F>(&self, prefix: &str, blocks: &BlockCache, mut visit: F) -> std::io::Result<()>
+    where
+        F: FnMut(&str, u64),
+    {
+        let mut k = match self.root.binary_search_by_key(&prefix, |(a, _)| a) {
+            Ok(k) => k,
+            Err(k) => k.saturating_sub(1),
+        };
 
-            let mut reader = BufReader::new(&mut self.post_file);
-            let mut postings = Vec::with_capacity(1024);
+        while k < self.root.len() {
+            let block = blocks.get_or_load(&(self.id, k), || self.read_block(k))?;
+            let start = block.partition_point(|(term, _, _)| term.as_str() < prefix);
 
-            read_postings(&mut reader, &mut postings)?;
+            for (term, ptr, _) in &block[start..] {
+                if !term.starts_with(prefix) {
+                    return Ok(());
+                }
+                visit(term, *ptr);
+            }
 
-            Ok(postings)
-        } else {
-            Ok(Vec::new())
+            k += 1;
         }
+
+        Ok(())
     }
 
-    // Returns the document name associated with the document index `doc`.
-    pub fn document(&self, doc: u64) -> &str {
-        std::str::from_utf8(self.docs[doc as usize].name.as_slice()).unwrap()
+    // Returns the terms of the segment starting with `prefix`, with the
+    // offsets of their postings.
+    fn prefixed(&self, prefix: &str, blocks: &BlockCache) -> std::io::Result<Vec<(String, u64)>> {
+        let mut terms = Vec::new();
+        self.scan(prefix, blocks, |term, ptr| {
+            terms.push((term.to_string(), ptr))
+        })?;
+        Ok(terms)
     }
 
-    pub fn search(&mut self, query: &str) -> std::io::Result<impl Iterator<Item = (f32, u64)>> {
-        // Document id -> w_dq
-        // We use a HashMap here instead of a Vec because (hopefully?) the
-        // weights will be sparse. That is, not many documents will have a
-        // positive score relative to the overall corpus size.
-        let mut weights: HashMap<u64, f32> = HashMap::new();
-        weights.reserve(self.docs.len());
+    // Returns the terms of the segment within `distance` edits of `term`,
+    // with the offsets of their postings and their number of edits, found by
+    // walking a Levenshtein automaton over the sorted dictionary. A term
+    // scoped to a field is only compared with the other terms of the field,
+    // and an unscoped term only with the unscoped terms.
+    fn fuzzy(
+        &self,
+        term: &str,
+        distance: u32,
+        blocks: &BlockCache,
+    ) -> std::io::Result<Vec<(String, u64, u32)>> {
+        let scope = scope(term);
+        let word = &term[scope.len()..];
+
+        let automaton = Levenshtein::new(word, distance);
+        let mut walker = automaton.walker();
+
+        let mut terms = Vec::new();
+        self.scan(scope, blocks, |found, ptr| {
+            let rest = &found[scope.len()..];
+            if scope.is_empty() && rest.contains(':') {
+                return;
+            }
 
-        let t = Terms::new(query);
+            if let Some(edits) = walker.edits(rest) {
+                terms.push((found.to_string(), ptr, edits));
+            }
+        })?;
 
-        // (BM25)
-        // score(D,Q) = Sum{1..n}
-        // IDF(q_i) * ( ( f(q_i, D) * (k_1 + 1) ) /
-        //   ( f(q_i, D) + k_1 * (1 - b + b * (|D| / avgdl))) )
+        Ok(terms)
+    }
 
-        for term in t {
-            let postings = self.postings(&term)?;
+    // Returns the terms of the segment matching the wildcard `pattern`, with
+    // the offsets of their postings. Plain prefixes are scanned for, and
+    // other patterns are narrowed down with the k-gram index, or failing
+    // that, to the terms starting with whatever comes before the first
+    // wildcard. Either way, each candidate is then checked against the whole
+    // pattern.
+    fn wildcard(&self, pattern: &str, blocks: &BlockCache) -> std::io::Result<Vec<(String, u64)>> {
+        let candidates = match wildcard::prefix(pattern) {
+            Some(prefix) => self.prefixed(prefix, blocks)?,
+            None => match self.kgrams()?.candidates(pattern) {
+                Some(ordinals) => ordinals
+                    .into_iter()
+                    .map(|ordinal| self.term(ordinal, blocks))
+                    .collect::<std::io::Result<Vec<_>>>()?,
+                None => self.prefixed(wildcard::literal_prefix(pattern), blocks)?,
+            },
+        };
 
-            // IDF(q_i) = ln( (N - n(q_i) + 0.5) / (n(q_i) + 0.5) + 1)
-            // where,
-            // N = total number of documents in the collection,
-            // n(q_i) = number of documents containing q_i
-            let N = self.docs.len() as f32;
-            let n_q_i = postings.len() as f32;
-            let idf = (N / n_q_i).ln();
+        Ok(candidates
+            .into_iter()
+            .filter(|(term, _)| wildcard::matches(pattern, term))
+            .collect())
+    }
 
-            // `score_qt` is determined by multiplying by `idf`, so if `idf` is
-            // 0 we know that the score can't positively effect the outcome.
-            if idf == 0.0 {
+    // Returns the terms of the segment within `distance` edits of `term`,
+    // with the offsets of their postings and their number of edits. The
+    // candidates come from the k-gram index, and are checked with a
+    // Levenshtein automaton. Like `fuzzy`, a term is only compared with the
+    // terms of the same field, or the unscoped terms.
+    fn similar(
+        &self,
+        term: &str,
+        distance: u32,
+        blocks: &BlockCache,
+    ) -> std::io::Result<Vec<(String, u64, u32)>> {
+        let automaton = Levenshtein::new(term, distance);
+        let mut walker = automaton.walker();
+
+        // The candidates are numbered in dictionary order, as the walker
+        // needs them.
+        let mut terms = Vec::new();
+        for ordinal in self.kgrams()?.similar(term, distance as usize) {
+            let (found, ptr) = self.term(ordinal, blocks)?;
+            if scope(&found) != scope(term) {
                 continue;
             }
 
-            for posting in &postings {
-                // f(qi, D) = term frequency in document D,
-                let term_freq = posting.frequency as f32;
+            if let Some(edits) = walker.edits(&found) {
+                terms.push((found, ptr, edits));
+            }
+        }
+
+        Ok(terms)
+    }
 
-                let D = self.docs[posting.document as usize].term_count as f32;
+    // Returns the `ordinal`th term of the dictionary, with the offset of its
+    // postings.
+    fn term(&self, ordinal: u64, blocks: &BlockCache) -> std::io::Result<(String, u64)> {
+        let k = ordinal as usize / BLOCK_SIZE;
+        let block = blocks.get_or_load(&(self.id, k), || self.read_block(k))?;
+        let (term, ptr, _) = &block[ordinal as usize % BLOCK_SIZE];
 
-                // Reference: Andrew Trotman, Matt Crane, "Snip!".
-                // http://www.cs.otago.ac.nz/homepages/andrew/papers/2011-13.pdf
-                let k = 0.9;
-                let b = 0.4;
+        Ok((term.clone(), *ptr))
+    }
 
-                let score_qt = idf
-                    * ((term_freq * (k + 1.0))
-                        / (term_freq + k * (1.0 - b + b * (D / self.avg_dl))));
+    // Returns the k-gram index of the segment's terms, building it from the
+    // whole dictionary if this is the first time it's needed. The blocks are
+    // read around the block cache, so building it doesn't evict anything.
+    fn kgrams(&self) -> std::io::Result<&KGrams> {
+        if let Some(kgrams) = self.kgrams.get() {
+            return Ok(kgrams);
+        }
 
-                let w = weights.entry(posting.document).or_insert(0.0);
-                *w += score_qt;
+        let mut kgrams = KGrams::new();
+        for k in 0..self.root.len() {
+            for (term, _, _) in self.read_block(k)?.0 {
+                kgrams.push(&term);
             }
         }
 
-        // Note: Don't use a B-Tree for this sorting operation.
-        // f32 doesn't implement Ord, so can't be natively used. OrderedFloat
-        // introduces non-determinism that hides some results.
-        // Sinking to a vector and sorting the vector is a little slower, but
-        // whatever.
+        // Another thread may have built it in the meantime, in which case
+        // this copy is dropped.
+        let _ = self.kgrams.set(kgrams);
+        Ok(self.kgrams.get().unwrap())
+    }
 
-        let mut res = weights
-            .into_iter()
-            .map(|(doc, w)| (w, doc))
-            .collect::<Vec<_>>();
-        res.sort_by(|&a, &b| a.0.partial_cmp(&b.0).unwrap());
+    // Opens a cursor over the postings of `term`, alongside where its
+    // positions start in the positions file if the segment kept them. The
+    // cursor decodes straight out of the mapped postings file.
+    fn cursor(&self, ptr: u64) -> std::io::Result<(PostingsCursor<'_>, Option<u64>)> {
+        let mut reader = at(&self.post_map, ptr)?;
+        let cursor = PostingsCursor::read(self.codec, &mut reader)?;
+
+        let positions = match self.positions_map {
+            Some(_) => Some(read_varint(&mut reader)?.0),
+            None => None,
+        };
+
+        Ok((cursor, positions))
+    }
+
+    // The number of live documents holding the term whose postings are at
+    // `ptr`, and the sum of its frequencies in them. These are read from the
+    // head of the postings unless some of the segment's documents have been
+    // deleted, when the postings are walked to leave those out.
+    fn stats(&self, ptr: u64) -> std::io::Result<TermStats> {
+        let (mut cursor, _) = self.cursor(ptr)?;
+        if self.deletes.is_empty() {
+            return Ok(TermStats {
+                documents: cursor.len(),
+                frequency: cursor.frequency(),
+            });
+        }
+
+        let mut stats = TermStats {
+            documents: 0,
+            frequency: 0,
+        };
+        while let Some(p) = cursor.posting() {
+            if !self.deletes.contains(p.document) {
+                stats.documents += 1;
+                stats.frequency += p.frequency;
+            }
+            cursor.advance()?;
+        }
+
+        Ok(stats)
+    }
+
+    fn positions_map(&self) -> std::io::Result<&Mmap> {
+        self.positions_map.as_ref().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "segment was built without positions",
+            )
+        })
+    }
+
+    // Reads the positions of the block `cursor` is on, given where the
+    // positions of its whole list start.
+    fn block_positions(&self, cursor: &PostingsCursor, ptr: u64) -> std::io::Result<Vec<u64>> {
+        let (postings, offset) = cursor.block_postings();
+
+        let mut positions = Vec::new();
+        read_positions(
+            postings.iter().map(|p| p.frequency),
+            &mut at(self.positions_map()?, ptr + offset)?,
+            &mut positions,
+        )?;
+
+        Ok(positions)
+    }
+
+    // Appends a posting to `matches` for every live document of the segment
+    // holding every one of `terms`, numbered globally, with `count` of the
+    // terms' positions in the document as the frequency.
+    //
+    // The term with the fewest postings leads, and the others jump to each
+    // of its documents with the skip table. Positions are only read for the
+    // blocks which hold a document with every term.
+    fn matches<F>(
+        &self,
+        terms: &[String],
+        blocks: &BlockCache,
+        count: &F,
+        matches: &mut Vec<Posting>,
+    ) -> std::io::Result<()>
+    where
+        F: Fn(&[&[u64]]) -> u64,
+    {
+        self.positions_map()?;
+
+        let mut cursors = Vec::with_capacity(terms.len());
+        for term in terms {
+            let ptr = match self.lookup(term, blocks)? {
+                Some((ptr, _)) => ptr,
+                None => return Ok(()),
+            };
+
+            let (cursor, positions) = self.cursor(ptr)?;
+            cursors.push((cursor, positions.unwrap_or_default()));
+        }
+
+        let mut order = (0..cursors.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| cursors[i].0.len());
+
+        // For each term, the block whose positions have been read, its
+        // positions and where the positions of each of its postings start.
+        let mut loaded = vec![(usize::MAX, Vec::new(), Vec::new()); cursors.len()];
+
+        let mut document = 0;
+        'documents: loop {
+            for &i in &order {
+                match cursors[i].0.next_geq(document)? {
+                    None => break 'documents,
+                    Some(p) if p.document > document => {
+                        document = p.document;
+                        continue 'documents;
+                    }
+                    Some(_) => {}
+                }
+            }
+
+            if !self.deletes.contains(document) {
+                for ((cursor, ptr), loaded) in cursors.iter().zip(loaded.iter_mut()) {
+                    if loaded.0 != cursor.block() {
+                        let positions = self.block_positions(cursor, *ptr)?;
+                        let starts = cursor
+                            .block_postings()
+                            .0
+                            .iter()
+                            .scan(0, |start, p| {
+                                let this = *start;
+                                *start += p.frequency as usize;
+                                Some(this)
+                            })
+                            .collect();
+
+                        *loaded = (cursor.block(), positions, starts);
+                    }
+                }
+
+                let lists = cursors
+                    .iter()
+                    .zip(&loaded)
+                    .map(|((cursor, _), (_, positions, starts))| {
+                        let start = starts[cursor.index()];
+                        let frequency = cursor.posting().unwrap().frequency as usize;
+                        &positions[start..start + frequency]
+                    })
+                    .collect::<Vec<_>>();
+
+                let frequency = count(&lists);
+                if frequency > 0 {
+                    matches.push(Posting {
+                        document: document + self.base,
+                        frequency,
+                    });
+                }
+            }
+
+            document += 1;
+        }
+
+        Ok(())
+    }
+
+    // Appends the postings at the offset `ptr` of the postings file to
+    // `container`, numbered within this segment. If `positions` is given,
+    // the term's positions are appended to it too. The postings (and
+    // positions) are decoded straight out of the mapped files, so no system
+    // calls are made, though touching a page for the first time may fault it
+    // in from disk.
+    fn postings(
+        &self,
+        ptr: u64,
+        container: &mut Vec<Posting>,
+        positions: Option<&mut Vec<u64>>,
+    ) -> std::io::Result<()> {
+        let mut reader = at(&self.post_map, ptr)?;
+
+        let start = container.len();
+        read_postings(self.codec, &mut reader, container)?;
+
+        if let Some(positions) = positions {
+            // The postings are followed by a pointer into the positions
+            // file.
+            let (ptr, _) = read_varint(&mut reader)?;
+
+            read_positions(
+                container[start..].iter().map(|p| p.frequency),
+                &mut at(self.positions_map()?, ptr)?,
+                positions,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+// Walks the live postings of a term through each segment in turn, numbered
+// globally. Always left on a live posting, or past the last.
+struct TermCursor<'a> {
+    // Each segment holding the term, and a cursor over its postings.
+    segments: Vec<(&'a Segment, PostingsCursor<'a>)>,
+    // The segment the cursor is in.
+    current: usize,
+    // Number of postings in every segment, deleted or not.
+    len: u64,
+}
+
+impl<'a> TermCursor<'a> {
+    fn new(segments: Vec<(&'a Segment, PostingsCursor<'a>)>) -> std::io::Result<TermCursor<'a>> {
+        let len = segments.iter().map(|(_, cursor)| cursor.len()).sum();
+        let mut cursor = TermCursor {
+            segments,
+            current: 0,
+            len,
+        };
+        cursor.next_geq(0)?;
+
+        Ok(cursor)
+    }
+}
+
+impl Cursor for TermCursor<'_> {
+    fn posting(&self) -> Option<Posting> {
+        let (segment, cursor) = self.segments.get(self.current)?;
+        cursor.posting().map(|p| Posting {
+            document: p.document + segment.base,
+            frequency: p.frequency,
+        })
+    }
+
+    fn next_geq(&mut self, document: u64) -> std::io::Result<Option<Posting>> {
+        while let Some((segment, cursor)) = self.segments.get_mut(self.current) {
+            let mut local = document.saturating_sub(segment.base);
+            while let Some(p) = cursor.next_geq(local)? {
+                if !segment.deletes.contains(p.document) {
+                    return Ok(self.posting());
+                }
+                local = p.document + 1;
+            }
+
+            self.current += 1;
+        }
+
+        Ok(None)
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
 
-        Ok(res.into_iter().rev())
+    fn block_bound(&self, document: u64) -> Option<(u64, TermBound)> {
+        self.segments[self.current..]
+            .iter()
+            .find_map(|(segment, cursor)| {
+                let (last, bound) = cursor.block_bound(document.saturating_sub(segment.base))?;
+                Some((last + segment.base, bound))
+            })
     }
 }
 
-enum Block {
-    Loaded { block: Vec<(String, u64)> },
-    Unloaded,
+// Maps the file at `path` into memory. Component files are never modified
+// once written (a changed segment is written out under a new name), so the
+// mapped bytes can't change underneath a search.
+fn map(path: &Path) -> std::io::Result<Mmap> {
+    let file = File::open(path)?;
+    unsafe { Mmap::map(&file) }
+}
+
+// The bytes of `map` from the offset `ptr` on.
+fn at(map: &[u8], ptr: u64) -> std::io::Result<&[u8]> {
+    map.get(ptr as usize..).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "pointer past the end of the file",
+        )
+    })
 }
 
 // +-----------------+------------------------------------------+
@@ -263,18 +1962,18 @@ pub fn write_documents<I: Iterator<Item = Document>, W: Write>(
     iter: I,
     mut writer: &mut W,
 ) -> std::io::Result<usize> {
-    let mut offset = write_varint(&mut writer, n as u64)?;
+    let mut offset = write_varint(&mut writer, n)?;
     writer.write_all(&avg_dl.to_be_bytes()[..])?;
 
     for doc in iter {
         // Write term count
-        offset += write_varint(&mut writer, doc.term_count as u64)?;
+        offset += write_varint(&mut writer, doc.term_count)?;
 
         // Write document name
         offset += write_varint(&mut writer, doc.name.len() as u64)?;
-        writer.write_all(&doc.name.as_bytes()[..])?;
+        writer.write_all(doc.name.as_bytes())?;
 
-        offset += doc.name.as_bytes().len();
+        offset += doc.name.len();
     }
 
     Ok(offset)
@@ -286,7 +1985,7 @@ pub fn write_documents<I: Iterator<Item = Document>, W: Write>(
 // BM25 ranking.
 // `container` can be any structure which is extended from `DiskDocument`s,
 // although realistically it's probably going to be a vector.
-pub fn read_documents<R: Read, C: Extend<DiskDocument>>(
+pub(crate) fn read_documents<R: Read, C: Extend<DiskDocument>>(
     mut reader: &mut R,
     avg_dl: &mut f32,
     container: &mut C,
@@ -317,7 +2016,7 @@ pub fn read_documents<R: Read, C: Extend<DiskDocument>>(
         offset += term_count_offset + len_offset + bytes.len();
 
         documents.push(DiskDocument {
-            term_count: term_count as u64,
+            term_count,
             name: bytes,
         });
     }
@@ -327,25 +2026,150 @@ pub fn read_documents<R: Read, C: Extend<DiskDocument>>(
     Ok(offset)
 }
 
-// Write a set of postings, given by `iter` to `writer`.
+// The length of each field of every document, in the same order as
+// documents.bin.
+//
+// +-----------------+----------------------+
+// | N      (varint) | Field Count (varint) |
+// +-----------------+----------------------+
+// ..
+// +-----------------------+
+// | Field Length (varint) | ..
+// +-----------------------+
+// (Field Count times per document, N times)
+pub fn write_field_lengths<'a, I: Iterator<Item = &'a [u64]>, W: Write>(
+    n: u64,
+    fields: u64,
+    iter: I,
+    mut writer: &mut W,
+) -> std::io::Result<usize> {
+    let mut offset = write_varint(&mut writer, n)?;
+    offset += write_varint(&mut writer, fields)?;
+
+    for lengths in iter {
+        assert_eq!(lengths.len() as u64, fields);
+        for &length in lengths {
+            offset += write_varint(&mut writer, length)?;
+        }
+    }
+
+    Ok(offset)
+}
+
+pub fn read_field_lengths<R: Read>(mut reader: &mut R) -> std::io::Result<Vec<Vec<u64>>> {
+    let (len, _) = read_varint(&mut reader)?;
+    let (fields, _) = read_varint(&mut reader)?;
+
+    let mut documents = Vec::with_capacity(len as usize);
+    for _ in 0..len {
+        let mut lengths = Vec::with_capacity(fields as usize);
+        for _ in 0..fields {
+            lengths.push(read_varint(&mut reader)?.0);
+        }
+        documents.push(lengths);
+    }
+
+    Ok(documents)
+}
+
+// Reads the field lengths of the segment in `dir`, if it kept fields.
+pub(crate) fn read_segment_fields(dir: &Path) -> std::io::Result<Option<Vec<Vec<u64>>>> {
+    match std::fs::read(dir.join(FIELDS_FILE)) {
+        Ok(bytes) => read_field_lengths(&mut bytes.as_slice()).map(Some),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Postings are split into blocks of `POSTINGS_BLOCK_SIZE`, with a skip
+// table up front so a reader can jump to the block holding a document
+// without decoding the ones before it.
+//
+// +-----------------+---------------------------+
+// | N      (varint) | Total Frequency (varint)  |
+// +-----------------+---------------------------+
+// +-----------------------------+-----------------------+--------------------------------+
+// | Last Document Diff (varint) | Block Length (varint) | Positions Offset Diff (varint) |
+// +-----------------------------+-----------------------+--------------------------------+
+// +-------------------------+----------------------+
+// | Max Frequency (varint)  | Min Length (varint)  |
+// +-------------------------+----------------------+
+// (Once per block)
+// +----------------------------------+
+// | Block (Block Length bytes)       | ..
+// +----------------------------------+
+// (Once per block)
+//
+// Each block is encoded by `codec`. With `Codec::Varint`, document IDs are
+// stored as the difference from the previous posting's, interleaved with the
+// frequencies, so the blocks together are one stream of differences. The
+// last document of each block is stored as the difference from the last of
+// the block before.
+// `positions` holds where the positions of each block start, relative to
+// those of the first block, and may be left empty if there are none.
+// `bounds` holds the bound of each block's postings, which lets a search
+// skip a block which can't score enough without decoding it, and may be left
+// empty if they're not needed (they're written as 0).
 pub fn write_postings<I: Iterator<Item = Posting>, W: Write>(
+    codec: Codec,
     n: u64,
     iter: I,
+    positions: &[u64],
+    bounds: &[TermBound],
     mut writer: &mut W,
 ) -> std::io::Result<usize> {
-    let mut offset = write_varint(&mut writer, n as u64)?;
+    // The blocks are encoded first, as the skip table needs their lengths.
+    let mut blocks = Vec::new();
+    // (last document, length) of each block.
+    let mut skips = Vec::with_capacity(block_count(n));
+    let mut block = Vec::with_capacity(POSTINGS_BLOCK_SIZE);
     let mut previous: u64 = 0;
+    let mut frequency = 0;
+
+    for (i, posting) in iter.enumerate() {
+        assert!(posting.document >= block.last().map_or(previous, |p: &Posting| p.document));
+        block.push(posting);
+        frequency += posting.frequency;
+
+        if block.len() == POSTINGS_BLOCK_SIZE || i as u64 + 1 == n {
+            let start = blocks.len();
+            codec.encode_block(&block, previous, &mut blocks)?;
+
+            previous = posting.document;
+            skips.push((previous, blocks.len() - start));
+            block.clear();
+        }
+    }
+
+    assert_eq!(skips.len(), block_count(n));
+    assert!(positions.is_empty() || positions.len() == skips.len());
+    assert!(bounds.is_empty() || bounds.len() == skips.len());
+
+    let mut offset = write_varint(&mut writer, n)?;
+    offset += write_varint(&mut writer, frequency)?;
+    let mut last = 0;
+    let mut position = 0;
+
+    for (i, &(document, length)) in skips.iter().enumerate() {
+        let block_position = positions.get(i).copied().unwrap_or(0);
+        let bound = bounds.get(i).copied().unwrap_or(TermBound {
+            max_frequency: 0,
+            min_length: 0,
+        });
 
-    for posting in iter {
-        // Let the document ID be the diff, which we encode in varint format.
-        assert!(posting.document >= previous);
-        let diff: u64 = posting.document - previous;
-        previous = posting.document;
+        offset += write_varint(&mut writer, document - last)?;
+        offset += write_varint(&mut writer, length as u64)?;
+        offset += write_varint(&mut writer, block_position - position)?;
+        offset += write_varint(&mut writer, bound.max_frequency)?;
+        offset += write_varint(&mut writer, bound.min_length)?;
 
-        offset += write_varint(&mut writer, diff as u64)?;
-        offset += write_varint(&mut writer, posting.frequency as u64)?;
+        last = document;
+        position = block_position;
     }
 
+    writer.write_all(&blocks)?;
+    offset += blocks.len();
+
     Ok(offset)
 }
 
@@ -362,7 +2186,7 @@ pub fn write_term<W: Write>(buf: &[u8], ptr: u64, mut writer: &mut W) -> std::io
     offset += buf.len();
 
     // Write ptr
-    offset += write_varint(&mut writer, ptr as u64)?;
+    offset += write_varint(&mut writer, ptr)?;
 
     Ok(offset)
 }
@@ -376,7 +2200,23 @@ pub fn read_term<R: Read>(mut reader: &mut R) -> std::io::Result<(String, u64)>
 
     let (ptr, _offset) = read_varint(&mut reader)?;
 
-    Ok((String::from_utf8(data).unwrap(), ptr as u64))
+    Ok((String::from_utf8(data).unwrap(), ptr))
+}
+
+// In blocks.bin, each term is followed by its bound.
+pub fn write_bound<W: Write>(bound: &TermBound, mut writer: &mut W) -> std::io::Result<usize> {
+    let offset = write_varint(&mut writer, bound.max_frequency)?;
+    Ok(offset + write_varint(&mut writer, bound.min_length)?)
+}
+
+pub fn read_bound<R: Read>(mut reader: &mut R) -> std::io::Result<TermBound> {
+    let (max_frequency, _) = read_varint(&mut reader)?;
+    let (min_length, _) = read_varint(&mut reader)?;
+
+    Ok(TermBound {
+        max_frequency,
+        min_length,
+    })
 }
 
 pub fn read_terms<R: Read, C: Extend<(String, u64)>>(
@@ -395,32 +2235,97 @@ pub fn read_terms<R: Read, C: Extend<(String, u64)>>(
 }
 
 pub fn read_postings<R: Read, C: Extend<Posting>>(
+    codec: Codec,
     mut reader: &mut R,
     container: &mut C,
 ) -> std::io::Result<usize> {
     let (len, mut offset) = read_varint(&mut reader)?;
+    offset += read_varint(&mut reader)?.1;
+
+    // Reading the whole list, so only the length of each block is needed.
+    let mut lengths = Vec::with_capacity(block_count(len));
+    for _ in 0..block_count(len) {
+        let (_, last) = read_varint(&mut reader)?;
+        let (length, size) = read_varint(&mut reader)?;
+        let (_, positions) = read_varint(&mut reader)?;
+        let (_, max_frequency) = read_varint(&mut reader)?;
+        let (_, min_length) = read_varint(&mut reader)?;
+
+        offset += last + size + positions + max_frequency + min_length;
+        lengths.push(length as usize);
+    }
 
     let mut previous: u64 = 0;
-    let mut postings = Vec::new();
+    let mut postings = Vec::with_capacity(len as usize);
+    let mut bytes = Vec::new();
 
-    for _ in 0..len {
-        let (diff, off) = read_varint(&mut reader)?;
-        offset += off;
+    for (i, &length) in lengths.iter().enumerate() {
+        bytes.resize(length, 0);
+        reader.read_exact(bytes.as_mut_slice())?;
+        offset += length;
 
-        let document = (diff as u64) + previous;
-        previous = document;
+        let count = (len as usize - i * POSTINGS_BLOCK_SIZE).min(POSTINGS_BLOCK_SIZE);
+        codec.decode_block(&bytes, count, previous, &mut postings)?;
+        previous = postings.last().map_or(previous, |p| p.document);
+    }
 
-        let (frequency, off) = read_varint(&mut reader)?;
-        offset += off;
+    container.extend(postings);
 
-        postings.push(Posting {
-            document,
-            frequency: frequency as u64,
-        });
-        offset += 4;
+    Ok(offset)
+}
+
+// Positions are written in the same order as the postings they belong to,
+// each posting's `frequency` positions in turn.
+//
+// +---------------------------+-------------------------------+
+// | First Position (varint)   | Position Diff (varint)        | ..
+// +---------------------------+-------------------------------+
+// (Once per posting)
+//
+// Positions count terms from the start of the document, and each one after
+// the first is stored as the difference from the one before.
+pub fn write_positions<I: Iterator<Item = u64>, W: Write>(
+    frequencies: I,
+    positions: &[u64],
+    mut writer: &mut W,
+) -> std::io::Result<usize> {
+    let mut offset = 0;
+    let mut start = 0;
+
+    for frequency in frequencies {
+        let end = start + frequency as usize;
+        let mut previous = 0;
+
+        for &position in &positions[start..end] {
+            assert!(position >= previous);
+            offset += write_varint(&mut writer, position - previous)?;
+            previous = position;
+        }
+
+        start = end;
     }
 
-    container.extend(postings);
+    Ok(offset)
+}
+
+pub fn read_positions<I: Iterator<Item = u64>, R: Read>(
+    frequencies: I,
+    mut reader: &mut R,
+    container: &mut Vec<u64>,
+) -> std::io::Result<usize> {
+    let mut offset = 0;
+
+    for frequency in frequencies {
+        let mut previous = 0;
+
+        for _ in 0..frequency {
+            let (diff, off) = read_varint(&mut reader)?;
+            offset += off;
+
+            previous += diff;
+            container.push(previous);
+        }
+    }
 
     Ok(offset)
 }
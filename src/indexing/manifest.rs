@@ -0,0 +1,282 @@
+use crate::indexing::codec::Codec;
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Write};
+use std::path::Path;
+
+// Name of the manifest inside an index directory.
+pub const MANIFEST: &str = "manifest.txt";
+
+const VERSION: u64 = 5;
+
+// The manifest lists every segment of an index, in document order, along
+// with each of the segment's component files and their sizes in bytes. It is
+// written last, once every other file has been flushed, so an index directory
+// without a manifest (or with files that don't match it) was not written
+// completely.
+//
+// The format is plain text, one entry per line. `file` and `deletes` entries
+// belong to the `segment` above them, and file names are relative to the
+// segment's directory,
+//
+//   version 5
+//   codec varint
+//   generation 3
+//   segment seg-000000 600
+//   file documents.bin 4321
+//   file postings.bin 98765
+//   ..
+//   deletes deletes-000002.bin 4
+//   file deletes-000002.bin 75
+//   segment seg-000001 25
+//   ..
+#[derive(Debug, Default, PartialEq)]
+pub struct Manifest {
+    // How the postings of every segment are compressed. Indexes written
+    // before there was a choice have no `codec` line, and use varints.
+    pub codec: Codec,
+    // Number used to name the next segment.
+    pub generation: u64,
+    pub segments: Vec<SegmentInfo>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SegmentInfo {
+    pub name: String,
+    // Number of documents in the segment, including deleted ones.
+    pub documents: u64,
+    pub files: Vec<(String, u64)>,
+
+    // The deletion bitmap of the segment, if any of its documents have been
+    // deleted, and the number of deleted documents.
+    pub deletes: Option<String>,
+    pub deleted: u64,
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+impl SegmentInfo {
+    pub fn new(name: &str, documents: u64) -> Self {
+        SegmentInfo {
+            name: name.to_string(),
+            documents,
+            files: Vec::new(),
+            deletes: None,
+            deleted: 0,
+        }
+    }
+
+    // Number of documents in the segment which haven't been deleted.
+    pub fn live(&self) -> u64 {
+        self.documents - self.deleted
+    }
+
+    // Records the component `file` of this segment, using its current size.
+    // `dir` is the index directory.
+    pub fn add<P: AsRef<Path>>(&mut self, dir: P, file: &str) -> std::io::Result<()> {
+        let size = fs::metadata(dir.as_ref().join(&self.name).join(file))?.len();
+        self.files.push((file.to_string(), size));
+        Ok(())
+    }
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Manifest::default()
+    }
+
+    // Reserves a number for a new file. Files are never overwritten in
+    // place; a new generation is written and the old one removed once the
+    // manifest points at the new one.
+    pub fn next_generation(&mut self) -> u64 {
+        self.generation += 1;
+        self.generation - 1
+    }
+
+    // Reserves a name for a new segment.
+    pub fn next_segment_name(&mut self) -> String {
+        format!("seg-{:06}", self.next_generation())
+    }
+
+    // Total number of documents across every segment.
+    pub fn documents(&self) -> u64 {
+        self.segments.iter().map(|s| s.documents).sum()
+    }
+
+    // Writes the manifest into `dir`. The manifest is written to a temporary
+    // file first and renamed into place, so it either exists in full or not
+    // at all.
+    pub fn write<P: AsRef<Path>>(&self, dir: P) -> std::io::Result<()> {
+        let dir = dir.as_ref();
+        let tmp = dir.join(format!("{}.tmp", MANIFEST));
+
+        {
+            let mut out = BufWriter::new(File::create(&tmp)?);
+
+            writeln!(out, "version {}", VERSION)?;
+            writeln!(out, "codec {}", self.codec.name())?;
+            writeln!(out, "generation {}", self.generation)?;
+            for segment in &self.segments {
+                writeln!(out, "segment {} {}", segment.name, segment.documents)?;
+                if let Some(deletes) = &segment.deletes {
+                    writeln!(out, "deletes {} {}", deletes, segment.deleted)?;
+                }
+                for (name, size) in &segment.files {
+                    writeln!(out, "file {} {}", name, size)?;
+                }
+            }
+
+            out.flush()?;
+            out.get_ref().sync_all()?;
+        }
+
+        fs::rename(tmp, dir.join(MANIFEST))
+    }
+
+    // Removes the manifest from `dir`, marking the index as incomplete. This
+    // is done before any component file is rewritten.
+    pub fn remove<P: AsRef<Path>>(dir: P) -> std::io::Result<()> {
+        match fs::remove_file(dir.as_ref().join(MANIFEST)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    // Returns true if `dir` holds a manifest, complete or not.
+    pub fn exists<P: AsRef<Path>>(dir: P) -> bool {
+        dir.as_ref().join(MANIFEST).exists()
+    }
+
+    pub fn read<P: AsRef<Path>>(dir: P) -> std::io::Result<Manifest> {
+        let file = match File::open(dir.as_ref().join(MANIFEST)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(invalid(format!(
+                    "no {} in {} (the index is missing or was not completely written)",
+                    MANIFEST,
+                    dir.as_ref().display()
+                )))
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut manifest = Manifest::new();
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let fields = line.split_ascii_whitespace().collect::<Vec<_>>();
+            let bad = || invalid(format!("bad manifest entry '{}'", line));
+
+            match fields.as_slice() {
+                [] => continue,
+                ["version", v] => {
+                    if v.parse::<u64>().ok() != Some(VERSION) {
+                        return Err(invalid(format!("unsupported index version {}", v)));
+                    }
+                }
+                ["codec", name] => {
+                    manifest.codec = Codec::from_name(name)
+                        .ok_or_else(|| invalid(format!("unknown codec {}", name)))?;
+                }
+                ["generation", n] => {
+                    manifest.generation = n.parse::<u64>().map_err(|_| bad())?;
+                }
+                ["segment", name, documents] => {
+                    let documents = documents.parse::<u64>().map_err(|_| bad())?;
+                    manifest.segments.push(SegmentInfo::new(name, documents));
+                }
+                ["deletes", name, deleted] => {
+                    let deleted = deleted.parse::<u64>().map_err(|_| bad())?;
+                    let segment = manifest.segments.last_mut().ok_or_else(bad)?;
+                    segment.deletes = Some(name.to_string());
+                    segment.deleted = deleted;
+                }
+                ["file", name, size] => {
+                    let size = size.parse::<u64>().map_err(|_| bad())?;
+                    manifest
+                        .segments
+                        .last_mut()
+                        .ok_or_else(bad)?
+                        .files
+                        .push((name.to_string(), size));
+                }
+                _ => return Err(bad()),
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    // Checks that every component file exists and has the recorded size.
+    pub fn verify<P: AsRef<Path>>(&self, dir: P) -> std::io::Result<()> {
+        for segment in &self.segments {
+            for (name, size) in &segment.files {
+                let path = dir.as_ref().join(&segment.name).join(name);
+
+                let actual = match fs::metadata(&path) {
+                    Ok(meta) => meta.len(),
+                    Err(e) if e.kind() == ErrorKind::NotFound => {
+                        return Err(invalid(format!("index file {} is missing", path.display())))
+                    }
+                    Err(e) => return Err(e),
+                };
+
+                if actual != *size {
+                    return Err(invalid(format!(
+                        "index file {} is {} bytes, expected {}",
+                        path.display(),
+                        actual,
+                        size
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::testing::TempDir;
+
+    fn scratch(name: &str) -> TempDir {
+        let dir = TempDir::new(&format!("manifest-{}", name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = scratch("round-trip");
+
+        let mut manifest = Manifest::new();
+        manifest.codec = Codec::OptPfor;
+        let name = manifest.next_segment_name();
+        fs::create_dir_all(dir.join(&name)).unwrap();
+        fs::write(dir.join(&name).join("a.bin"), [1, 2, 3]).unwrap();
+
+        let mut segment = SegmentInfo::new(&name, 3);
+        segment.add(&dir, "a.bin").unwrap();
+        manifest.segments.push(segment);
+        manifest.write(&dir).unwrap();
+
+        let read = Manifest::read(&dir).unwrap();
+        assert_eq!(read, manifest);
+        assert_eq!(read.generation, 1);
+        assert!(read.verify(&dir).is_ok());
+
+        // A truncated component is detected.
+        fs::write(dir.join(&name).join("a.bin"), [1]).unwrap();
+        assert!(read.verify(&dir).is_err());
+    }
+
+    #[test]
+    fn missing_manifest() {
+        let dir = scratch("missing");
+        assert!(Manifest::read(&dir).is_err());
+    }
+}
@@ -0,0 +1,326 @@
+use crate::indexing::deletes::Deletes;
+use crate::indexing::index::POSITIONS_FILE;
+use crate::indexing::manifest::Manifest;
+use crate::indexing::runs::merge_runs;
+use crate::indexing::segment::{
+    read_segment_documents, remove_segment, SegmentReader, SegmentWriter,
+};
+use std::ops::Range;
+use std::path::Path;
+
+// Decides which segments to merge. Segments are grouped into tiers by their
+// number of documents, each tier holding segments roughly `segments_per_tier`
+// times larger than the tier below. Whenever `segments_per_tier` neighbouring
+// segments fall into the same tier they are merged into a single segment of
+// the next tier up.
+//
+// Only neighbouring segments are merged, so documents keep their relative
+// order (and their IDs, as long as nothing before them is merged away).
+// Segments are sized by their live documents, as deleted documents are
+// dropped when they are merged.
+#[derive(Debug, Clone)]
+pub struct TieredMergePolicy {
+    pub segments_per_tier: usize,
+    // Segments with at most this many documents all belong to the lowest
+    // tier.
+    pub floor_documents: u64,
+}
+
+impl Default for TieredMergePolicy {
+    fn default() -> Self {
+        TieredMergePolicy {
+            segments_per_tier: 10,
+            floor_documents: 1000,
+        }
+    }
+}
+
+impl TieredMergePolicy {
+    fn tier(&self, documents: u64) -> u32 {
+        let factor = self.segments_per_tier.max(2) as f64;
+
+        if documents <= self.floor_documents {
+            0
+        } else {
+            let ratio = documents as f64 / self.floor_documents.max(1) as f64;
+            ratio.log(factor).floor() as u32 + 1
+        }
+    }
+
+    // Given the size of each segment, returns the range of segments which
+    // should be merged next, if any. The newest (smallest) segments are
+    // considered first.
+    pub fn find_merge(&self, segments: &[u64]) -> Option<Range<usize>> {
+        let width = self.segments_per_tier.max(2);
+        if segments.len() < width {
+            return None;
+        }
+
+        (0..=segments.len() - width).rev().find_map(|start| {
+            let tier = self.tier(segments[start]);
+            let range = start..start + width;
+
+            if segments[range.clone()]
+                .iter()
+                .all(|&s| self.tier(s) == tier)
+            {
+                Some(range)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+// Merges segments of the index in `dir` until `policy` finds nothing left to
+// merge. Returns the number of merges performed.
+pub fn merge_segments<P: AsRef<Path>>(
+    dir: P,
+    policy: &TieredMergePolicy,
+) -> std::io::Result<usize> {
+    let dir = dir.as_ref();
+    let mut merges = 0;
+
+    loop {
+        let manifest = Manifest::read(dir)?;
+        let sizes = manifest
+            .segments
+            .iter()
+            .map(|s| s.live())
+            .collect::<Vec<_>>();
+
+        match policy.find_merge(&sizes) {
+            Some(range) => {
+                merge_range(dir, manifest, range)?;
+                merges += 1;
+            }
+            None => return Ok(merges),
+        }
+    }
+}
+
+// Merges every segment of the index in `dir` into one, reclaiming the space
+// used by deleted documents.
+pub fn force_merge<P: AsRef<Path>>(dir: P) -> std::io::Result<()> {
+    let dir = dir.as_ref();
+    let manifest = Manifest::read(dir)?;
+
+    if manifest.segments.len() > 1 || manifest.segments.iter().any(|s| s.deleted > 0) {
+        let range = 0..manifest.segments.len();
+        merge_range(dir, manifest, range)?;
+    }
+
+    Ok(())
+}
+
+// Replaces the segments in `range` with a single new segment holding all of
+// their live documents, in order. The old segments are only removed once the
+// new manifest is in place, so an interrupted merge leaves the index as it
+// was.
+fn merge_range(dir: &Path, mut manifest: Manifest, range: Range<usize>) -> std::io::Result<()> {
+    manifest.verify(dir)?;
+
+    // Positions are only kept if every merged segment has them.
+    let positions = manifest.segments[range.clone()]
+        .iter()
+        .all(|s| s.files.iter().any(|(file, _)| file == POSITIONS_FILE));
+
+    let name = manifest.next_segment_name();
+
+    let mut documents = Vec::new();
+    let mut readers = Vec::new();
+
+    for i in range.clone() {
+        let segment_dir = dir.join(&manifest.segments[i].name);
+        let deletes = Deletes::for_segment(dir, &manifest, i)?;
+        let base = documents.len() as u64;
+
+        documents.extend(
+            read_segment_documents(&segment_dir)?
+                .into_iter()
+                .enumerate()
+                .filter(|(doc, _)| !deletes.contains(*doc as u64))
+                .map(|(_, document)| document),
+        );
+        readers.push(SegmentReader::open(
+            &segment_dir,
+            base,
+            deletes,
+            manifest.codec,
+        )?);
+    }
+
+    let mut writer = SegmentWriter::create(dir.join(&name), documents, positions, manifest.codec)?;
+    merge_runs(readers, |term, postings, positions| {
+        writer.push(
+            term,
+            postings.len() as u64,
+            postings.iter().copied(),
+            positions,
+        )
+    })?;
+
+    let merged = writer.finish(dir, &name)?;
+    let old = manifest
+        .segments
+        .splice(range, std::iter::once(merged))
+        .collect::<Vec<_>>();
+
+    manifest.write(dir)?;
+
+    for segment in old {
+        remove_segment(dir, &segment.name)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::builder::IndexBuilder;
+    use crate::indexing::deletes::delete_documents;
+    use crate::indexing::index::DiskIndex;
+    use crate::indexing::testing::TempDir;
+    use std::fs;
+
+    #[test]
+    fn find_merge() {
+        let policy = TieredMergePolicy {
+            segments_per_tier: 3,
+            floor_documents: 10,
+        };
+
+        assert_eq!(policy.find_merge(&[5, 5]), None);
+        assert_eq!(policy.find_merge(&[5, 5, 5]), Some(0..3));
+        assert_eq!(policy.find_merge(&[100, 5, 5, 5]), Some(1..4));
+        assert_eq!(policy.find_merge(&[100, 5, 100, 5, 5]), None);
+        assert_eq!(policy.find_merge(&[25, 28, 20, 5]), Some(0..3));
+    }
+
+    #[test]
+    fn segments() {
+        let dir = TempDir::new("merge");
+
+        let docs = (0..30u64)
+            .map(|n| {
+                (
+                    format!("doc{}", n),
+                    vec![format!("t{}", n % 4), "all".to_string()],
+                )
+            })
+            .collect::<Vec<_>>();
+
+        for chunk in docs.chunks(3) {
+            let mut builder = IndexBuilder::new();
+            for (name, terms) in chunk {
+                builder.add_document(name, terms).unwrap();
+            }
+            builder.write_segment(&dir).unwrap();
+        }
+
+        assert_eq!(Manifest::read(&dir).unwrap().segments.len(), 10);
+
+        let check = |dir: &Path| {
+            let index = DiskIndex::from_disk(dir).unwrap();
+            assert_eq!(index.len(), 30);
+            assert_eq!(index.postings("all").unwrap().len(), 30);
+
+            let t1 = index.postings("t1").unwrap();
+            assert_eq!(
+                t1.iter().map(|p| p.document).collect::<Vec<_>>(),
+                (0..30).filter(|n| n % 4 == 1).collect::<Vec<_>>()
+            );
+            assert_eq!(index.document(t1[2].document), "doc9");
+        };
+
+        check(dir.path());
+
+        let policy = TieredMergePolicy {
+            segments_per_tier: 4,
+            floor_documents: 10,
+        };
+        assert_eq!(merge_segments(&dir, &policy).unwrap(), 2);
+        assert_eq!(Manifest::read(&dir).unwrap().segments.len(), 4);
+        check(dir.path());
+
+        force_merge(&dir).unwrap();
+        assert_eq!(Manifest::read(&dir).unwrap().segments.len(), 1);
+        check(dir.path());
+
+        // Only the merged segment is left on disk.
+        let entries = fs::read_dir(&dir)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().is_dir())
+            .count();
+        assert_eq!(entries, 1);
+    }
+
+    #[test]
+    fn deletes_and_updates() {
+        let dir = TempDir::new("deletes");
+
+        for chunk in [["a", "b", "c"], ["d", "e", "f"]] {
+            let mut builder = IndexBuilder::new().positions(true);
+            for name in chunk {
+                builder.add_document(name, vec![name, "all"]).unwrap();
+            }
+            builder.write_segment(&dir).unwrap();
+        }
+
+        assert_eq!(delete_documents(&dir, ["b", "e", "missing"]).unwrap(), 2);
+        assert_eq!(delete_documents(&dir, ["b"]).unwrap(), 0);
+
+        let index = DiskIndex::from_disk(&dir).unwrap();
+        assert_eq!(index.len(), 4);
+        assert!(index.postings("b").unwrap().is_empty());
+        assert_eq!(index.postings("all").unwrap().len(), 4);
+
+        // Updating "c" deletes the old copy and adds the new one at the end.
+        let mut builder = IndexBuilder::new().positions(true);
+        builder.add_document("c", vec!["all", "changed"]).unwrap();
+        builder.update(&dir).unwrap();
+
+        let names = |dir: &Path| {
+            let index = DiskIndex::from_disk(dir).unwrap();
+            let all = index.postings("all").unwrap();
+            all.iter()
+                .map(|p| index.document(p.document).to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(dir.path()), vec!["a", "d", "f", "c"]);
+
+        let manifest = Manifest::read(&dir).unwrap();
+        assert_eq!(manifest.segments[0].deleted, 2);
+        // Only the latest bitmap of each segment is kept.
+        let bitmaps = fs::read_dir(dir.join(&manifest.segments[0].name))
+            .unwrap()
+            .filter(|e| {
+                let name = e.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with("deletes-")
+            })
+            .count();
+        assert_eq!(bitmaps, 1);
+
+        let before = DiskIndex::from_disk(&dir)
+            .unwrap()
+            .search("all changed")
+            .unwrap()
+            .collect::<Vec<_>>();
+
+        // Merging drops the deleted documents without changing any scores.
+        force_merge(&dir).unwrap();
+        let manifest = Manifest::read(&dir).unwrap();
+        assert_eq!(manifest.segments.len(), 1);
+        assert_eq!(manifest.segments[0].documents, 4);
+        assert_eq!(manifest.segments[0].deletes, None);
+        assert_eq!(names(dir.path()), vec!["a", "d", "f", "c"]);
+
+        let index = DiskIndex::from_disk(&dir).unwrap();
+        assert!(index.postings("b").unwrap().is_empty());
+        // Positions of the deleted documents are dropped along with them.
+        assert_eq!(index.positions("all").unwrap().1, vec![1, 1, 1, 0]);
+        let after = index.search("all changed").unwrap().collect::<Vec<_>>();
+        assert_eq!(before[0], (after[0].0, after[0].1 + 3));
+    }
+}
@@ -1,2 +1,17 @@
+pub mod builder;
+pub mod cache;
+pub mod codec;
+pub mod deletes;
+pub mod fuzzy;
 pub mod index;
+pub mod manifest;
+pub mod merge;
+pub mod parallel;
+pub mod postings;
+pub mod runs;
+pub mod segment;
+pub mod stats;
+#[cfg(test)]
+pub mod testing;
 pub mod varint;
+pub mod wildcard;
@@ -0,0 +1,268 @@
+use crate::indexing::builder::IndexBuilder;
+use crate::parsing::analyzer::ParsedDocument;
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Splits the documents into batches which are analyzed and inverted on worker
+// threads. Each batch becomes a partial index (an `IndexBuilder` of its own),
+// and the partial indexes are appended to the destination builder in the
+// order the batches were read. Document IDs are therefore assigned exactly as
+// they would be by a single thread, and the index written out is byte for
+// byte the same.
+pub struct ParallelIndexer {
+    threads: usize,
+    batch_size: usize,
+}
+
+impl ParallelIndexer {
+    pub fn new(threads: usize) -> Self {
+        ParallelIndexer {
+            threads: threads.max(1),
+            batch_size: 1000,
+        }
+    }
+
+    // Sets the number of documents handed to a worker at a time.
+    pub fn batch_size(mut self, documents: usize) -> Self {
+        self.batch_size = documents.max(1);
+        self
+    }
+
+    // Indexes `documents` into `builder`. `analyze` runs on the worker threads
+    // and turns each input (an already parsed document, or the raw text of
+    // one) into the documents it holds.
+    pub fn run<I, D, F, P>(
+        &self,
+        builder: &mut IndexBuilder,
+        documents: I,
+        analyze: F,
+    ) -> std::io::Result<()>
+    where
+        I: IntoIterator<Item = std::io::Result<D>>,
+        D: Send,
+        F: Fn(D) -> P + Sync,
+        P: IntoIterator<Item = ParsedDocument>,
+    {
+        // Bounding the queue stops the reader running too far ahead of the
+        // workers.
+        let (batch_tx, batch_rx) = sync_channel::<(usize, Vec<D>)>(self.threads * 2);
+        let (partial_tx, partial_rx) = channel::<(usize, IndexBuilder)>();
+        let batch_rx = Arc::new(Mutex::new(batch_rx));
+        let positions = builder.keeps_positions();
+        let fields = builder.keeps_fields();
+
+        thread::scope(|scope| {
+            for _ in 0..self.threads {
+                let batch_rx = Arc::clone(&batch_rx);
+                let partial_tx = partial_tx.clone();
+                let analyze = &analyze;
+
+                scope.spawn(move || loop {
+                    // The lock is only held while waiting for the next batch.
+                    let next = batch_rx.lock().unwrap().recv();
+                    let (n, batch) = match next {
+                        Ok(next) => next,
+                        Err(_) => break,
+                    };
+
+                    let mut partial = IndexBuilder::new().positions(positions).fields(fields);
+                    for doc in batch.into_iter().flat_map(analyze) {
+                        // Without a memory budget nothing is flushed, so this
+                        // can't fail.
+                        partial.add_parsed_document(&doc).unwrap();
+                    }
+
+                    if partial_tx.send((n, partial)).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(partial_tx);
+
+            let mut merger = Merger {
+                builder,
+                next: 0,
+                pending: BTreeMap::new(),
+            };
+
+            let result = self.send_batches(documents, &batch_tx, &mut merger, &partial_rx);
+
+            // Closing the queue lets the workers finish once it's empty.
+            drop(batch_tx);
+
+            result.and_then(|_| merger.drain(&partial_rx, true))
+        })
+    }
+
+    // Reads `documents` into batches and queues them for the workers,
+    // appending finished partial indexes along the way.
+    fn send_batches<I, D>(
+        &self,
+        documents: I,
+        batch_tx: &SyncSender<(usize, Vec<D>)>,
+        merger: &mut Merger,
+        partial_rx: &Receiver<(usize, IndexBuilder)>,
+    ) -> std::io::Result<()>
+    where
+        I: IntoIterator<Item = std::io::Result<D>>,
+    {
+        let mut sent = 0;
+        let mut batch = Vec::with_capacity(self.batch_size);
+
+        for doc in documents {
+            batch.push(doc?);
+
+            if batch.len() == self.batch_size {
+                let full = std::mem::replace(&mut batch, Vec::with_capacity(self.batch_size));
+                batch_tx.send((sent, full)).unwrap();
+                sent += 1;
+
+                merger.drain(partial_rx, false)?;
+            }
+        }
+
+        if !batch.is_empty() {
+            batch_tx.send((sent, batch)).unwrap();
+        }
+
+        Ok(())
+    }
+}
+
+// Appends partial indexes to the builder in batch order, holding on to any
+// which arrive early.
+struct Merger<'a> {
+    builder: &'a mut IndexBuilder,
+    next: usize,
+    pending: BTreeMap<usize, IndexBuilder>,
+}
+
+impl<'a> Merger<'a> {
+    // Appends every partial index which is ready. If `wait` is set, blocks
+    // until all workers have finished.
+    fn drain(&mut self, rx: &Receiver<(usize, IndexBuilder)>, wait: bool) -> std::io::Result<()> {
+        loop {
+            let received = if wait {
+                rx.recv().ok()
+            } else {
+                rx.try_recv().ok()
+            };
+
+            match received {
+                Some((n, partial)) => {
+                    self.pending.insert(n, partial);
+                }
+                None => break,
+            }
+
+            while let Some(partial) = self.pending.remove(&self.next) {
+                self.builder.append(partial)?;
+                self.next += 1;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::index::{
+        BLOCKS_FILE, DOCUMENTS_FILE, FIELDS_FILE, INDEX_FILE, POSITIONS_FILE, POSTINGS_FILE,
+    };
+    use crate::indexing::testing::TempDir;
+    use crate::parsing::analyzer::Analyzer;
+    use std::fs;
+
+    #[test]
+    fn matches_single_threaded() {
+        let root = TempDir::new("parallel");
+
+        let docs = (0..500u64)
+            .map(|n| {
+                let text = (0..(n % 17 + 1))
+                    .map(|t| format!("w{}", (n * 11 + t * 5) % 211))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                (format!("doc{}", n), text)
+            })
+            .collect::<Vec<_>>();
+
+        // The same documents as a collection, which is analyzed whole on one
+        // thread and a document at a time on the workers.
+        let collection = docs
+            .iter()
+            .map(|(name, text)| {
+                format!(
+                    "<DOC>\n<DOCNO> {} </DOCNO>\n<HL> {} </HL>\n<TEXT>\n{}\n</TEXT>\n</DOC>\n",
+                    name,
+                    &text[..text.find(' ').unwrap_or(text.len())],
+                    text
+                )
+            })
+            .collect::<String>();
+
+        let analyzer = Analyzer::new();
+
+        let mut single = IndexBuilder::new();
+        for (name, text) in &docs {
+            single.add_document(name, analyzer.terms(text)).unwrap();
+        }
+        single.write(root.join("single")).unwrap();
+
+        let mut parallel = IndexBuilder::new()
+            .memory_budget(8192)
+            .temp_dir(root.join("runs"));
+        ParallelIndexer::new(4)
+            .batch_size(7)
+            .run(&mut parallel, docs.into_iter().map(Ok), |(name, text)| {
+                Some(ParsedDocument {
+                    terms: analyzer.terms(&text).collect(),
+                    name,
+                    fields: Vec::new(),
+                })
+            })
+            .unwrap();
+        parallel.write(root.join("parallel")).unwrap();
+
+        let mut single_raw = IndexBuilder::new().positions(true).fields(true);
+        for doc in analyzer.documents(collection.as_bytes()) {
+            single_raw.add_parsed_document(&doc).unwrap();
+        }
+        single_raw.write(root.join("single-raw")).unwrap();
+
+        let mut parallel_raw = IndexBuilder::new()
+            .positions(true)
+            .fields(true)
+            .memory_budget(8192)
+            .temp_dir(root.join("raw-runs"));
+        ParallelIndexer::new(4)
+            .batch_size(7)
+            .run(
+                &mut parallel_raw,
+                analyzer.raw_documents(collection.as_bytes()),
+                |raw| analyzer.documents(&raw).collect::<Vec<_>>(),
+            )
+            .unwrap();
+        parallel_raw.write(root.join("parallel-raw")).unwrap();
+
+        let files = [DOCUMENTS_FILE, POSTINGS_FILE, BLOCKS_FILE, INDEX_FILE];
+        let with_fields = [&files[..], &[POSITIONS_FILE, FIELDS_FILE]].concat();
+        for (single, parallel, files) in &[
+            ("single", "parallel", &files[..]),
+            ("single-raw", "parallel-raw", &with_fields[..]),
+        ] {
+            for name in files.iter() {
+                assert_eq!(
+                    fs::read(root.join(single).join("seg-000000").join(name)).unwrap(),
+                    fs::read(root.join(parallel).join("seg-000000").join(name)).unwrap(),
+                    "{}",
+                    name
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use crate::indexing::codec::Codec;
+use crate::indexing::index::{Posting, TermBound};
+use crate::indexing::varint::read_varint;
+
+// Number of postings in each block of a postings list. Every block but the
+// last is full.
+pub const POSTINGS_BLOCK_SIZE: usize = 128;
+
+// Number of blocks a postings list of `n` postings is split into.
+pub fn block_count(n: u64) -> usize {
+    (n as usize).div_ceil(POSTINGS_BLOCK_SIZE)
+}
+
+// A list of postings walked in document order, which can skip ahead.
+pub trait Cursor {
+    // The posting the cursor is on, or None once it has passed the last.
+    fn posting(&self) -> Option<Posting>;
+
+    // Moves on to the first posting for `document` or a later document. The
+    // cursor never moves backwards, so if it's already past `document` it
+    // stays where it is.
+    fn next_geq(&mut self, document: u64) -> std::io::Result<Option<Posting>>;
+
+    // Number of postings in the whole list, or an estimate of it.
+    fn len(&self) -> u64;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The last document of the block which would hold `document`, and the
+    // bound of the block's postings, found without decoding the block. None
+    // if the cursor doesn't split its postings into blocks, or has none left
+    // at or after `document`.
+    fn block_bound(&self, _document: u64) -> Option<(u64, TermBound)> {
+        None
+    }
+}
+
+// An entry of a postings list's skip table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Skip {
+    // The last document in the block.
+    last: u64,
+    // Where the block starts, relative to the first block.
+    offset: usize,
+    // Where the positions of the block's postings start, relative to those
+    // of the first block.
+    positions: u64,
+    bound: TermBound,
+}
+
+// Walks a postings list in document order. Only the skip table is decoded
+// up front; each block is decoded once the cursor reaches it, and `next_geq`
+// jumps straight to the block which could hold a document, so the blocks in
+// between are never decoded at all. The encoded blocks are borrowed, so a
+// cursor over a mapped postings file decodes straight out of the mapping.
+// See `write_postings` for the format.
+pub struct PostingsCursor<'a> {
+    codec: Codec,
+    len: u64,
+    // The sum of the frequencies of every posting.
+    frequency: u64,
+    skips: Vec<Skip>,
+    // The encoded blocks.
+    data: &'a [u8],
+    // The block decoded into `postings`. Once the cursor has passed the
+    // last posting, this is the number of blocks.
+    block: usize,
+    postings: Vec<Posting>,
+    // The current posting, within `postings`.
+    index: usize,
+}
+
+impl<'a> PostingsCursor<'a> {
+    // Reads a postings list from the start of `bytes`, leaving the cursor on
+    // its first posting and `bytes` just past the list.
+    pub fn read(codec: Codec, bytes: &mut &'a [u8]) -> std::io::Result<PostingsCursor<'a>> {
+        let mut reader = *bytes;
+        let (len, _) = read_varint(&mut reader)?;
+        let (frequency, _) = read_varint(&mut reader)?;
+
+        let mut skips = Vec::with_capacity(block_count(len));
+        let mut last = 0;
+        let mut offset = 0;
+        let mut positions = 0;
+
+        for _ in 0..block_count(len) {
+            last += read_varint(&mut reader)?.0;
+            let (length, _) = read_varint(&mut reader)?;
+            positions += read_varint(&mut reader)?.0;
+            let bound = TermBound {
+                max_frequency: read_varint(&mut reader)?.0,
+                min_length: read_varint(&mut reader)?.0,
+            };
+
+            skips.push(Skip {
+                last,
+                offset,
+                positions,
+                bound,
+            });
+            offset += length as usize;
+        }
+
+        if reader.len() < offset {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "postings list runs past the end of the file",
+            ));
+        }
+        let (data, rest) = reader.split_at(offset);
+        *bytes = rest;
+
+        let mut cursor = PostingsCursor {
+            codec,
+            len,
+            frequency,
+            skips,
+            data,
+            block: 0,
+            postings: Vec::with_capacity(POSTINGS_BLOCK_SIZE),
+            index: 0,
+        };
+        if !cursor.skips.is_empty() {
+            cursor.decode(0)?;
+        }
+
+        Ok(cursor)
+    }
+
+    // Moves on to the next posting.
+    pub fn advance(&mut self) -> std::io::Result<Option<Posting>> {
+        self.index += 1;
+        if self.index >= self.postings.len() && self.block + 1 < self.skips.len() {
+            self.decode(self.block + 1)?;
+        }
+
+        Ok(self.posting())
+    }
+
+    // The sum of the frequencies of every posting, deleted or not.
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    // The block the cursor is on, counting from 0.
+    pub fn block(&self) -> usize {
+        self.block
+    }
+
+    // The postings of the block the cursor is on, and where their positions
+    // start relative to those of the whole list.
+    pub fn block_postings(&self) -> (&[Posting], u64) {
+        let positions = self.skips.get(self.block).map_or(0, |s| s.positions);
+        (&self.postings, positions)
+    }
+
+    // The position of the current posting within its block.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    fn decode(&mut self, block: usize) -> std::io::Result<()> {
+        let start = self.skips[block].offset;
+        let end = self
+            .skips
+            .get(block + 1)
+            .map_or(self.data.len(), |s| s.offset);
+
+        // Document IDs carry on from the last document of the block before.
+        let previous = if block == 0 {
+            0
+        } else {
+            self.skips[block - 1].last
+        };
+
+        let count = (self.len as usize - block * POSTINGS_BLOCK_SIZE).min(POSTINGS_BLOCK_SIZE);
+
+        self.postings.clear();
+        self.codec
+            .decode_block(&self.data[start..end], count, previous, &mut self.postings)?;
+
+        self.block = block;
+        self.index = 0;
+        Ok(())
+    }
+}
+
+impl Cursor for PostingsCursor<'_> {
+    fn posting(&self) -> Option<Posting> {
+        self.postings.get(self.index).copied()
+    }
+
+    // Jumps straight to the block which could hold `document` with the skip
+    // table, so the blocks in between are never decoded.
+    fn next_geq(&mut self, document: u64) -> std::io::Result<Option<Posting>> {
+        match self.posting() {
+            Some(p) if p.document < document => {}
+            posting => return Ok(posting),
+        }
+
+        if self.skips[self.block].last < document {
+            // Every block before the one we need ends before `document`.
+            let block = self.block
+                + 1
+                + self.skips[self.block + 1..].partition_point(|s| s.last < document);
+
+            if block == self.skips.len() {
+                self.block = block;
+                self.postings.clear();
+                self.index = 0;
+                return Ok(None);
+            }
+
+            self.decode(block)?;
+        }
+
+        self.index += gallop(&self.postings[self.index..], document);
+        Ok(self.posting())
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    // Only looks at the skip table, from the block the cursor is on.
+    fn block_bound(&self, document: u64) -> Option<(u64, TermBound)> {
+        let rest = self.skips.get(self.block..)?;
+        let skip = rest.get(rest.partition_point(|s| s.last < document))?;
+
+        Some((skip.last, skip.bound))
+    }
+}
+
+// The number of `postings` before the first for `document` or a later
+// document. Gallops, doubling its stride until it passes the document and
+// then searching the last stride, so a nearby document is found in a few
+// steps.
+fn gallop(postings: &[Posting], document: u64) -> usize {
+    let mut stride = 1;
+    while stride < postings.len() && postings[stride - 1].document < document {
+        stride *= 2;
+    }
+    let end = stride.min(postings.len());
+    let start = stride / 2;
+
+    start + postings[start..end].partition_point(|p| p.document < document)
+}
+
+// A cursor over postings already decoded, such as those of a phrase.
+pub struct DecodedCursor {
+    postings: Vec<Posting>,
+    index: usize,
+}
+
+impl DecodedCursor {
+    pub fn new(postings: Vec<Posting>) -> DecodedCursor {
+        DecodedCursor { postings, index: 0 }
+    }
+
+    // A cursor over a sorted list of documents, each with a frequency of 1.
+    pub fn documents(documents: &[u64]) -> DecodedCursor {
+        DecodedCursor::new(
+            documents
+                .iter()
+                .map(|&document| Posting {
+                    document,
+                    frequency: 1,
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Cursor for DecodedCursor {
+    fn posting(&self) -> Option<Posting> {
+        self.postings.get(self.index).copied()
+    }
+
+    fn next_geq(&mut self, document: u64) -> std::io::Result<Option<Posting>> {
+        self.index += gallop(&self.postings[self.index..], document);
+        Ok(self.posting())
+    }
+
+    fn len(&self) -> u64 {
+        self.postings.len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::index::{read_postings, write_postings};
+
+    #[test]
+    fn next_geq() {
+        let postings = (0..1000)
+            .map(|i| Posting {
+                document: i * 10 + i % 7,
+                frequency: 1 + i % 4,
+            })
+            .collect::<Vec<_>>();
+        let offsets = (0..block_count(1000) as u64)
+            .map(|b| b * 10)
+            .collect::<Vec<_>>();
+        let bounds = postings
+            .chunks(POSTINGS_BLOCK_SIZE)
+            .map(|block| TermBound::of(block, |doc| 100 + doc % 13))
+            .collect::<Vec<_>>();
+
+        let mut bytes = Vec::new();
+        write_postings(
+            Codec::Varint,
+            1000,
+            postings.iter().copied(),
+            &offsets,
+            &bounds,
+            &mut bytes,
+        )
+        .unwrap();
+        bytes.push(42);
+
+        // Read straight through, both ways.
+        let mut reader = bytes.as_slice();
+        let mut read = Vec::new();
+        read_postings(Codec::Varint, &mut reader, &mut read).unwrap();
+        assert_eq!(read, postings);
+        assert_eq!(reader, &[42]);
+
+        let mut reader = bytes.as_slice();
+        let mut cursor = PostingsCursor::read(Codec::Varint, &mut reader).unwrap();
+        assert_eq!(reader, &[42]);
+        assert_eq!(cursor.len(), 1000);
+        assert_eq!(
+            cursor.frequency(),
+            postings.iter().map(|p| p.frequency).sum::<u64>()
+        );
+
+        let mut read = Vec::new();
+        while let Some(p) = cursor.posting() {
+            read.push(p);
+            cursor.advance().unwrap();
+        }
+        assert_eq!(read, postings);
+        assert_eq!(cursor.advance().unwrap(), None);
+
+        // Skipping finds the same postings as a linear scan.
+        let mut cursor = PostingsCursor::read(Codec::Varint, &mut bytes.as_slice()).unwrap();
+        for target in [0, 1, 2, 200, 1283, 1280, 1281, 5000, 5000, 4000, 9996, 9997] {
+            let expected = postings.iter().copied().find(|p| p.document >= target);
+            let expected = match (expected, cursor.posting()) {
+                (Some(e), Some(p)) if p.document > e.document => Some(p),
+                (e, _) => e,
+            };
+            assert_eq!(cursor.next_geq(target).unwrap(), expected, "{}", target);
+        }
+        assert_eq!(cursor.posting(), None);
+
+        let mut cursor = PostingsCursor::read(Codec::Varint, &mut bytes.as_slice()).unwrap();
+        let p = cursor.next_geq(7000).unwrap().unwrap();
+        assert_eq!(cursor.block(), 5);
+        assert_eq!(cursor.block_postings().1, 50);
+        assert_eq!(cursor.block_postings().0[cursor.index()], p);
+
+        // Block bounds come from the skip table, from the block the cursor is
+        // on onwards.
+        assert_eq!(
+            cursor.block_bound(0),
+            Some((postings[767].document, bounds[5]))
+        );
+        assert_eq!(
+            cursor.block_bound(8956),
+            Some((postings[895].document, bounds[6]))
+        );
+        assert_eq!(
+            cursor.block_bound(9000),
+            Some((postings[999].document, bounds[7]))
+        );
+        assert_eq!(cursor.block_bound(10000), None);
+
+        let mut bytes = Vec::new();
+        write_postings(Codec::Varint, 0, std::iter::empty(), &[], &[], &mut bytes).unwrap();
+        let mut cursor = PostingsCursor::read(Codec::Varint, &mut bytes.as_slice()).unwrap();
+        assert!(cursor.is_empty());
+        assert_eq!(cursor.next_geq(5).unwrap(), None);
+    }
+
+    #[test]
+    fn decoded_next_geq() {
+        let documents = (0..300).map(|i| i * 3).collect::<Vec<_>>();
+        let mut cursor = DecodedCursor::documents(&documents);
+        assert_eq!(cursor.len(), 300);
+
+        for target in [0, 0, 1, 5, 6, 7, 100, 450, 449, 896, 897, 898] {
+            let expected = documents.iter().copied().find(|&d| d >= target);
+            let expected = match (expected, cursor.posting()) {
+                (Some(e), Some(p)) if p.document > e => Some(p.document),
+                (e, _) => e,
+            };
+            let found = cursor.next_geq(target).unwrap().map(|p| p.document);
+            assert_eq!(found, expected, "{}", target);
+        }
+        assert_eq!(cursor.posting(), None);
+
+        let mut cursor = DecodedCursor::new(Vec::new());
+        assert!(cursor.is_empty());
+        assert_eq!(cursor.next_geq(0).unwrap(), None);
+    }
+}
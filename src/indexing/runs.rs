@@ -0,0 +1,231 @@
+use crate::indexing::codec::Codec;
+use crate::indexing::index::{
+    read_positions, read_postings, write_positions, write_postings, Posting,
+};
+use crate::indexing::varint::{read_varint, write_varint};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+// A run is a sorted, partial index which has been flushed to disk because the
+// in-memory index grew past its budget. Runs are later merged into the final
+// index files.
+//
+// +-----------------+---------------+-------------------------------+
+// | Length (varint) | Term (bytes)  | Postings (as in postings.bin) |
+// +-----------------+---------------+-------------------------------+
+// +---------------------------+-----------------------------------+
+// | Position Count (varint)   | Positions (as in positions.bin)   |
+// +---------------------------+-----------------------------------+
+// ..
+// (Repeated until EOF)
+//
+// The position count is 0 when positions aren't being kept.
+
+// Writes a run containing `iter`, which must yield terms in sorted order,
+// alongside their postings and positions.
+pub fn write_run<'a, P, I>(path: P, iter: I) -> std::io::Result<()>
+where
+    P: AsRef<Path>,
+    I: Iterator<Item = (&'a str, &'a [(u64, u64)], &'a [u64])>,
+{
+    let mut out = BufWriter::new(File::create(path)?);
+
+    for (term, postings, positions) in iter {
+        write_varint(&mut out, term.len() as u64)?;
+        out.write_all(term.as_bytes())?;
+
+        write_postings(
+            Codec::Varint,
+            postings.len() as u64,
+            postings.iter().map(|&(document, frequency)| Posting {
+                document,
+                frequency,
+            }),
+            &[],
+            &[],
+            &mut out,
+        )?;
+
+        write_varint(&mut out, positions.len() as u64)?;
+        if !positions.is_empty() {
+            write_positions(postings.iter().map(|p| p.1), positions, &mut out)?;
+        }
+    }
+
+    out.flush()
+}
+
+// A term, its postings, and the positions of the term within each posting's
+// document, flattened in posting order. The positions are empty if they
+// aren't being kept.
+pub type Entry = (String, Vec<Posting>, Vec<u64>);
+
+// A source of sorted entries which can be merged.
+pub trait Entries {
+    // Returns the next entry, or `None` once exhausted.
+    fn next_entry(&mut self) -> std::io::Result<Option<Entry>>;
+}
+
+// Reads the entries of a run one term at a time.
+pub struct RunReader {
+    reader: BufReader<File>,
+}
+
+impl RunReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<RunReader> {
+        Ok(RunReader {
+            reader: BufReader::new(File::open(path)?),
+        })
+    }
+}
+
+impl Entries for RunReader {
+    fn next_entry(&mut self) -> std::io::Result<Option<Entry>> {
+        let len = match read_varint(&mut self.reader) {
+            Ok((len, _)) => len,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut data = vec![0; len as usize];
+        self.reader.read_exact(data.as_mut_slice())?;
+
+        let mut postings = Vec::new();
+        read_postings(Codec::Varint, &mut self.reader, &mut postings)?;
+
+        let mut positions = Vec::new();
+        let (count, _) = read_varint(&mut self.reader)?;
+        if count > 0 {
+            read_positions(
+                postings.iter().map(|p| p.frequency),
+                &mut self.reader,
+                &mut positions,
+            )?;
+        }
+
+        Ok(Some((
+            String::from_utf8(data).unwrap(),
+            postings,
+            positions,
+        )))
+    }
+}
+
+// Performs a k-way merge of `runs`, calling `f` once per distinct term with
+// the term's complete postings list and positions. Terms are visited in
+// sorted order.
+//
+// Runs must be given in document order: when a term appears in several runs,
+// its postings are concatenated in the order the runs were given, which keeps
+// the merged list sorted by document.
+pub fn merge_runs<E, F>(mut runs: Vec<E>, mut f: F) -> std::io::Result<()>
+where
+    E: Entries,
+    F: FnMut(&str, &[Posting], &[u64]) -> std::io::Result<()>,
+{
+    // The heap holds the current entry of every run which hasn't been
+    // exhausted. Ties on the term are broken by run number, so that the
+    // earliest run is popped first.
+    let mut heap = BinaryHeap::new();
+    let mut pending: Vec<Option<(Vec<Posting>, Vec<u64>)>> = Vec::with_capacity(runs.len());
+
+    for (n, run) in runs.iter_mut().enumerate() {
+        match run.next_entry()? {
+            Some((term, postings, positions)) => {
+                heap.push(Reverse((term, n)));
+                pending.push(Some((postings, positions)));
+            }
+            None => pending.push(None),
+        }
+    }
+
+    let mut postings: Vec<Posting> = Vec::new();
+    let mut positions: Vec<u64> = Vec::new();
+
+    while let Some(Reverse((term, n))) = heap.pop() {
+        let (next_postings, next_positions) = pending[n].as_mut().unwrap();
+        postings.append(next_postings);
+        positions.append(next_positions);
+
+        // Refill the heap from the run we just consumed.
+        if let Some((next, next_postings, next_positions)) = runs[n].next_entry()? {
+            heap.push(Reverse((next, n)));
+            pending[n] = Some((next_postings, next_positions));
+        }
+
+        // Keep collecting until every run holding `term` has been consumed.
+        if let Some(Reverse((peek, _))) = heap.peek() {
+            if *peek == term {
+                continue;
+            }
+        }
+
+        f(&term, &postings, &positions)?;
+        postings.clear();
+        positions.clear();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::testing::TempDir;
+    use std::fs;
+
+    #[test]
+    fn merge() {
+        let dir = TempDir::new("runs");
+        fs::create_dir_all(&dir).unwrap();
+
+        type Run<'a> = &'a [(&'a str, &'a [(u64, u64)], &'a [u64])];
+
+        let runs: [Run; 3] = [
+            &[
+                ("apple", &[(0, 1), (1, 2)], &[4, 0, 9]),
+                ("pear", &[(1, 1)], &[3]),
+            ],
+            &[("banana", &[(2, 3)], &[1, 2, 7]), ("pear", &[(3, 1)], &[0])],
+            &[("apple", &[(4, 1)], &[2]), ("zucchini", &[(5, 7)], &[])],
+        ];
+
+        let mut readers = Vec::new();
+        for (n, run) in runs.iter().enumerate() {
+            let path = dir.join(format!("{}.run", n));
+            write_run(&path, run.iter().copied()).unwrap();
+            readers.push(RunReader::open(&path).unwrap());
+        }
+
+        let mut merged = Vec::new();
+        merge_runs(readers, |term, postings, positions| {
+            merged.push((
+                term.to_string(),
+                postings
+                    .iter()
+                    .map(|p| (p.document, p.frequency))
+                    .collect::<Vec<_>>(),
+                positions.to_vec(),
+            ));
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(
+            merged,
+            vec![
+                (
+                    "apple".to_string(),
+                    vec![(0, 1), (1, 2), (4, 1)],
+                    vec![4, 0, 9, 2]
+                ),
+                ("banana".to_string(), vec![(2, 3)], vec![1, 2, 7]),
+                ("pear".to_string(), vec![(1, 1), (3, 1)], vec![3, 0]),
+                ("zucchini".to_string(), vec![(5, 7)], vec![]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,440 @@
+use crate::indexing::codec::Codec;
+use crate::indexing::deletes::Deletes;
+use crate::indexing::index::{
+    read_bound, read_documents, read_positions, read_postings, read_segment_fields, read_term,
+    write_bound, write_documents, write_field_lengths, write_positions, write_postings, write_term,
+    Document, Posting, TermBound, BLOCKS_FILE, DOCUMENTS_FILE, FIELDS_FILE, INDEX_FILE,
+    POSITIONS_FILE, POSTINGS_FILE,
+};
+use crate::indexing::manifest::SegmentInfo;
+use crate::indexing::postings::{block_count, POSTINGS_BLOCK_SIZE};
+use crate::indexing::runs::{Entries, Entry};
+use crate::indexing::varint::{read_varint, write_varint};
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// A segment is a self-contained index over a contiguous range of documents.
+// Each segment lives in its own subdirectory of the index directory and has
+// its own `documents.bin`, `postings.bin`, `blocks.bin` and `index.bin`, and
+// `positions.bin` if positions are kept. In that case every postings list in
+// `postings.bin` is followed by a pointer to its positions, and its skip
+// table points at the positions of each block. Segments whose
+// documents all have field lengths also have `fields.bin`.
+// Document IDs inside a segment start from 0; the index numbers documents
+// globally by stacking the segments in the order the manifest lists them.
+
+// Number of terms stored in each leaf block of the dictionary. Every
+// `BLOCK_SIZE`th term is also written to the root index.
+pub const BLOCK_SIZE: usize = 1000;
+
+const DICTIONARY_TMP: &str = "dictionary.tmp";
+
+// Writes the files of a single segment. Terms must be pushed in sorted order.
+//
+// The number of distinct terms isn't necessarily known up front (for instance
+// while merging), so the dictionary is first streamed to a temporary file and
+// then copied into `blocks.bin` and `index.bin` by `finish`.
+pub struct SegmentWriter {
+    dir: PathBuf,
+    documents: Vec<Document>,
+    post_out: BufWriter<File>,
+    dictionary_out: BufWriter<File>,
+    positions_out: Option<BufWriter<File>>,
+    codec: Codec,
+    postings_offset: usize,
+    positions_offset: usize,
+    term_count: u64,
+}
+
+impl SegmentWriter {
+    // Creates the segment directory `dir` for `documents` and starts writing
+    // into it, compressing postings with `codec`. If `positions` is set,
+    // every term must be pushed with its positions.
+    pub fn create<P: AsRef<Path>>(
+        dir: P,
+        documents: Vec<Document>,
+        positions: bool,
+        codec: Codec,
+    ) -> std::io::Result<SegmentWriter> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let post_out = BufWriter::new(File::create(dir.join(POSTINGS_FILE))?);
+        let dictionary_out = BufWriter::new(File::create(dir.join(DICTIONARY_TMP))?);
+        let positions_out = if positions {
+            Some(BufWriter::new(File::create(dir.join(POSITIONS_FILE))?))
+        } else {
+            None
+        };
+
+        Ok(SegmentWriter {
+            dir,
+            documents,
+            post_out,
+            dictionary_out,
+            positions_out,
+            codec,
+            postings_offset: 0,
+            positions_offset: 0,
+            term_count: 0,
+        })
+    }
+
+    // Adds a term and its `n` postings. `positions` holds the positions of
+    // the term within each posting's document, flattened in posting order,
+    // and is ignored unless the segment keeps positions.
+    pub fn push<I: Iterator<Item = Posting>>(
+        &mut self,
+        term: &str,
+        n: u64,
+        postings: I,
+        positions: &[u64],
+    ) -> std::io::Result<()> {
+        let post_ptr = self.postings_offset;
+
+        // Each block of postings is bounded, as is the whole list.
+        let postings = postings.collect::<Vec<_>>();
+        let documents = &self.documents;
+        let bounds = postings
+            .chunks(POSTINGS_BLOCK_SIZE)
+            .map(|block| TermBound::of(block, |doc| documents[doc as usize].term_count))
+            .collect::<Vec<_>>();
+        let bound = bounds.iter().fold(
+            TermBound {
+                max_frequency: 0,
+                min_length: u64::MAX,
+            },
+            |bound, &block| bound.union(block),
+        );
+
+        match self.positions_out.as_mut() {
+            Some(positions_out) => {
+                // Positions are written a block of postings at a time, so the
+                // skip table can point at where each block starts.
+                let mut offsets = Vec::with_capacity(block_count(n));
+                let mut written = 0;
+                let mut start = 0;
+                for block in postings.chunks(POSTINGS_BLOCK_SIZE) {
+                    let end = start + block.iter().map(|p| p.frequency as usize).sum::<usize>();
+
+                    offsets.push(written as u64);
+                    written += write_positions(
+                        block.iter().map(|p| p.frequency),
+                        &positions[start..end],
+                        positions_out,
+                    )?;
+                    start = end;
+                }
+
+                self.postings_offset += write_postings(
+                    self.codec,
+                    n,
+                    postings.into_iter(),
+                    &offsets,
+                    &bounds,
+                    &mut self.post_out,
+                )?;
+                self.postings_offset +=
+                    write_varint(&mut self.post_out, self.positions_offset as u64)?;
+                self.positions_offset += written;
+            }
+            None => {
+                self.postings_offset += write_postings(
+                    self.codec,
+                    n,
+                    postings.into_iter(),
+                    &[],
+                    &bounds,
+                    &mut self.post_out,
+                )?;
+            }
+        }
+
+        write_term(term.as_bytes(), post_ptr as u64, &mut self.dictionary_out)?;
+        write_bound(&bound, &mut self.dictionary_out)?;
+        self.term_count += 1;
+
+        Ok(())
+    }
+
+    // Writes the documents and the dictionary, completing the segment. The
+    // returned `SegmentInfo` is ready to be added to the manifest.
+    pub fn finish(mut self, index_dir: &Path, name: &str) -> std::io::Result<SegmentInfo> {
+        let documents = std::mem::take(&mut self.documents);
+
+        self.post_out.flush()?;
+        self.dictionary_out.flush()?;
+        drop(self.dictionary_out);
+
+        let positions = match self.positions_out.take() {
+            Some(mut positions_out) => {
+                positions_out.flush()?;
+                true
+            }
+            None => false,
+        };
+
+        let count = documents.len() as u64;
+        let fields = !documents.is_empty()
+            && documents
+                .iter()
+                .all(|d| d.fields.len() == documents[0].fields.len() && !d.fields.is_empty());
+
+        if fields {
+            let mut fields_out = BufWriter::new(File::create(self.dir.join(FIELDS_FILE))?);
+            write_field_lengths(
+                count,
+                documents[0].fields.len() as u64,
+                documents.iter().map(|d| d.fields.as_slice()),
+                &mut fields_out,
+            )?;
+            fields_out.flush()?;
+        }
+
+        // Write documents
+        {
+            let avg_dl = if documents.is_empty() {
+                0.0
+            } else {
+                documents.iter().fold(0, |a, b| a + b.term_count) as f32 / documents.len() as f32
+            };
+
+            let docs_file = File::create(self.dir.join(DOCUMENTS_FILE))?;
+            let mut docs_out = BufWriter::new(docs_file);
+
+            write_documents(count, avg_dl, documents.into_iter(), &mut docs_out)?;
+
+            docs_out.flush()?;
+        }
+
+        {
+            let dictionary_path = self.dir.join(DICTIONARY_TMP);
+            let mut reader = BufReader::new(File::open(&dictionary_path)?);
+            let mut dictionary = DictionaryWriter::create(&self.dir, self.term_count)?;
+
+            for _ in 0..self.term_count {
+                let (term, post_ptr) = read_term(&mut reader)?;
+                dictionary.push(&term, post_ptr, &read_bound(&mut reader)?)?;
+            }
+
+            dictionary.finish()?;
+            fs::remove_file(dictionary_path)?;
+        }
+
+        let mut info = SegmentInfo::new(name, count);
+        for file in &[DOCUMENTS_FILE, POSTINGS_FILE, BLOCKS_FILE, INDEX_FILE] {
+            info.add(index_dir, file)?;
+        }
+        if positions {
+            info.add(index_dir, POSITIONS_FILE)?;
+        }
+        if fields {
+            info.add(index_dir, FIELDS_FILE)?;
+        }
+
+        Ok(info)
+    }
+}
+
+// Writes the two levels of the ISAM dictionary: `blocks.bin` holds every term
+// alongside a pointer into `postings.bin` and its bound, and `index.bin` holds every
+// `BLOCK_SIZE`th term alongside a pointer into `blocks.bin`.
+// Terms must be pushed in sorted order.
+struct DictionaryWriter {
+    block_out: BufWriter<File>,
+    index_out: BufWriter<File>,
+    blocks_offset: usize,
+    n: usize,
+}
+
+impl DictionaryWriter {
+    fn create(dir: &Path, term_count: u64) -> std::io::Result<DictionaryWriter> {
+        let block_file = File::create(dir.join(BLOCKS_FILE))?;
+        let mut block_out = BufWriter::new(block_file);
+
+        let index_file = File::create(dir.join(INDEX_FILE))?;
+        let mut index_out = BufWriter::new(index_file);
+
+        // `index_count` indicates the length of the root index.
+        // If the number of terms is exactly divisible by `BLOCK_SIZE`, we
+        // have that many terms in the root index. Otherwise, there is an
+        // extra entry.
+        let index_count = term_count.div_ceil(BLOCK_SIZE as u64);
+
+        let blocks_offset = write_varint(&mut block_out, term_count)?;
+        write_varint(&mut index_out, index_count)?;
+
+        Ok(DictionaryWriter {
+            block_out,
+            index_out,
+            blocks_offset,
+            n: 0,
+        })
+    }
+
+    fn push(&mut self, term: &str, post_ptr: u64, bound: &TermBound) -> std::io::Result<()> {
+        let block_ptr = self.blocks_offset;
+
+        self.blocks_offset += write_term(term.as_bytes(), post_ptr, &mut self.block_out)?;
+        self.blocks_offset += write_bound(bound, &mut self.block_out)?;
+
+        // Write every `BLOCK_SIZE` terms to the root index
+        if self.n.is_multiple_of(BLOCK_SIZE) {
+            write_term(term.as_bytes(), block_ptr as u64, &mut self.index_out)?;
+        }
+
+        self.n += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> std::io::Result<()> {
+        self.block_out.flush()?;
+        self.index_out.flush()
+    }
+}
+
+// Reads every term of a segment, with its postings, in sorted order. Postings
+// are written in the same order as the dictionary, so both files are simply
+// read front to back.
+//
+// Deleted documents are dropped from the postings, and the remaining
+// documents renumbered to close the gaps. Terms which only occurred in
+// deleted documents are skipped entirely.
+pub struct SegmentReader {
+    blocks: BufReader<File>,
+    postings: BufReader<File>,
+    positions: Option<BufReader<File>>,
+    codec: Codec,
+    remaining: u64,
+    // Added to every document ID read from the segment.
+    base: u64,
+    deletes: Deletes,
+    // Number of deleted documents before each document, if any are deleted.
+    shifts: Vec<u64>,
+}
+
+impl SegmentReader {
+    pub fn open<P: AsRef<Path>>(
+        dir: P,
+        base: u64,
+        deletes: Deletes,
+        codec: Codec,
+    ) -> std::io::Result<SegmentReader> {
+        let dir = dir.as_ref();
+
+        let mut blocks = BufReader::new(File::open(dir.join(BLOCKS_FILE))?);
+        let (remaining, _) = read_varint(&mut blocks)?;
+
+        let mut shifts = Vec::new();
+        if !deletes.is_empty() {
+            let documents = read_segment_documents(dir)?.len() as u64;
+            let mut shift = 0;
+            for doc in 0..documents {
+                shifts.push(shift);
+                if deletes.contains(doc) {
+                    shift += 1;
+                }
+            }
+        }
+
+        let positions = match File::open(dir.join(POSITIONS_FILE)) {
+            Ok(file) => Some(BufReader::new(file)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        Ok(SegmentReader {
+            blocks,
+            postings: BufReader::new(File::open(dir.join(POSTINGS_FILE))?),
+            positions,
+            codec,
+            remaining,
+            base,
+            deletes,
+            shifts,
+        })
+    }
+}
+
+impl Entries for SegmentReader {
+    fn next_entry(&mut self) -> std::io::Result<Option<Entry>> {
+        while self.remaining > 0 {
+            self.remaining -= 1;
+
+            let (term, _) = read_term(&mut self.blocks)?;
+            read_bound(&mut self.blocks)?;
+
+            let mut postings = Vec::new();
+            read_postings(self.codec, &mut self.postings, &mut postings)?;
+
+            // Positions are read front to back too, so the pointer to them
+            // isn't needed.
+            let mut positions = Vec::new();
+            if let Some(reader) = self.positions.as_mut() {
+                read_varint(&mut self.postings)?;
+                read_positions(postings.iter().map(|p| p.frequency), reader, &mut positions)?;
+            }
+
+            if !self.deletes.is_empty() {
+                if !positions.is_empty() {
+                    let mut start = 0;
+                    let mut kept = Vec::with_capacity(positions.len());
+                    for p in &postings {
+                        let end = start + p.frequency as usize;
+                        if !self.deletes.contains(p.document) {
+                            kept.extend_from_slice(&positions[start..end]);
+                        }
+                        start = end;
+                    }
+                    positions = kept;
+                }
+
+                postings.retain(|p| !self.deletes.contains(p.document));
+                if postings.is_empty() {
+                    continue;
+                }
+            }
+
+            for posting in postings.iter_mut() {
+                let shift = self.shifts.get(posting.document as usize).unwrap_or(&0);
+                posting.document = posting.document - shift + self.base;
+            }
+
+            return Ok(Some((term, postings, positions)));
+        }
+
+        Ok(None)
+    }
+}
+
+// Reads the documents of the segment in `dir`, with their field lengths if
+// the segment has them.
+pub fn read_segment_documents<P: AsRef<Path>>(dir: P) -> std::io::Result<Vec<Document>> {
+    let dir = dir.as_ref();
+    let bytes = fs::read(dir.join(DOCUMENTS_FILE))?;
+
+    let mut avg_dl = 0.0;
+    let mut docs = Vec::new();
+    read_documents(&mut bytes.as_slice(), &mut avg_dl, &mut docs)?;
+
+    let mut fields = read_segment_fields(dir)?.unwrap_or_default().into_iter();
+
+    Ok(docs
+        .into_iter()
+        .map(|doc| Document {
+            term_count: doc.term_count,
+            name: String::from_utf8(doc.name.to_vec()).unwrap(),
+            fields: fields.next().unwrap_or_default(),
+        })
+        .collect())
+}
+
+// Removes the directory of the segment `name`, if it's still there.
+pub(crate) fn remove_segment(dir: &Path, name: &str) -> std::io::Result<()> {
+    match fs::remove_dir_all(dir.join(name)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
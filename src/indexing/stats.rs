@@ -0,0 +1,113 @@
+use crate::indexing::codec::Codec;
+use crate::indexing::deletes::Deletes;
+use crate::indexing::index::{read_postings, write_postings, Posting};
+use crate::indexing::manifest::Manifest;
+use crate::indexing::runs::Entries;
+use crate::indexing::segment::SegmentReader;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// Decoding is repeated until it has taken at least this long, so the timing
+// of a small index isn't just noise.
+const MIN_DECODE_TIME: Duration = Duration::from_millis(200);
+
+// How a codec fares on the postings of an index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodecStats {
+    pub codec: Codec,
+    // Number of postings, across every list.
+    pub postings: u64,
+    // Size of every postings list, skip tables included.
+    pub bytes: u64,
+    // Time taken to decode every list once.
+    pub decode: Duration,
+}
+
+impl CodecStats {
+    pub fn bits_per_posting(&self) -> f64 {
+        self.bytes as f64 * 8.0 / self.postings.max(1) as f64
+    }
+
+    // Compression ratio, against storing each posting as a pair of 32 bit
+    // integers.
+    pub fn ratio(&self) -> f64 {
+        (self.postings * 8) as f64 / self.bytes.max(1) as f64
+    }
+
+    // Millions of postings decoded per second.
+    pub fn decode_speed(&self) -> f64 {
+        self.postings as f64 / self.decode.as_secs_f64().max(f64::EPSILON) / 1e6
+    }
+}
+
+// Reads every postings list of the index in `dir` and encodes and decodes
+// them all with each of `codecs`. Every list is held in memory at once, and
+// deleted documents are counted like any other.
+pub fn codec_stats<P: AsRef<Path>>(dir: P, codecs: &[Codec]) -> std::io::Result<Vec<CodecStats>> {
+    let dir = dir.as_ref();
+    let manifest = Manifest::read(dir)?;
+    manifest.verify(dir)?;
+
+    let mut lists = Vec::new();
+    for info in &manifest.segments {
+        let mut reader = SegmentReader::open(
+            dir.join(&info.name),
+            0,
+            Deletes::new(info.documents),
+            manifest.codec,
+        )?;
+
+        while let Some((_, postings, _)) = reader.next_entry()? {
+            lists.push(postings);
+        }
+    }
+
+    let postings = lists.iter().map(|l| l.len() as u64).sum();
+
+    codecs
+        .iter()
+        .map(|&codec| {
+            let encoded = lists
+                .iter()
+                .map(|list| {
+                    let mut bytes = Vec::new();
+                    write_postings(
+                        codec,
+                        list.len() as u64,
+                        list.iter().copied(),
+                        &[],
+                        &[],
+                        &mut bytes,
+                    )?;
+                    Ok(bytes)
+                })
+                .collect::<std::io::Result<Vec<_>>>()?;
+
+            let mut decoded: Vec<Posting> = Vec::new();
+            let mut rounds = 0;
+            let start = Instant::now();
+
+            while rounds == 0 || start.elapsed() < MIN_DECODE_TIME {
+                for (bytes, list) in encoded.iter().zip(&lists) {
+                    decoded.clear();
+                    read_postings(codec, &mut bytes.as_slice(), &mut decoded)?;
+
+                    if rounds == 0 && decoded != *list {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("{} didn't decode what it encoded", codec.name()),
+                        ));
+                    }
+                }
+                rounds += 1;
+            }
+
+            Ok(CodecStats {
+                codec,
+                postings,
+                bytes: encoded.iter().map(|b| b.len() as u64).sum(),
+                decode: start.elapsed() / rounds,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,102 @@
+// Helpers for tests which build an index on disk.
+
+use crate::indexing::builder::IndexBuilder;
+use crate::indexing::index::DiskIndex;
+use crate::parsing::analyzer::{Analyzer, ParsedDocument};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Three documents with headlines and text, one of them with a dateline.
+const FIELDED: &[u8] = b"<DOC><DOCNO> a </DOCNO><HL> Merger Talks </HL>\
+    <TEXT> The merger was called off. </TEXT></DOC>\
+    <DOC><DOCNO> b </DOCNO><HL> Quiet Day </HL>\
+    <DATELINE> TOKYO </DATELINE><TEXT> Talks of a merger. </TEXT></DOC>\
+    <DOC><DOCNO> c </DOCNO><HL> Markets </HL><TEXT> Stocks rose. </TEXT></DOC>";
+
+pub fn fielded_documents() -> Vec<ParsedDocument> {
+    Analyzer::new().documents(FIELDED).collect()
+}
+
+// A directory under the system's temporary directory, named after the test
+// using it so tests running at once don't collide. Removed when dropped, so
+// a failing assertion doesn't leave it behind.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("asgn1-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.path.join(path)
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// Writes an index of `docs` to `dir` with `builder`, each document's text
+// analyzed the way the search program would, and opens it.
+pub fn index<P: AsRef<Path>>(
+    dir: P,
+    mut builder: IndexBuilder,
+    docs: &[(&str, &str)],
+) -> DiskIndex {
+    let analyzer = Analyzer::new();
+    for (name, text) in docs {
+        builder.add_document(name, analyzer.terms(text)).unwrap();
+    }
+    builder.write(&dir).unwrap();
+
+    DiskIndex::from_disk(&dir).unwrap()
+}
+
+// Writes an index of already parsed `docs` to `dir` with `builder`, and
+// opens it.
+pub fn parsed_index<P: AsRef<Path>>(
+    dir: P,
+    mut builder: IndexBuilder,
+    docs: &[ParsedDocument],
+) -> DiskIndex {
+    for doc in docs {
+        builder.add_parsed_document(doc).unwrap();
+    }
+    builder.write(&dir).unwrap();
+
+    DiskIndex::from_disk(&dir).unwrap()
+}
+
+// The names of the documents `query` finds, best first.
+pub fn ranked(index: &DiskIndex, query: &str) -> Vec<String> {
+    index
+        .search(query)
+        .unwrap()
+        .map(|(_, doc)| index.document(doc).to_string())
+        .collect()
+}
+
+// The names of the documents `query` finds, in order of name.
+pub fn matched(index: &DiskIndex, query: &str) -> Vec<String> {
+    let mut names = ranked(index, query);
+    names.sort();
+    names
+}
@@ -93,9 +93,9 @@ mod tests {
         for (data, result) in examples.iter() {
             println!("result: {}", result);
 
-            let mut buf = Vec::new();
+            let buf = Vec::new();
             let mut writer = Cursor::new(buf);
-            write_varint(&mut writer, *result);
+            write_varint(&mut writer, *result).unwrap();
             assert_eq!(&writer.into_inner(), data);
         }
     }
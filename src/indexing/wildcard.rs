@@ -0,0 +1,269 @@
+use crate::indexing::index::intersect;
+use std::collections::{HashMap, HashSet};
+
+// Wildcard patterns over the terms of the dictionary. `*` matches any run of
+// characters and `?` matches exactly one, though neither matches the colon
+// between a field and a term, so `*ger` doesn't match `hl:merger`.
+
+// Length of the grams in a `KGrams` index.
+const K: usize = 3;
+
+// Marks the start and end of a term, so grams can anchor a pattern to
+// either end. Never part of an analyzed term.
+const BOUNDARY: u8 = b'$';
+
+fn is_wildcard(c: char) -> bool {
+    c == '*' || c == '?'
+}
+
+// Returns true if `word` holds any wildcards.
+pub fn is_pattern(word: &str) -> bool {
+    word.contains(is_wildcard)
+}
+
+// The part of `pattern` before its first wildcard.
+pub fn literal_prefix(pattern: &str) -> &str {
+    let end = pattern.find(is_wildcard).unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+// If `pattern` is a plain prefix, like `merg*`, returns the prefix. Those
+// are expanded by scanning the sorted dictionary instead.
+pub fn prefix(pattern: &str) -> Option<&str> {
+    let prefix = pattern.strip_suffix('*')?;
+    if is_pattern(prefix) {
+        None
+    } else {
+        Some(prefix)
+    }
+}
+
+// Returns true if the whole of `term` matches `pattern`.
+pub fn matches(pattern: &str, term: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let term = term.chars().collect::<Vec<_>>();
+
+    let (mut i, mut j) = (0, 0);
+    // Just past the last `*` seen, and where in the term it stopped.
+    let mut star = None;
+
+    while j < term.len() {
+        match pattern.get(i) {
+            Some('*') => {
+                star = Some((i + 1, j));
+                i += 1;
+            }
+            Some(&c) if c == term[j] || (c == '?' && term[j] != ':') => {
+                i += 1;
+                j += 1;
+            }
+            // Backtrack, letting the last `*` swallow one more character.
+            _ => match star {
+                Some((after, stop)) if term[stop] != ':' => {
+                    star = Some((after, stop + 1));
+                    i = after;
+                    j = stop + 1;
+                }
+                _ => return false,
+            },
+        }
+    }
+
+    pattern[i..].iter().all(|&c| c == '*')
+}
+
+// The grams of a piece of a term or pattern without wildcards.
+fn grams(piece: &[u8]) -> impl Iterator<Item = [u8; K]> + '_ {
+    piece.windows(K).map(|gram| [gram[0], gram[1], gram[2]])
+}
+
+// An index from each k-gram to the terms holding it, which narrows down the
+// terms a wildcard pattern can match. Terms are numbered in the order they
+// were pushed, so pushing the terms of a segment in dictionary order numbers
+// them by their position in the dictionary.
+#[derive(Default)]
+pub struct KGrams {
+    // Gram -> terms holding it, in order
+    grams: HashMap<[u8; K], Vec<u64>>,
+    terms: u64,
+}
+
+impl KGrams {
+    pub fn new() -> KGrams {
+        KGrams::default()
+    }
+
+    pub fn push(&mut self, term: &str) {
+        for gram in grams(&padded(term)) {
+            let terms = self.grams.entry(gram).or_default();
+            // A gram can occur more than once in the same term.
+            if terms.last() != Some(&self.terms) {
+                terms.push(self.terms);
+            }
+        }
+
+        self.terms += 1;
+    }
+
+    // Returns the terms holding every gram of `pattern`, in order. These
+    // are only candidates, as the grams can occur in the wrong order or too
+    // far apart. Returns None if no piece of the pattern is long enough to
+    // hold a gram.
+    pub fn candidates(&self, pattern: &str) -> Option<Vec<u64>> {
+        let padded = padded(pattern);
+
+        let mut lists = Vec::new();
+        for piece in padded.split(|&b| b == b'*' || b == b'?') {
+            for gram in grams(piece) {
+                match self.grams.get(&gram) {
+                    Some(terms) => lists.push(terms.as_slice()),
+                    None => return Some(Vec::new()),
+                }
+            }
+        }
+
+        // The rarest grams go first, so the intersection shrinks quickly.
+        lists.sort_by_key(|terms| terms.len());
+        let (first, rest) = lists.split_first()?;
+
+        let mut terms = first.to_vec();
+        for list in rest {
+            terms = intersect(&terms, list);
+        }
+
+        Some(terms)
+    }
+
+    // Returns the terms which could be within `edits` edits of `term`, in
+    // order. Each edit changes at most `K` of a term's grams, so those terms
+    // share all but `edits * K` of the grams of `term`, and at least one.
+    pub fn similar(&self, term: &str, edits: usize) -> Vec<u64> {
+        let padded = padded(term);
+        let grams = grams(&padded).collect::<HashSet<_>>();
+        let shared = grams.len().saturating_sub(edits * K).max(1);
+
+        // Term -> number of grams shared
+        let mut counts: HashMap<u64, usize> = HashMap::new();
+        for gram in grams {
+            for &t in self.grams.get(&gram).map_or(&[][..], Vec::as_slice) {
+                *counts.entry(t).or_insert(0) += 1;
+            }
+        }
+
+        let mut terms = counts
+            .into_iter()
+            .filter(|&(_, count)| count >= shared)
+            .map(|(t, _)| t)
+            .collect::<Vec<_>>();
+        terms.sort_unstable();
+        terms
+    }
+}
+
+// Bounds `term` (or a pattern) with `BOUNDARY` at both ends.
+fn padded(term: &str) -> Vec<u8> {
+    let mut padded = Vec::with_capacity(term.len() + 2);
+    padded.push(BOUNDARY);
+    padded.extend_from_slice(term.as_bytes());
+    padded.push(BOUNDARY);
+    padded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::builder::IndexBuilder;
+    use crate::indexing::deletes::delete_documents;
+    use crate::indexing::index::DiskIndex;
+    use crate::indexing::testing::{matched, TempDir};
+
+    #[test]
+    fn patterns() {
+        assert!(matches("merg*", "merger"));
+        assert!(matches("merg*", "merg"));
+        assert!(matches("*ger", "merger"));
+        assert!(matches("m?rg*r", "merger"));
+        assert!(matches("*e*e*", "merger"));
+        assert!(!matches("m?rg*r", "mergers"));
+        assert!(!matches("?erger", "erger"));
+        assert!(!matches("*ger", "hl:merger"));
+        assert!(!matches("hl?merger", "hl:merger"));
+        assert!(matches("hl:*ger", "hl:merger"));
+
+        assert_eq!(prefix("merg*"), Some("merg"));
+        assert_eq!(prefix("m?rg*"), None);
+        assert_eq!(prefix("*"), Some(""));
+        assert_eq!(literal_prefix("hl:me?g*"), "hl:me");
+    }
+
+    #[test]
+    fn candidates() {
+        let mut kgrams = KGrams::new();
+        for term in &["banana", "bandana", "cabana", "merger", "nab"] {
+            kgrams.push(term);
+        }
+
+        assert_eq!(kgrams.candidates("*ana"), Some(vec![0, 1, 2]));
+        assert_eq!(kgrams.candidates("ban*ana"), Some(vec![0, 1]));
+        // Grams can be found in the wrong order.
+        assert_eq!(kgrams.candidates("*ana*ban*"), Some(vec![0, 1, 2]));
+        assert_eq!(kgrams.candidates("*xyz*"), Some(Vec::new()));
+        assert_eq!(kgrams.candidates("?a*"), None);
+
+        // `bandana` shares 4 of the 5 grams of `banana`, and `cabana` 3.
+        assert_eq!(kgrams.similar("banana", 0), vec![0]);
+        assert_eq!(kgrams.similar("banana", 1), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn wildcard_queries() {
+        let dir = TempDir::new("wildcard");
+
+        // Enough terms in the first segment to span several dictionary
+        // blocks, and a second segment sharing some of its terms.
+        let mut builder = IndexBuilder::new();
+        builder.add_document("a", ["merger"]).unwrap();
+        builder.add_document("b", ["mergers", "merge"]).unwrap();
+        builder.add_document("c", ["emerge"]).unwrap();
+        builder
+            .add_document("f", (0..2500).map(|i| format!("t{:04}", i)))
+            .unwrap();
+        builder.write(&dir).unwrap();
+
+        let mut builder = IndexBuilder::new();
+        builder.add_document("d", ["merged", "merger"]).unwrap();
+        builder.add_document("e", ["tokyo"]).unwrap();
+        builder.write_segment(&dir).unwrap();
+
+        let mut index = DiskIndex::from_disk(&dir).unwrap();
+        assert_eq!(
+            index.expand("merg*").unwrap(),
+            vec!["merge", "merged", "merger", "mergers"]
+        );
+        assert_eq!(index.expand("*erge?").unwrap(), vec!["merged", "merger"]);
+        assert_eq!(index.expand("t1*9").unwrap().len(), 100);
+        assert!(index.expand("x*").unwrap().is_empty());
+
+        // The pattern counts as a single term, occurring twice in b.
+        let postings = index.pattern("merg*").unwrap();
+        assert_eq!(
+            postings.iter().map(|p| p.frequency).collect::<Vec<_>>(),
+            vec![1, 2, 2]
+        );
+
+        assert_eq!(matched(&index, "merg* tokyo"), vec!["a", "b", "d", "e"]);
+        assert_eq!(matched(&index, "*merge AND NOT merg?r"), vec!["b", "c"]);
+
+        // Only the terms held by the most documents are kept, and ties go to
+        // the first in order.
+        index.set_expansions(2);
+        assert_eq!(index.expand("merg*").unwrap(), vec!["merge", "merger"]);
+
+        // Deleted documents don't count, so "merger" is held by no more
+        // documents than the rest.
+        delete_documents(&dir, ["a"]).unwrap();
+        let mut index = DiskIndex::from_disk(&dir).unwrap();
+        index.set_expansions(2);
+        assert_eq!(index.expand("merg*").unwrap(), vec!["merge", "merged"]);
+    }
+}
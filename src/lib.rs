@@ -0,0 +1,10 @@
+pub mod indexing;
+pub mod parsing;
+pub mod searching;
+
+pub use indexing::builder::IndexBuilder;
+pub use indexing::index::DiskIndex;
+pub use parsing::analyzer::{Analyzer, ParsedDocument};
+pub use searching::options::SearchOptions;
+pub use searching::query::Query;
+pub use searching::searcher::{SearchResult, Searcher};
@@ -1,68 +1,24 @@
 use std::io;
-use std::io::{stdout, BufWriter, Read, Write};
+use std::io::{stdout, BufWriter, Write};
 
-mod parsing;
-
-use parsing::terms::Terms;
-use parsing::tokens::{Token, Tokens};
+use asgn1::parsing::stream::write_document;
+use asgn1::Analyzer;
 
 fn main() -> std::io::Result<()> {
     let stdin = io::stdin();
-    let mut content = Vec::new();
-    stdin.lock().read_to_end(&mut content).unwrap();
-
-    let mut t = Tokens::new(content.as_slice());
+    let analyzer = Analyzer::new();
 
     let stdout = stdout();
     let mut out = BufWriter::new(stdout.lock());
 
+    // The collection is read a document at a time rather than all at once.
     let mut first = true;
-
-    while let Some(token) = t.next() {
-        match token {
-            Token::Tag(tag) => {
-                if tag.open && tag.name.to_ascii_lowercase() == "docno" {
-                    // Try print the document id
-                    // Note the extra newline to separate documents
-                    if let Some(Token::Text(id)) = t.next() {
-                        if first {
-                            first = false;
-                            writeln!(out, "{}", id)?;
-                        } else {
-                            writeln!(out, "\n{}", id)?;
-                        }
-
-                        // Write the components of the DOCNO to output
-                        for term in Terms::new(id) {
-                            writeln!(out, "{}", term)?;
-                        }
-                    }
-                }
-            }
-            // https://en.wikipedia.org/wiki/List_of_XML_and_HTML_character_entity_references
-            Token::Entity(data) => {
-                writeln!(
-                    out,
-                    "{}",
-                    match data {
-                        "quot" => "\"",
-                        "amp" => "&",
-                        "apos" => "'",
-                        "lt" => "<",
-                        "gt" => ">",
-                        _ => "",
-                    }
-                )?;
-            }
-            Token::Text(data) => {
-                let terms = Terms::new(data);
-
-                for term in terms {
-                    writeln!(out, "{}", term)?;
-                }
-            }
+    for raw in analyzer.raw_documents(stdin.lock()) {
+        for doc in analyzer.documents(&raw?) {
+            write_document(&mut out, &doc, first)?;
+            first = false;
         }
     }
 
-    Ok(())
+    out.flush()
 }
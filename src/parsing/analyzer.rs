@@ -0,0 +1,274 @@
+use super::field::Field;
+use super::terms::Terms;
+use super::tokens::{Token, Tokens};
+use std::io::BufRead;
+use std::ops::Range;
+
+// A document extracted from the collection, ready to be handed to the
+// indexer.
+#[derive(Debug, PartialEq)]
+pub struct ParsedDocument {
+    pub name: String,
+    pub terms: Vec<String>,
+    // The field each run of terms was read from. Terms outside any field
+    // (such as the DOCNO) aren't covered.
+    pub fields: Vec<(Field, Range<usize>)>,
+}
+
+impl ParsedDocument {
+    // Returns the field of every term.
+    pub fn term_fields(&self) -> Vec<Option<Field>> {
+        let mut fields = vec![None; self.terms.len()];
+        for (field, range) in &self.fields {
+            for f in &mut fields[range.clone()] {
+                *f = Some(*field);
+            }
+        }
+        fields
+    }
+}
+
+// The analyzer is the single place which decides how raw text becomes terms.
+// Both the indexer and the searcher go through it so that query terms are
+// normalised exactly the same way as document terms.
+#[derive(Debug, Default, Clone)]
+pub struct Analyzer;
+
+impl Analyzer {
+    pub fn new() -> Self {
+        Analyzer
+    }
+
+    // Splits a piece of text (a query, a DOCNO, the body of a tag) into terms.
+    pub fn terms<'a>(&self, text: &'a str) -> Terms<'a> {
+        Terms::new(text)
+    }
+
+    // Splits an XML collection into documents. A new document is started
+    // every time a <DOCNO> tag is encountered.
+    pub fn documents<'a>(&self, buffer: &'a [u8]) -> Documents<'a> {
+        Documents {
+            tokens: Tokens::new(buffer),
+            current: None,
+            field: None,
+        }
+    }
+
+    // Splits an XML collection read from `reader` into the raw text of each
+    // document, without analyzing it, so the collection needn't be held in
+    // memory at once. Each piece ends with a </DOC> tag, and going through
+    // `documents` on its own gives what it would have as part of the whole.
+    pub fn raw_documents<R: BufRead>(&self, reader: R) -> RawDocuments<R> {
+        RawDocuments { reader }
+    }
+}
+
+// https://en.wikipedia.org/wiki/List_of_XML_and_HTML_character_entity_references
+fn entity(name: &str) -> Option<&'static str> {
+    match name {
+        "quot" => Some("\""),
+        "amp" => Some("&"),
+        "apos" => Some("'"),
+        "lt" => Some("<"),
+        "gt" => Some(">"),
+        _ => None,
+    }
+}
+
+pub struct RawDocuments<R: BufRead> {
+    reader: R,
+}
+
+impl<R: BufRead> Iterator for RawDocuments<R> {
+    type Item = std::io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut doc = Vec::new();
+
+        // Reads a tag at a time, stopping after a closing DOC tag.
+        loop {
+            let start = doc.len();
+            match self.reader.read_until(b'>', &mut doc) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e)),
+            }
+
+            let read = &doc[start..];
+            if let Some(open) = read.iter().rposition(|&c| c == b'<') {
+                if read[open + 1..].eq_ignore_ascii_case(b"/doc>") {
+                    return Some(Ok(doc));
+                }
+            }
+        }
+
+        // Anything after the last document which isn't just whitespace.
+        if doc.iter().all(u8::is_ascii_whitespace) {
+            None
+        } else {
+            Some(Ok(doc))
+        }
+    }
+}
+
+pub struct Documents<'a> {
+    tokens: Tokens<'a>,
+    current: Option<ParsedDocument>,
+    // The field being read, and the index of its first term.
+    field: Option<(Field, usize)>,
+}
+
+impl<'a> Documents<'a> {
+    // Ends the field being read, if any.
+    fn close_field(&mut self) {
+        if let (Some((field, start)), Some(doc)) = (self.field.take(), self.current.as_mut()) {
+            if doc.terms.len() > start {
+                doc.fields.push((field, start..doc.terms.len()));
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Documents<'a> {
+    type Item = ParsedDocument;
+
+    fn next(&mut self) -> Option<ParsedDocument> {
+        while let Some(token) = self.tokens.next() {
+            match token {
+                Token::Tag(tag) => {
+                    if tag.open && tag.name.eq_ignore_ascii_case("docno") {
+                        if let Some(Token::Text(id)) = self.tokens.next() {
+                            self.close_field();
+
+                            // The components of the DOCNO are searchable too.
+                            let next = ParsedDocument {
+                                name: id.to_string(),
+                                terms: Terms::new(id).collect(),
+                                fields: Vec::new(),
+                            };
+
+                            if let Some(previous) = self.current.replace(next) {
+                                return Some(previous);
+                            }
+                        }
+                    } else if let Some(field) = Field::from_name(tag.name) {
+                        self.close_field();
+
+                        if tag.open {
+                            let start = self.current.as_ref().map_or(0, |d| d.terms.len());
+                            self.field = Some((field, start));
+                        }
+                    }
+                }
+                Token::Entity(data) => {
+                    if let (Some(doc), Some(text)) = (self.current.as_mut(), entity(data)) {
+                        doc.terms.push(text.to_string());
+                    }
+                }
+                Token::Text(data) => {
+                    if let Some(doc) = self.current.as_mut() {
+                        doc.terms.extend(Terms::new(data));
+                    }
+                }
+            }
+        }
+
+        self.close_field();
+        self.current.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documents() {
+        let collection = r#"
+<DOC>
+<DOCNO> WSJ870324-0001 </DOCNO>
+<HL> John Blair &amp; Co.</HL>
+</DOC>
+<DOC>
+<DOCNO> WSJ870324-0002 </DOCNO>
+<TEXT>
+   Industry sources said.
+</TEXT>
+</DOC>
+        "#;
+
+        let docs = Analyzer::new()
+            .documents(collection.as_bytes())
+            .collect::<Vec<_>>();
+
+        let expected = [
+            (
+                "WSJ870324-0001",
+                vec!["wsj870324", "0001", "john", "blair", "&", "co"],
+            ),
+            (
+                "WSJ870324-0002",
+                vec!["wsj870324", "0002", "industry", "sources", "said"],
+            ),
+        ];
+
+        assert_eq!(docs.len(), expected.len());
+        for (doc, (name, terms)) in docs.iter().zip(expected.iter()) {
+            assert_eq!(doc.name, *name);
+            assert_eq!(doc.terms, *terms);
+        }
+
+        assert_eq!(docs[0].fields, vec![(Field::Headline, 2..6)]);
+        assert_eq!(docs[1].fields, vec![(Field::Text, 2..5)]);
+        assert_eq!(
+            docs[1].term_fields(),
+            vec![
+                None,
+                None,
+                Some(Field::Text),
+                Some(Field::Text),
+                Some(Field::Text)
+            ]
+        );
+    }
+
+    #[test]
+    fn raw_documents() {
+        let collection = "<DOC>\n<DOCNO> WSJ870324-0001 </DOCNO>\n<HL> A &amp; B </HL>\n</DOC>\n\
+                          <doc><DOCNO> WSJ870324-0002 </DOCNO>\n<TEXT> Sources said. </TEXT></doc>\n\
+                          <DOC> <DOCNO> WSJ870324-0003 </DOCNO> <TEXT> Cut";
+
+        let analyzer = Analyzer::new();
+        let raw = analyzer
+            .raw_documents(collection.as_bytes())
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(raw.len(), 3);
+        assert!(raw[0].ends_with(b"</DOC>"));
+        assert!(raw[1].ends_with(b"</doc>"));
+        assert_eq!(raw.concat(), collection.as_bytes());
+
+        let docs = raw
+            .iter()
+            .flat_map(|doc| analyzer.documents(doc))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            docs,
+            analyzer
+                .documents(collection.as_bytes())
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!(analyzer.raw_documents(" \n".as_bytes()).count(), 0);
+    }
+
+    #[test]
+    fn no_documents() {
+        let docs = Analyzer::new()
+            .documents("<TEXT> no document number </TEXT>".as_bytes())
+            .collect::<Vec<_>>();
+
+        assert!(docs.is_empty());
+    }
+}
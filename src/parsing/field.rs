@@ -0,0 +1,121 @@
+// The elements of a WSJ document whose terms are indexed as separate fields,
+// on top of the document as a whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Field {
+    Headline,
+    Date,
+    Source,
+    Industry,
+    Dateline,
+    Text,
+}
+
+impl Field {
+    pub const COUNT: usize = 6;
+
+    // Every field, in the order per field data is stored in.
+    pub const ALL: [Field; Field::COUNT] = [
+        Field::Headline,
+        Field::Date,
+        Field::Source,
+        Field::Industry,
+        Field::Dateline,
+        Field::Text,
+    ];
+
+    // The name of the field, which is also the (lowercased) name of the tag
+    // it's read from.
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Headline => "hl",
+            Field::Date => "dd",
+            Field::Source => "so",
+            Field::Industry => "in",
+            Field::Dateline => "dateline",
+            Field::Text => "text",
+        }
+    }
+
+    // The position of the field within `Field::ALL`.
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn from_name(name: &str) -> Option<Field> {
+        Field::ALL
+            .iter()
+            .copied()
+            .find(|f| f.name().eq_ignore_ascii_case(name))
+    }
+
+    // Terms are indexed a second time under their field, prefixed with the
+    // field's name (`hl:merger`). Analyzed terms never hold a colon, so these
+    // can't clash with ordinary terms.
+    pub fn qualify(self, term: &str) -> String {
+        format!("{}:{}", self.name(), term)
+    }
+
+    // Splits a qualified term (or query word) into its field and the rest.
+    pub fn split(term: &str) -> Option<(Field, &str)> {
+        let (name, rest) = term.split_once(':')?;
+        Some((Field::from_name(name)?, rest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::builder::IndexBuilder;
+    use crate::indexing::testing::{fielded_documents, parsed_index, ranked, TempDir};
+
+    #[test]
+    fn names() {
+        for field in Field::ALL {
+            assert_eq!(Field::from_name(field.name()), Some(field));
+        }
+        assert_eq!(Field::from_name("DATELINE"), Some(Field::Dateline));
+        assert_eq!(Field::from_name("docno"), None);
+        assert_eq!(Field::Headline.qualify("merger"), "hl:merger");
+        assert_eq!(Field::split("hl:merger"), Some((Field::Headline, "merger")));
+        assert_eq!(Field::split("merger"), None);
+        assert_eq!(Field::split("docno:1"), None);
+
+        for (i, field) in Field::ALL.iter().enumerate() {
+            assert_eq!(field.index(), i);
+        }
+    }
+
+    #[test]
+    fn field_queries() {
+        let root = TempDir::new("fields");
+        let docs = fielded_documents();
+
+        let mut scores = Vec::new();
+        for fields in [false, true] {
+            let builder = IndexBuilder::new().fields(fields).positions(true);
+            let index = parsed_index(root.join(fields.to_string()), builder, &docs);
+            scores.push(index.search("merger").unwrap().collect::<Vec<_>>());
+
+            if fields {
+                assert_eq!(index.postings("hl:merger").unwrap().len(), 1);
+                assert_eq!(ranked(&index, "hl:merger"), vec!["a"]);
+                assert_eq!(ranked(&index, "dateline:tokyo"), vec!["b"]);
+                assert_eq!(ranked(&index, "hl:\"quiet day\""), vec!["b"]);
+                assert!(ranked(&index, "hl:tokyo").is_empty());
+            } else {
+                assert!(index.postings("hl:merger").unwrap().is_empty());
+            }
+        }
+
+        // Field terms don't count towards document lengths, so scores for
+        // unqualified terms are unchanged.
+        assert_eq!(scores[0], scores[1]);
+
+        // Indexes with and without fields can't be combined.
+        let mut with = IndexBuilder::new().fields(true);
+        with.add_parsed_document(&docs[0]).unwrap();
+        let mut without = IndexBuilder::new();
+        without.add_parsed_document(&docs[1]).unwrap();
+        assert!(with.append(without).is_err());
+    }
+}
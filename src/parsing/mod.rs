@@ -1,2 +1,5 @@
+pub mod analyzer;
+pub mod field;
+pub mod stream;
 pub mod terms;
 pub mod tokens;
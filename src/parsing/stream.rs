@@ -0,0 +1,144 @@
+use super::analyzer::ParsedDocument;
+use super::field::Field;
+use std::io::{BufRead, Lines, Write};
+
+// The term stream is the plain text format written by `parse` and read by
+// `index`. The first line of each document is its name, and each following
+// line is a term. Documents are separated by an empty line.
+//
+// The terms of a field are enclosed in marker lines naming the field, like
+// `<hl>` and `</hl>`. Terms never start with `<` followed by a letter, so
+// markers can't be mistaken for terms.
+
+// Parses a marker line into its field, and whether it opens the field.
+fn marker(line: &str) -> Option<(Field, bool)> {
+    let name = line.strip_prefix('<')?.strip_suffix('>')?;
+
+    match name.strip_prefix('/') {
+        Some(name) => Some((Field::from_name(name)?, false)),
+        None => Some((Field::from_name(name)?, true)),
+    }
+}
+
+// Writes `doc` to `writer`. `first` must be set for the first document of the
+// stream, which isn't preceded by a separator.
+pub fn write_document<W: Write>(
+    writer: &mut W,
+    doc: &ParsedDocument,
+    first: bool,
+) -> std::io::Result<()> {
+    // Note the extra newline to separate documents
+    if first {
+        writeln!(writer, "{}", doc.name)?;
+    } else {
+        writeln!(writer, "\n{}", doc.name)?;
+    }
+
+    let mut fields = doc.fields.iter().peekable();
+
+    for (i, term) in doc.terms.iter().enumerate() {
+        if let Some((field, range)) = fields.peek() {
+            if range.start == i {
+                writeln!(writer, "<{}>", field.name())?;
+            }
+        }
+
+        writeln!(writer, "{}", term)?;
+
+        if let Some((field, range)) = fields.peek() {
+            if range.end == i + 1 {
+                writeln!(writer, "</{}>", field.name())?;
+                fields.next();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Reads documents back out of a term stream, one line at a time.
+pub struct TermStream<R: BufRead> {
+    lines: Lines<R>,
+}
+
+impl<R: BufRead> TermStream<R> {
+    pub fn new(reader: R) -> Self {
+        TermStream {
+            lines: reader.lines(),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for TermStream<R> {
+    type Item = std::io::Result<ParsedDocument>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let name = match self.lines.next()? {
+            Ok(name) => name,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let mut terms = Vec::new();
+        let mut fields = Vec::new();
+        let mut open = None;
+
+        for line in &mut self.lines {
+            match line {
+                Ok(line) if line.is_empty() => break,
+                Ok(line) => match marker(&line) {
+                    Some((field, true)) => open = Some((field, terms.len())),
+                    Some((field, false)) => {
+                        if let Some((_, start)) = open.take().filter(|(f, _)| *f == field) {
+                            fields.push((field, start..terms.len()));
+                        }
+                    }
+                    None => terms.push(line),
+                },
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        Some(Ok(ParsedDocument {
+            name,
+            terms,
+            fields,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trip() {
+        let docs = vec![
+            ParsedDocument {
+                name: "WSJ870324-0001".to_string(),
+                terms: vec!["john".to_string(), "blair".to_string()],
+                fields: Vec::new(),
+            },
+            ParsedDocument {
+                name: "WSJ870324-0002".to_string(),
+                terms: vec!["<".to_string(), "industry".to_string()],
+                fields: vec![(Field::Text, 1..2)],
+            },
+        ];
+
+        let mut buffer = Vec::new();
+        for (n, doc) in docs.iter().enumerate() {
+            write_document(&mut buffer, doc, n == 0).unwrap();
+        }
+
+        assert_eq!(
+            String::from_utf8(buffer.clone()).unwrap(),
+            "WSJ870324-0001\njohn\nblair\n\nWSJ870324-0002\n<\n<text>\nindustry\n</text>\n"
+        );
+
+        let read = TermStream::new(Cursor::new(buffer))
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(read, docs);
+    }
+}
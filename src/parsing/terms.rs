@@ -21,7 +21,7 @@ impl<'a> Iterator for Terms<'a> {
     type Item = String;
 
     fn next(&mut self) -> Option<String> {
-        while let Some(m) = self.matches.next() {
+        for m in self.matches.by_ref() {
             let s = m.as_str();
 
             if s.chars().any(|c| c.is_alphanumeric()) {
@@ -51,7 +51,7 @@ mod tests {
 
     #[test]
     fn contractions() {
-        let mut t = Terms::new(
+        let t = Terms::new(
             "a'ight ain't amn't aren't can't could've couldn't didn't doesn't don't hasn't",
         );
 
@@ -68,7 +68,7 @@ mod tests {
 
     #[test]
     fn basic_words() {
-        let mut t = Terms::new(
+        let t = Terms::new(
             "John Blair was acquired last year by Reliance Capital Group Inc., which has been divesting itself of John Blair's major assets."
                 ,
         );
@@ -1,71 +1,387 @@
-mod indexing;
-mod parsing;
-use indexing::index::DiskIndex;
+use asgn1::searching::evaluation::{Evaluation, Qrels, Run};
+use asgn1::searching::options::{Grid, SearchOptions, PARAMETERS};
+use asgn1::searching::scorer::SCORERS;
+use asgn1::searching::synonyms::{Thesaurus, DEFAULT_SYNONYM_WEIGHT};
+use asgn1::{SearchResult, Searcher};
 
 use std::env;
-use std::io::{stdin, stdout, BufRead, BufWriter, Write};
+use std::fs::File;
+use std::io::{stderr, stdin, stdout, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+// Names the run in TREC output.
+const RUN_ID: &str = "thomas-passmore";
 
 fn main() -> std::io::Result<()> {
-    let mut index = match DiskIndex::from_disk() {
-        Ok(index) => index,
-        Err(_) => {
-            eprintln!("Failed to load index from disk.");
-            eprintln!("(Are the binary index files in this directory?)");
-            return Ok(())
+    // Parse options. Flags taking a value are given as `--flag value` or
+    // `--flag=value`.
+    let args: Vec<String> = env::args().collect();
+    let value = |flag: &str| {
+        args.iter()
+            .enumerate()
+            .find_map(|(i, a)| match a.strip_prefix(flag) {
+                Some("") => args.get(i + 1).map(String::as_str),
+                Some(rest) => rest.strip_prefix('='),
+                None => None,
+            })
+    };
+
+    let trec = args.iter().any(|a| a == "--trec");
+    let dir = value("--index").unwrap_or(".");
+
+    // Evaluates a run against the relevance judgments in `--qrels`, like
+    // trec_eval. The run is read from `--run`, or made by running the TREC
+    // queries on stdin with the options below. `-q` also reports each query.
+    let qrels = if args.get(1).map(String::as_str) == Some("evaluate") {
+        let path = match value("--qrels") {
+            Some(path) => path,
+            None => {
+                eprintln!("evaluate needs --qrels <file>");
+                return Ok(());
+            }
+        };
+        let qrels = Qrels::read(BufReader::new(File::open(path)?))?;
+
+        if let Some(path) = value("--run") {
+            let run = Run::read(BufReader::new(File::open(path)?))?;
+            let per_query = args.iter().any(|a| a == "-q");
+            return Evaluation::new(&qrels, &run).write(&mut stdout().lock(), per_query);
+        }
+
+        Some(qrels)
+    } else {
+        None
+    };
+
+    let mut options = SearchOptions::default();
+
+    // Ranks documents with one of the built-in models rather than BM25.
+    if let Some(name) = value("--scorer") {
+        options.scorer = name.to_string();
+    }
+
+    // Every ranking parameter has a flag of its own, like `--k1 1.2` or, for
+    // BM25F's per field parameters, `--weight-hl=3` and `--b-hl=0.3`.
+    for name in PARAMETERS.iter() {
+        if let Some(v) = value(&format!("--{}", name)) {
+            match v.parse() {
+                Ok(v) => {
+                    options.set(name, v);
+                }
+                Err(_) => {
+                    eprintln!("Invalid value {} for --{}", v, name);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Only returns the best N documents for each query, which lets plain
+    // scoring skip documents that can't make it.
+    if let Some(v) = value("--limit") {
+        match v.parse() {
+            Ok(limit) => options.limit = Some(limit),
+            Err(_) => {
+                eprintln!("Invalid value {} for --limit", v);
+                return Ok(());
+            }
+        }
+    }
+
+    // Caps the number of terms a wildcard pattern like `merg*` expands to.
+    if let Some(v) = value("--expansions") {
+        match v.parse() {
+            Ok(expansions) => options.expansions = expansions,
+            Err(_) => {
+                eprintln!("Invalid value {} for --expansions", v);
+                return Ok(());
+            }
+        }
+    }
+
+    // Number of queries run at once, each on its own thread. The results are
+    // still written in the order the queries were read.
+    let threads = match value("--threads").map(|t| t.parse::<usize>()) {
+        None => 1,
+        Some(Ok(threads)) if threads > 0 => threads,
+        Some(_) => {
+            eprintln!("--threads takes a positive number");
+            return Ok(());
         }
     };
 
+    // Boosts documents where the query terms occur close together. Needs an
+    // index built with positions.
+    options.proximity = args.iter().any(|a| a == "--proximity");
+
+    // Ranks documents with BM25F, weighting each field separately. Needs an
+    // index built with fields.
+    options.bm25f = args.iter().any(|a| a == "--bm25f");
+
+    // Each `--sweep` adds a parameter to the grid, like `--sweep k1=0.5,0.9`
+    // or `--sweep b=0.1:0.9:0.1`.
+    let mut grid = Grid::new();
+    for spec in args.windows(2).filter(|w| w[0] == "--sweep") {
+        if let Err(e) = grid.axis(&spec[1]) {
+            eprintln!("{}", e);
+            return Ok(());
+        }
+    }
+
+    let mut searcher = match Searcher::open(dir) {
+        Ok(searcher) => searcher,
+        Err(e) => {
+            eprintln!("Failed to load index from disk: {}", e);
+            eprintln!("(Are the binary index files in {}?)", dir);
+            return Ok(());
+        }
+    };
+
+    // Bounds the cache of dictionary blocks and the cache of postings lists
+    // to this many MiB each.
+    if let Some(v) = value("--cache") {
+        match v.parse::<usize>() {
+            Ok(mib) => {
+                searcher.index().set_block_cache(mib * 1024 * 1024);
+                searcher.index().set_postings_cache(mib * 1024 * 1024);
+            }
+            Err(_) => {
+                eprintln!("Invalid value {} for --cache", v);
+                return Ok(());
+            }
+        }
+    }
+
+    if let Err(e) = searcher.index().set_options(&options) {
+        eprintln!("{}", e);
+        eprintln!("(Expected one of {})", SCORERS.join(", "));
+        return Ok(());
+    }
+
+    // Expands the words and phrases of each query with their synonyms from
+    // a file in Solr's or WordNet's format, each scoring `--synonym-weight`
+    // times what the word would. Queries starting with `=` aren't expanded.
+    if let Some(path) = value("--synonyms") {
+        let thesaurus = match File::open(path).and_then(|f| Thesaurus::read(BufReader::new(f))) {
+            Ok(thesaurus) => thesaurus,
+            Err(e) => {
+                eprintln!("Failed to read synonyms from {}: {}", path, e);
+                return Ok(());
+            }
+        };
+
+        let weight = match value("--synonym-weight").map(|w| w.parse()) {
+            None => DEFAULT_SYNONYM_WEIGHT,
+            Some(Ok(weight)) => weight,
+            Some(Err(_)) => {
+                eprintln!("Invalid value for --synonym-weight");
+                return Ok(());
+            }
+        };
+        searcher
+            .index()
+            .set_synonyms(Some(thesaurus.weight(weight)));
+    }
+
+    if !grid.is_empty() {
+        let runs = value("--runs").unwrap_or(".");
+        return sweep(&mut searcher, &grid, &options, threads, Path::new(runs));
+    }
+
+    // Writes a "Did you mean" line to stderr for each query with a likely
+    // misspelling. They're kept off stdout so run files stay as trec_eval
+    // expects them.
+    let suggest = args.iter().any(|a| a == "--suggest");
+
     let stdout = stdout();
     let mut out = BufWriter::new(stdout.lock());
 
-    // Parse options
-    let args: Vec<String> = env::args().collect();
-    let trec = args.iter().any(|a| a == "--trec");
+    if let Some(qrels) = qrels {
+        let (ids, queries): (Vec<_>, Vec<_>) = read_queries(true).into_iter().unzip();
+        let results = searcher.search_all(&queries, threads)?;
 
-    let stdin = stdin();
-    for line in stdin.lock().lines() {
-        if let Ok(str) = line {
-            if str.is_empty() {
-                continue;
+        let mut run = Run::new(RUN_ID);
+        for (trec_id, results) in ids.into_iter().zip(results) {
+            let query = trec_id.unwrap().to_string();
+            for r in results {
+                run.push(&query, &r.name, r.score as f64);
             }
+        }
 
-            // If we're parsing the query as a TREC query, take the first
-            // column to be the query ID.
-            let (trec_id, query) = if trec {
-                let mut split = str.split_ascii_whitespace();
-                let id = split.next().unwrap();
-
-                (
-                    Some(id.parse::<u32>().unwrap()),
-                    split.fold(String::new(), |mut a, b| {
-                        a.push_str(b);
-                        a.push(' ');
-                        a
-                    }),
-                )
-            } else {
-                (None, str)
-            };
+        let per_query = args.iter().any(|a| a == "-q");
+        Evaluation::new(&qrels, &run).write(&mut out, per_query)?;
+        return out.flush();
+    }
 
-            let results = index.search(&query)?;
-            if let Some(trec_id) = trec_id {
-                for r in results.into_iter() {
-                    writeln!(
-                        out,
-                        "{} Q0 {} 0 {} thomas-passmore",
-                        trec_id,
-                        index.document(r.1),
-                        r.0
-                    )?;
+    if threads > 1 {
+        // Every query is read up front, so the results can be written out in
+        // order once they're all in.
+        let (ids, queries): (Vec<_>, Vec<_>) = read_queries(trec).into_iter().unzip();
+        let results = searcher.search_all(&queries, threads)?;
+
+        for ((trec_id, query), results) in ids.into_iter().zip(&queries).zip(results) {
+            if suggest {
+                if let Some(corrected) = searcher.suggest(query)? {
+                    write_suggestion(&mut stderr(), trec_id, &corrected)?;
                 }
-            } else {
-                for r in results.into_iter() {
-                    writeln!(out, "{} {}", index.document(r.1), r.0)?;
+            }
+            write_results(&mut out, trec_id, results)?;
+        }
+        out.flush()?;
+    } else {
+        let stdin = stdin();
+        for line in stdin.lock().lines() {
+            // Lines which aren't valid UTF-8 are skipped.
+            let str = match line {
+                Ok(str) if !str.is_empty() => str,
+                _ => continue,
+            };
+
+            let (trec_id, query) = parse_line(str, trec);
+            let results = searcher.search(&query)?;
+            if suggest {
+                if let Some(corrected) = searcher.suggest(&query)? {
+                    write_suggestion(&mut stderr(), trec_id, &corrected)?;
                 }
             }
+            write_results(&mut out, trec_id, results)?;
             out.flush()?;
         }
     }
 
+    if args.iter().any(|a| a == "--cache-stats") {
+        let index = searcher.index();
+        for (name, stats) in [
+            ("blocks", index.block_cache_stats()),
+            ("postings", index.postings_cache_stats()),
+        ] {
+            eprintln!(
+                "{} cache: {} hits, {} misses ({:.1}% hit rate), {} evictions, {} entries, {}/{} bytes",
+                name,
+                stats.hits,
+                stats.misses,
+                stats.hit_rate() * 100.0,
+                stats.evictions,
+                stats.entries,
+                stats.bytes,
+                stats.capacity
+            );
+        }
+    }
+
     Ok(())
 }
+
+// Runs every TREC query on stdin once for each setting of `grid`, writing a
+// run file for each into `runs`, named after the setting. The queries of
+// each setting are run on `threads` threads.
+fn sweep(
+    searcher: &mut Searcher,
+    grid: &Grid,
+    options: &SearchOptions,
+    threads: usize,
+    runs: &Path,
+) -> std::io::Result<()> {
+    let (ids, queries): (Vec<_>, Vec<_>) = read_queries(true).into_iter().unzip();
+
+    std::fs::create_dir_all(runs)?;
+
+    for (label, options) in grid.settings(options) {
+        searcher.index().set_options(&options)?;
+
+        let path = runs.join(format!("{}_{}.run", options.scorer, label));
+        let mut out = BufWriter::new(File::create(&path)?);
+
+        let results = searcher.search_all(&queries, threads)?;
+        for (trec_id, results) in ids.iter().zip(results) {
+            write_results(&mut out, *trec_id, results)?;
+        }
+
+        out.flush()?;
+        eprintln!("{}", path.display());
+    }
+
+    Ok(())
+}
+
+// Reads every query on stdin, skipping blank lines and lines which aren't
+// valid UTF-8.
+fn read_queries(trec: bool) -> Vec<(Option<u32>, String)> {
+    let mut queries = Vec::new();
+    for line in stdin().lock().lines() {
+        match line {
+            Ok(line) if !line.is_empty() => queries.push(parse_line(line, trec)),
+            _ => continue,
+        }
+    }
+    queries
+}
+
+// If we're parsing the query as a TREC query, take the first column to be
+// the query ID.
+fn parse_line(str: String, trec: bool) -> (Option<u32>, String) {
+    if trec {
+        let mut split = str.split_ascii_whitespace();
+        let id = split.next().unwrap();
+
+        (
+            Some(id.parse::<u32>().unwrap()),
+            split.fold(String::new(), |mut a, b| {
+                a.push_str(b);
+                a.push(' ');
+                a
+            }),
+        )
+    } else {
+        (None, str)
+    }
+}
+
+// Writes the corrected version of a query, prefixed with the query ID if
+// it's a TREC query.
+fn write_suggestion<W: Write>(
+    out: &mut W,
+    trec_id: Option<u32>,
+    corrected: &str,
+) -> std::io::Result<()> {
+    match trec_id {
+        Some(trec_id) => writeln!(out, "{} Did you mean: {}", trec_id, corrected.trim_end()),
+        None => writeln!(out, "Did you mean: {}", corrected.trim_end()),
+    }
+}
+
+fn write_results<W: Write>(
+    out: &mut W,
+    trec_id: Option<u32>,
+    results: Vec<SearchResult>,
+) -> std::io::Result<()> {
+    if let Some(trec_id) = trec_id {
+        for r in results.into_iter() {
+            writeln!(out, "{} Q0 {} 0 {} {}", trec_id, r.name, r.score, RUN_ID)?;
+        }
+    } else {
+        for r in results.into_iter() {
+            writeln!(out, "{} {}", r.name, r.score)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggestion_lines() {
+        let mut out = Vec::new();
+        write_suggestion(&mut out, None, "\"wall street\" journal").unwrap();
+        // TREC queries are parsed with a trailing space.
+        write_suggestion(&mut out, Some(401), "foreign minorities germany ").unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Did you mean: \"wall street\" journal\n401 Did you mean: foreign minorities germany\n"
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use crate::parsing::field::Field;
+
+// Parameters of BM25F (Robertson, Zaragoza and Taylor, "Simple BM25 Extension
+// to Multiple Weighted Fields", CIKM 2004). Rather than scoring each field on
+// its own, the frequency of a term in every field is length normalised
+// against that field, weighted and summed into a single pseudo frequency,
+// which then goes through the usual BM25 saturation,
+//
+// tf(q_i, D) = Sum{fields} w_f * f(q_i, D_f) / (1 - b_f + b_f * (|D_f| / avgdl_f))
+// score(D,Q) = Sum{1..n} IDF(q_i) * ( tf(q_i, D) * (k_1 + 1) ) / ( tf(q_i, D) + k_1 )
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bm25f {
+    pub k1: f32,
+    // Per field, in `Field::ALL` order.
+    pub weights: [f32; Field::COUNT],
+    pub b: [f32; Field::COUNT],
+}
+
+impl Default for Bm25f {
+    // Headlines are short and say what the article is about, so they're
+    // weighted well above the body. The date and source fields are
+    // boilerplate and mostly get in the way.
+    fn default() -> Self {
+        let mut params = Bm25f {
+            k1: 0.9,
+            weights: [1.0; Field::COUNT],
+            b: [0.4; Field::COUNT],
+        };
+        params.weights[Field::Headline.index()] = 3.0;
+        params.weights[Field::Date.index()] = 0.0;
+        params.weights[Field::Source.index()] = 0.0;
+        params.b[Field::Headline.index()] = 0.3;
+        params
+    }
+}
+
+impl Bm25f {
+    pub fn weight(mut self, field: Field, weight: f32) -> Self {
+        self.weights[field.index()] = weight;
+        self
+    }
+
+    pub fn b(mut self, field: Field, b: f32) -> Self {
+        self.b[field.index()] = b;
+        self
+    }
+
+    // The contribution of `frequency` occurrences in `field` to a term's
+    // pseudo frequency, for a field `length` terms long.
+    pub fn frequency(&self, field: Field, frequency: u64, length: u64, avg_length: f32) -> f32 {
+        let b = self.b[field.index()];
+        let ratio = if avg_length > 0.0 {
+            length as f32 / avg_length
+        } else {
+            1.0
+        };
+
+        self.weights[field.index()] * frequency as f32 / (1.0 - b + b * ratio)
+    }
+
+    // Scores a term with pseudo frequency `tf`.
+    pub fn score(&self, idf: f32, tf: f32) -> f32 {
+        idf * (tf * (self.k1 + 1.0)) / (tf + self.k1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::builder::IndexBuilder;
+    use crate::indexing::index::DiskIndex;
+    use crate::indexing::merge::force_merge;
+    use crate::indexing::testing::{fielded_documents, parsed_index, TempDir};
+
+    #[test]
+    fn frequencies() {
+        let params = Bm25f::default()
+            .weight(Field::Text, 1.0)
+            .b(Field::Text, 0.5);
+
+        // An average length field isn't normalised at all.
+        assert_eq!(params.frequency(Field::Text, 2, 10, 10.0), 2.0);
+        // Longer fields count for less, shorter ones for more.
+        assert_eq!(params.frequency(Field::Text, 2, 30, 10.0), 1.0);
+        assert_eq!(params.frequency(Field::Text, 1, 0, 10.0), 2.0);
+        assert_eq!(params.frequency(Field::Date, 5, 1, 1.0), 0.0);
+
+        let headline = params.frequency(Field::Headline, 1, 5, 5.0);
+        assert_eq!(headline, 3.0);
+        assert!(params.score(1.0, headline) > params.score(1.0, 1.0));
+        assert!(params.score(1.0, 1000.0) < 1.0 + params.k1);
+    }
+
+    #[test]
+    fn ranking() {
+        let root = TempDir::new("bm25f");
+        let docs = fielded_documents();
+        let dir = root.join("fields");
+
+        let builder = IndexBuilder::new().fields(true).positions(true);
+        let mut index = parsed_index(&dir, builder, &docs);
+        assert!(index.has_fields());
+        index.set_bm25f(Some(Bm25f::default()));
+
+        let ranked = |index: &DiskIndex, query: &str| {
+            index
+                .search(query)
+                .unwrap()
+                .map(|(score, doc)| (index.document(doc).to_string(), score))
+                .collect::<Vec<_>>()
+        };
+
+        // BM25F prefers the headline match, even though the other document
+        // is shorter.
+        let before = ranked(&index, "merger talks");
+        assert_eq!(before[0].0, "a");
+        assert_eq!(ranked(&index, "hl:merger").len(), 1);
+
+        // Field lengths survive merging segments.
+        let mut builder = IndexBuilder::new().fields(true).positions(true);
+        builder.add_parsed_document(&docs[1]).unwrap();
+        builder.update(&dir).unwrap();
+        force_merge(&dir).unwrap();
+        let mut index = DiskIndex::from_disk(&dir).unwrap();
+        index.set_bm25f(Some(Bm25f::default()));
+        let mut after = ranked(&index, "merger talks");
+        after.sort_by(|a, b| b.1.total_cmp(&a.1));
+        assert_eq!(after, before);
+
+        // BM25F can't be used without fields.
+        let mut index = parsed_index(root.join("plain"), IndexBuilder::new(), &docs);
+        assert!(!index.has_fields());
+        index.set_bm25f(Some(Bm25f::default()));
+        assert!(index.search("merger").is_err());
+    }
+}
@@ -0,0 +1,461 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Write};
+
+// The recall levels interpolated precision is reported at. Written out rather
+// than computed so each compares equal to the recall it stands for (3 / 10 is
+// 0.3, but 3.0 * 0.1 isn't).
+const RECALL_LEVELS: [f64; 11] = [0.0, 0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0];
+
+// The cutoffs precision and nDCG are reported at.
+const CUTOFFS: [usize; 9] = [5, 10, 15, 20, 30, 100, 200, 500, 1000];
+
+// Average precisions are clamped to this before their logs are taken for
+// GM-MAP, so a single query with no relevant documents doesn't zero it.
+const MIN_GEO_MEAN: f64 = 0.00001;
+
+// Relevance judgments, as read from a TREC qrels file. Each line gives a
+// query ID, an unused iteration number, a document name and its relevance,
+//
+//   51 0 WSJ870227-0149 1
+//
+// A document is relevant if its relevance is at least 1. Documents judged 0
+// (or below) are judged non-relevant, and documents without a judgment are
+// unjudged.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Qrels {
+    // Query -> document -> relevance
+    judgments: HashMap<String, HashMap<String, i64>>,
+}
+
+// The documents retrieved for each query, with their scores, as read from a
+// TREC run file,
+//
+//   51 Q0 WSJ870227-0149 0 18.04851 thomas-passmore
+//
+// The rank column is ignored; like trec_eval, documents are ranked by score,
+// with ties going to the greater document name.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Run {
+    pub name: String,
+    // Query -> [(document, score)]
+    queries: BTreeMap<String, Vec<(String, f64)>>,
+}
+
+// The measures of a single query, or their aggregate over every query.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Measures {
+    pub retrieved: u64,
+    pub relevant: u64,
+    pub relevant_retrieved: u64,
+    // Average precision. Averaged over queries, this is MAP.
+    pub map: f64,
+    // For a single query, the log of its (clamped) average precision. Over
+    // every query, the geometric mean of their average precisions.
+    pub gm_map: f64,
+    // Precision after R documents, where R is the number of relevant ones.
+    pub r_prec: f64,
+    pub bpref: f64,
+    pub recip_rank: f64,
+    // Interpolated precision at each of `RECALL_LEVELS`.
+    pub iprec_at_recall: [f64; 11],
+    // Precision and nDCG after each of `CUTOFFS` documents.
+    pub precision: [f64; 9],
+    pub ndcg: [f64; 9],
+}
+
+// The measures of every query of a run which has judgments, and their
+// aggregate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Evaluation {
+    pub run: String,
+    pub queries: Vec<(String, Measures)>,
+    pub all: Measures,
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+impl Qrels {
+    pub fn read<R: BufRead>(reader: R) -> std::io::Result<Qrels> {
+        let mut qrels = Qrels::default();
+
+        for line in reader.lines() {
+            let line = line?;
+            let fields = line.split_ascii_whitespace().collect::<Vec<_>>();
+
+            match fields.as_slice() {
+                [] => continue,
+                [query, _, document, relevance] => {
+                    let relevance = relevance
+                        .parse::<i64>()
+                        .map_err(|_| invalid(format!("bad qrels line '{}'", line)))?;
+
+                    qrels
+                        .judgments
+                        .entry(query.to_string())
+                        .or_default()
+                        .insert(document.to_string(), relevance);
+                }
+                _ => return Err(invalid(format!("bad qrels line '{}'", line))),
+            }
+        }
+
+        Ok(qrels)
+    }
+}
+
+impl Run {
+    pub fn new(name: &str) -> Self {
+        Run {
+            name: name.to_string(),
+            queries: BTreeMap::new(),
+        }
+    }
+
+    // Reads a run file. The run is named after the last column of its first
+    // line.
+    pub fn read<R: BufRead>(reader: R) -> std::io::Result<Run> {
+        let mut run = Run::default();
+
+        for line in reader.lines() {
+            let line = line?;
+            let fields = line.split_ascii_whitespace().collect::<Vec<_>>();
+
+            match fields.as_slice() {
+                [] => continue,
+                [query, _, document, _, score, name] => {
+                    let score = score
+                        .parse::<f64>()
+                        .map_err(|_| invalid(format!("bad run line '{}'", line)))?;
+
+                    if run.queries.is_empty() {
+                        run.name = name.to_string();
+                    }
+                    run.push(query, document, score);
+                }
+                _ => return Err(invalid(format!("bad run line '{}'", line))),
+            }
+        }
+
+        Ok(run)
+    }
+
+    // Records that `document` was retrieved for `query` with `score`.
+    pub fn push(&mut self, query: &str, document: &str, score: f64) {
+        self.queries
+            .entry(query.to_string())
+            .or_default()
+            .push((document.to_string(), score));
+    }
+}
+
+impl Evaluation {
+    // Evaluates every query of `run` which has judgments in `qrels`, as
+    // trec_eval does by default. Queries are ordered by ID, compared as
+    // strings.
+    pub fn new(qrels: &Qrels, run: &Run) -> Evaluation {
+        let queries = run
+            .queries
+            .iter()
+            .filter_map(|(query, retrieved)| {
+                let judgments = qrels.judgments.get(query)?;
+                Some((query.clone(), Measures::of(judgments, retrieved)))
+            })
+            .collect::<Vec<_>>();
+
+        let all = Measures::aggregate(queries.iter().map(|(_, m)| m));
+
+        Evaluation {
+            run: run.name.clone(),
+            queries,
+            all,
+        }
+    }
+
+    // Writes the measures out in trec_eval's format, those of each query
+    // first if `per_query` is set.
+    pub fn write<W: Write>(&self, out: &mut W, per_query: bool) -> std::io::Result<()> {
+        if per_query {
+            for (query, measures) in &self.queries {
+                measures.write(out, query)?;
+            }
+        }
+
+        line(out, "runid", "all", &self.run)?;
+        line(out, "num_q", "all", self.queries.len())?;
+        self.all.write(out, "all")
+    }
+}
+
+fn line<W: Write, V: std::fmt::Display>(
+    out: &mut W,
+    measure: &str,
+    query: &str,
+    value: V,
+) -> std::io::Result<()> {
+    writeln!(out, "{:<22}\t{}\t{}", measure, query, value)
+}
+
+impl Measures {
+    // Measures a single query, given its judgments and the documents
+    // retrieved for it.
+    fn of(judgments: &HashMap<String, i64>, retrieved: &[(String, f64)]) -> Measures {
+        let mut ranking = retrieved.iter().collect::<Vec<_>>();
+        ranking.sort_by(|a, b| b.1.total_cmp(&a.1).then(b.0.cmp(&a.0)));
+
+        // The relevance of each retrieved document, if it was judged.
+        let judged = ranking
+            .iter()
+            .map(|(document, _)| judgments.get(document).copied())
+            .collect::<Vec<_>>();
+        let is_relevant = |r: &Option<i64>| matches!(r, Some(r) if *r >= 1);
+
+        let relevant = judgments.values().filter(|&&r| r >= 1).count();
+        let nonrelevant = judgments.len() - relevant;
+
+        let mut m = Measures {
+            retrieved: ranking.len() as u64,
+            relevant: relevant as u64,
+            ..Measures::default()
+        };
+
+        // Precision at each rank holding a relevant document.
+        let mut precisions = Vec::new();
+        let mut nonrelevant_so_far = 0;
+
+        for (rank, r) in judged.iter().enumerate() {
+            if is_relevant(r) {
+                precisions.push((precisions.len() + 1) as f64 / (rank + 1) as f64);
+
+                // bpref only counts the judged non-relevant documents ranked
+                // above, up to R of them.
+                m.bpref += if nonrelevant_so_far == 0 {
+                    1.0
+                } else {
+                    1.0 - nonrelevant_so_far.min(relevant) as f64 / relevant.min(nonrelevant) as f64
+                };
+            } else if r.is_some() {
+                nonrelevant_so_far += 1;
+            }
+        }
+
+        let relevant_in = |n: usize| judged.iter().take(n).filter(|r| is_relevant(r)).count();
+
+        m.relevant_retrieved = precisions.len() as u64;
+        if relevant > 0 {
+            m.map = precisions.iter().sum::<f64>() / relevant as f64;
+            m.r_prec = relevant_in(relevant) as f64 / relevant as f64;
+            m.bpref /= relevant as f64;
+        }
+        m.gm_map = m.map.max(MIN_GEO_MEAN).ln();
+        m.recip_rank = judged
+            .iter()
+            .position(is_relevant)
+            .map_or(0.0, |rank| 1.0 / (rank + 1) as f64);
+
+        // The interpolated precision at a recall level is the highest
+        // precision at any rank reaching that recall, and only ranks holding
+        // relevant documents can be the highest.
+        for (level, iprec) in RECALL_LEVELS.iter().zip(m.iprec_at_recall.iter_mut()) {
+            *iprec = precisions
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| (i + 1) as f64 / relevant.max(1) as f64 >= *level)
+                .map(|(_, &p)| p)
+                .fold(0.0, f64::max);
+        }
+
+        // Gains are the relevance of each document, and the ideal ranking
+        // puts the most relevant first.
+        let gain = |r: &Option<i64>| r.unwrap_or(0).max(0) as f64;
+        let mut ideal = judgments
+            .values()
+            .map(|&r| r.max(0) as f64)
+            .collect::<Vec<_>>();
+        ideal.sort_by(|a, b| b.partial_cmp(a).unwrap());
+
+        let dcg = |gains: &mut dyn Iterator<Item = f64>, k: usize| {
+            gains
+                .take(k)
+                .enumerate()
+                .map(|(rank, g)| g / (rank as f64 + 2.0).log2())
+                .sum::<f64>()
+        };
+
+        for (i, &k) in CUTOFFS.iter().enumerate() {
+            m.precision[i] = relevant_in(k) as f64 / k as f64;
+
+            let best = dcg(&mut ideal.iter().copied(), k);
+            if best > 0.0 {
+                m.ndcg[i] = dcg(&mut judged.iter().map(gain), k) / best;
+            }
+        }
+
+        m
+    }
+
+    // Sums the counts of `queries`, and takes the mean of everything else.
+    // The logs kept for GM-MAP are turned back into a geometric mean.
+    fn aggregate<'a, I: Iterator<Item = &'a Measures>>(queries: I) -> Measures {
+        let mut all = Measures::default();
+        let mut n = 0;
+
+        for m in queries {
+            n += 1;
+            all.retrieved += m.retrieved;
+            all.relevant += m.relevant;
+            all.relevant_retrieved += m.relevant_retrieved;
+            all.map += m.map;
+            all.gm_map += m.gm_map;
+            all.r_prec += m.r_prec;
+            all.bpref += m.bpref;
+            all.recip_rank += m.recip_rank;
+
+            let arrays = [
+                (&mut all.iprec_at_recall[..], &m.iprec_at_recall[..]),
+                (&mut all.precision[..], &m.precision[..]),
+                (&mut all.ndcg[..], &m.ndcg[..]),
+            ];
+            for (sums, values) in arrays {
+                for (sum, value) in sums.iter_mut().zip(values) {
+                    *sum += value;
+                }
+            }
+        }
+
+        if n == 0 {
+            return all;
+        }
+
+        let n = n as f64;
+        for mean in [
+            &mut all.map,
+            &mut all.gm_map,
+            &mut all.r_prec,
+            &mut all.bpref,
+            &mut all.recip_rank,
+        ] {
+            *mean /= n;
+        }
+        for mean in all
+            .iprec_at_recall
+            .iter_mut()
+            .chain(all.precision.iter_mut())
+            .chain(all.ndcg.iter_mut())
+        {
+            *mean /= n;
+        }
+        all.gm_map = all.gm_map.exp();
+
+        all
+    }
+
+    fn write<W: Write>(&self, out: &mut W, query: &str) -> std::io::Result<()> {
+        let float = |v: f64| format!("{:.4}", v);
+
+        line(out, "num_ret", query, self.retrieved)?;
+        line(out, "num_rel", query, self.relevant)?;
+        line(out, "num_rel_ret", query, self.relevant_retrieved)?;
+        line(out, "map", query, float(self.map))?;
+        line(out, "gm_map", query, float(self.gm_map))?;
+        line(out, "Rprec", query, float(self.r_prec))?;
+        line(out, "bpref", query, float(self.bpref))?;
+        line(out, "recip_rank", query, float(self.recip_rank))?;
+
+        for (level, iprec) in RECALL_LEVELS.iter().zip(&self.iprec_at_recall) {
+            let name = format!("iprec_at_recall_{:.2}", level);
+            line(out, &name, query, float(*iprec))?;
+        }
+        for (k, precision) in CUTOFFS.iter().zip(&self.precision) {
+            line(out, &format!("P_{}", k), query, float(*precision))?;
+        }
+        for (k, ndcg) in CUTOFFS.iter().zip(&self.ndcg) {
+            line(out, &format!("ndcg_cut_{}", k), query, float(*ndcg))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn measures() {
+        let qrels = Qrels::read(
+            "1 0 a 1\n1 0 b 0\n1 0 c 2\n1 0 d 1\n1 0 e 0\n\n2 0 x 0\n3 0 y 1\n".as_bytes(),
+        )
+        .unwrap();
+
+        // Query 1 ranks a, b, (unjudged) z, c, e; d isn't retrieved. Ranks
+        // come from the scores, not the order or the rank column. Query 4 has
+        // no judgments, so isn't evaluated.
+        let run = Run::read(
+            "1 Q0 b 1 4.0 test\n1 Q0 a 2 5.0 test\n1 Q0 z 3 3.0 test\n1 Q0 c 4 2.0 test\n\
+             1 Q0 e 5 1.0 test\n2 Q0 x 1 1.0 test\n3 Q0 q 1 2.0 test\n3 Q0 y 1 2.0 test\n\
+             4 Q0 a 1 1.0 test\n"
+                .as_bytes(),
+        )
+        .unwrap();
+
+        let evaluation = Evaluation::new(&qrels, &run);
+        assert_eq!(evaluation.run, "test");
+        assert_eq!(
+            evaluation
+                .queries
+                .iter()
+                .map(|(q, _)| q.as_str())
+                .collect::<Vec<_>>(),
+            vec!["1", "2", "3"]
+        );
+
+        let m = &evaluation.queries[0].1;
+        assert_eq!((m.retrieved, m.relevant, m.relevant_retrieved), (5, 3, 2));
+        // Relevant at ranks 1 and 4.
+        assert!(close(m.map, (1.0 + 2.0 / 4.0) / 3.0));
+        assert!(close(m.r_prec, 1.0 / 3.0));
+        // c has one judged non-relevant document above it, out of min(3, 2).
+        assert!(close(m.bpref, (1.0 + (1.0 - 1.0 / 2.0)) / 3.0));
+        assert!(close(m.recip_rank, 1.0));
+        assert!(close(m.iprec_at_recall[3], 1.0));
+        assert!(close(m.iprec_at_recall[4], 0.5));
+        assert!(close(m.iprec_at_recall[7], 0.0));
+        assert!(close(m.precision[0], 2.0 / 5.0));
+        let dcg = 1.0 + 2.0 / 5f64.log2();
+        let ideal = 2.0 + 1.0 / 3f64.log2() + 1.0 / 4f64.log2();
+        assert!(close(m.ndcg[0], dcg / ideal));
+
+        // No relevant documents at all.
+        let m = &evaluation.queries[1].1;
+        assert_eq!(m.map, 0.0);
+        assert!(close(m.gm_map, MIN_GEO_MEAN.ln()));
+
+        // Tied scores go to the greater document name, so y comes first.
+        let m = &evaluation.queries[2].1;
+        assert!(close(m.recip_rank, 1.0));
+
+        let all = &evaluation.all;
+        assert_eq!(all.retrieved, 8);
+        assert!(close(all.map, ((1.0 + 2.0 / 4.0) / 3.0 + 0.0 + 1.0) / 3.0));
+        assert!(close(
+            all.gm_map,
+            (((0.5f64).ln() + MIN_GEO_MEAN.ln() + 0.0) / 3.0).exp()
+        ));
+
+        let mut out = Vec::new();
+        evaluation.write(&mut out, true).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("num_ret               \t1\t5\n"));
+        assert!(out.contains("runid                 \tall\ttest\n"));
+        assert!(out.contains("num_q                 \tall\t3\n"));
+        assert!(out.contains("iprec_at_recall_0.40  \t1\t0.5000\n"));
+        assert!(out.contains("P_5                   \t1\t0.4000\n"));
+        assert!(out.contains("ndcg_cut_1000         \tall\t"));
+    }
+}
@@ -0,0 +1,9 @@
+pub mod bm25f;
+pub mod evaluation;
+pub mod options;
+pub mod proximity;
+pub mod query;
+pub mod scorer;
+pub mod searcher;
+pub mod synonyms;
+pub mod topk;
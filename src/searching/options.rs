@@ -0,0 +1,349 @@
+use crate::indexing::index::DEFAULT_EXPANSIONS;
+use crate::parsing::field::Field;
+use crate::searching::bm25f::Bm25f;
+use crate::searching::scorer::{
+    Bm25, Bm25Plus, Dirichlet, Idf, InL2, JelinekMercer, Pl2, Scorer, TfIdf,
+};
+
+// Names of the tunable ranking parameters, as accepted by
+// `SearchOptions::set` and the `search` program's flags. BM25F's are set per
+// field, named after it.
+pub const PARAMETERS: [&str; 18] = [
+    "k1",
+    "b",
+    "delta",
+    "mu",
+    "lambda",
+    "c",
+    "weight-hl",
+    "weight-dd",
+    "weight-so",
+    "weight-in",
+    "weight-dateline",
+    "weight-text",
+    "b-hl",
+    "b-dd",
+    "b-so",
+    "b-in",
+    "b-dateline",
+    "b-text",
+];
+
+// Everything that decides how documents are ranked. Each parameter only
+// applies to the models which use it, and defaults to the value used before
+// they were tunable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchOptions {
+    // One of `scorer::SCORERS`.
+    pub scorer: String,
+    // BM25, BM25+ and BM25F.
+    pub k1: f32,
+    pub b: f32,
+    // BM25+.
+    pub delta: f32,
+    // Dirichlet smoothing.
+    pub mu: f32,
+    // Jelinek-Mercer smoothing.
+    pub lambda: f32,
+    // Normalisation 2, for PL2 and InL2.
+    pub c: f32,
+    pub proximity: bool,
+    // If set, BM25F overrides the scorer.
+    pub bm25f: bool,
+    // BM25F, per field in `Field::ALL` order.
+    pub field_weights: [f32; Field::COUNT],
+    pub field_b: [f32; Field::COUNT],
+    // Number of documents to return, if limited.
+    pub limit: Option<usize>,
+    // Number of terms a wildcard pattern expands to, at most.
+    pub expansions: usize,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        let bm25 = Bm25::default();
+        let plus = Bm25Plus::default();
+        let bm25f = Bm25f::default();
+
+        SearchOptions {
+            scorer: "bm25".to_string(),
+            k1: bm25.k1,
+            b: bm25.b,
+            delta: plus.delta,
+            mu: Dirichlet::default().mu,
+            lambda: JelinekMercer::default().lambda,
+            c: Pl2::default().c,
+            proximity: false,
+            bm25f: false,
+            field_weights: bm25f.weights,
+            field_b: bm25f.b,
+            limit: None,
+            expansions: DEFAULT_EXPANSIONS,
+        }
+    }
+}
+
+impl SearchOptions {
+    // Sets the parameter called `name`, returning false if there's no such
+    // parameter.
+    pub fn set(&mut self, name: &str, value: f32) -> bool {
+        let field = |prefix| {
+            name.strip_prefix(prefix)
+                .and_then(Field::from_name)
+                .map(Field::index)
+        };
+
+        let parameter = match name {
+            "k1" => &mut self.k1,
+            "b" => &mut self.b,
+            "delta" => &mut self.delta,
+            "mu" => &mut self.mu,
+            "lambda" => &mut self.lambda,
+            "c" => &mut self.c,
+            _ => match (field("weight-"), field("b-")) {
+                (Some(i), _) => &mut self.field_weights[i],
+                (_, Some(i)) => &mut self.field_b[i],
+                _ => return false,
+            },
+        };
+
+        *parameter = value;
+        true
+    }
+
+    // The BM25F parameters, if BM25F is on.
+    pub fn build_bm25f(&self) -> Option<Bm25f> {
+        if !self.bm25f {
+            return None;
+        }
+
+        Some(Bm25f {
+            k1: self.k1,
+            weights: self.field_weights,
+            b: self.field_b,
+        })
+    }
+
+    // Builds the scorer named by `scorer` with these parameters.
+    pub fn build_scorer(&self) -> std::io::Result<Box<dyn Scorer>> {
+        let bm25 = |idf| Bm25 {
+            k1: self.k1,
+            b: self.b,
+            idf,
+        };
+
+        Ok(match self.scorer.as_str() {
+            "bm25" => Box::new(bm25(Idf::Plain)),
+            "bm25-classic" => Box::new(bm25(Idf::Classic)),
+            "bm25-lucene" => Box::new(bm25(Idf::Lucene)),
+            "bm25+" => Box::new(Bm25Plus {
+                k1: self.k1,
+                b: self.b,
+                delta: self.delta,
+            }),
+            "dirichlet" => Box::new(Dirichlet { mu: self.mu }),
+            "jelinek-mercer" => Box::new(JelinekMercer {
+                lambda: self.lambda,
+            }),
+            "pl2" => Box::new(Pl2 { c: self.c }),
+            "inl2" => Box::new(InL2 { c: self.c }),
+            "tfidf" => Box::new(TfIdf),
+            name => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("unknown scorer {}", name),
+                ))
+            }
+        })
+    }
+}
+
+// A grid of parameter settings to sweep over. Every combination of the
+// values of each axis is a setting.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Grid {
+    axes: Vec<(String, Vec<f32>)>,
+}
+
+impl Grid {
+    pub fn new() -> Grid {
+        Grid::default()
+    }
+
+    // Adds an axis given as `name=v1,v2,..` or `name=start:end:step`, where
+    // `end` is included.
+    pub fn axis(&mut self, spec: &str) -> std::io::Result<()> {
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid parameter grid {}", spec),
+            )
+        };
+
+        let (name, values) = spec.split_once('=').ok_or_else(invalid)?;
+        if !PARAMETERS.contains(&name) {
+            return Err(invalid());
+        }
+
+        let numbers = |separator| {
+            values
+                .split(separator)
+                .map(|v| v.trim().parse::<f32>().map_err(|_| invalid()))
+                .collect::<std::io::Result<Vec<_>>>()
+        };
+
+        let values = if values.contains(':') {
+            match numbers(':')?.as_slice() {
+                &[start, end, step] if step > 0.0 && end >= start => {
+                    // Counted up front, so rounding errors in the sum can't
+                    // add or lose a step.
+                    let steps = ((end - start) / step + 1e-3).floor() as usize;
+                    (0..=steps)
+                        .map(|i| ((start + i as f32 * step) * 1e6).round() / 1e6)
+                        .collect()
+                }
+                _ => return Err(invalid()),
+            }
+        } else {
+            numbers(',')?
+        };
+
+        self.axes.push((name.to_string(), values));
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.axes.is_empty()
+    }
+
+    // Returns every setting of the grid applied to `base`, alongside a label
+    // naming the setting, like `k1=0.9_b=0.4`.
+    pub fn settings(&self, base: &SearchOptions) -> Vec<(String, SearchOptions)> {
+        let mut settings = vec![(String::new(), base.clone())];
+
+        for (name, values) in &self.axes {
+            settings = settings
+                .into_iter()
+                .flat_map(|(label, options)| {
+                    values.iter().map(move |&value| {
+                        let mut options = options.clone();
+                        options.set(name, value);
+
+                        let label = if label.is_empty() {
+                            format!("{}={}", name, value)
+                        } else {
+                            format!("{}_{}={}", label, name, value)
+                        };
+                        (label, options)
+                    })
+                })
+                .collect();
+        }
+
+        settings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::searching::scorer::{Collection, TermStats, SCORERS};
+
+    #[test]
+    fn scorers() {
+        let collection = Collection {
+            documents: 100,
+            avg_length: 10.0,
+            total_length: 1000,
+        };
+        let term = TermStats {
+            documents: 5,
+            frequency: 10,
+        };
+
+        let mut options = SearchOptions::default();
+        for name in SCORERS {
+            options.scorer = name.to_string();
+            assert!(options.build_scorer().is_ok(), "{}", name);
+        }
+
+        // The defaults are the scorers' own.
+        options.scorer = "bm25".to_string();
+        let score = |options: &SearchOptions| {
+            options
+                .build_scorer()
+                .unwrap()
+                .score(&collection, &term, 2, 20)
+        };
+        assert_eq!(
+            score(&options),
+            Bm25::default().score(&collection, &term, 2, 20)
+        );
+
+        assert!(options.set("b", 0.0));
+        assert!(!options.set("bogus", 1.0));
+        assert!(score(&options) > Bm25::default().score(&collection, &term, 2, 20));
+
+        options.scorer = "bm26".to_string();
+        assert!(options.build_scorer().is_err());
+    }
+
+    #[test]
+    fn fields() {
+        let mut options = SearchOptions::default();
+        assert_eq!(options.build_bm25f(), None);
+
+        options.bm25f = true;
+        assert_eq!(options.build_bm25f(), Some(Bm25f::default()));
+
+        assert!(options.set("k1", 1.2));
+        assert!(options.set("weight-hl", 5.0));
+        assert!(options.set("b-dateline", 0.1));
+        assert!(!options.set("weight-docno", 1.0));
+        assert!(!options.set("b-", 1.0));
+        assert_eq!(
+            options.build_bm25f(),
+            Some(Bm25f {
+                k1: 1.2,
+                ..Bm25f::default()
+                    .weight(Field::Headline, 5.0)
+                    .b(Field::Dateline, 0.1)
+            })
+        );
+
+        // Every parameter can be set.
+        for name in PARAMETERS {
+            assert!(options.set(name, 1.0), "{}", name);
+        }
+    }
+
+    #[test]
+    fn grid() {
+        let mut grid = Grid::new();
+        grid.axis("k1=0.5,1.2").unwrap();
+        grid.axis("b=0.1:0.4:0.1").unwrap();
+        assert!(grid.axis("x=1").is_err());
+        assert!(grid.axis("b=1:0:0.1").is_err());
+        assert!(grid.axis("b=1,a").is_err());
+
+        let settings = grid.settings(&SearchOptions::default());
+        assert_eq!(settings.len(), 8);
+        assert_eq!(settings[0].0, "k1=0.5_b=0.1");
+        assert_eq!(settings[7].0, "k1=1.2_b=0.4");
+        assert_eq!(settings[7].1.k1, 1.2);
+        assert_eq!(settings[7].1.b, 0.4);
+        assert_eq!(settings[2].1.b, 0.3);
+
+        let mut grid = Grid::new();
+        grid.axis("weight-hl=1,2,3").unwrap();
+        let settings = grid.settings(&SearchOptions::default());
+        assert_eq!(settings.len(), 3);
+        assert_eq!(settings[2].0, "weight-hl=3");
+        assert_eq!(settings[2].1.field_weights[Field::Headline.index()], 3.0);
+
+        assert_eq!(
+            Grid::new().settings(&SearchOptions::default()),
+            vec![(String::new(), SearchOptions::default())]
+        );
+    }
+}
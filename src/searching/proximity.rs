@@ -0,0 +1,173 @@
+// Matching and scoring based on where terms occur within a document. Every
+// function here takes the sorted positions of each term within a single
+// document.
+
+// Terms further apart than this don't contribute to `term_proximity`.
+pub const PROXIMITY_DISTANCE: u64 = 5;
+
+// Counts the number of places where every term occurs within a window of
+// `width` positions, that is where the first and last of them are at most
+// `width` apart. If `ordered` is set the terms must also occur in the order
+// they are given.
+pub fn window_matches(lists: &[&[u64]], width: u64, ordered: bool) -> u64 {
+    if lists.is_empty() || lists.iter().any(|l| l.is_empty()) {
+        return 0;
+    }
+
+    if ordered {
+        ordered_matches(lists, width)
+    } else {
+        unordered_matches(lists, width)
+    }
+}
+
+// Each occurrence of the first term is matched with the earliest following
+// occurrence of every other term, in turn.
+fn ordered_matches(lists: &[&[u64]], width: u64) -> u64 {
+    let mut matches = 0;
+
+    'start: for &start in lists[0] {
+        let mut previous = start;
+
+        for list in &lists[1..] {
+            let next = match list.get(list.partition_point(|&p| p <= previous)) {
+                Some(&next) => next,
+                None => break 'start,
+            };
+
+            if next - start > width {
+                continue 'start;
+            }
+            previous = next;
+        }
+
+        matches += 1;
+    }
+
+    matches
+}
+
+// Sweeps over the positions of every term in document order, keeping the
+// shortest window which ends at the current position and still holds every
+// term. Each position which closes a narrow enough window is a match.
+fn unordered_matches(lists: &[&[u64]], width: u64) -> u64 {
+    let mut merged = lists
+        .iter()
+        .enumerate()
+        .flat_map(|(term, list)| list.iter().map(move |&p| (p, term)))
+        .collect::<Vec<_>>();
+    merged.sort_unstable();
+
+    let mut counts = vec![0; lists.len()];
+    let mut present = 0;
+    let mut left = 0;
+    let mut matches = 0;
+
+    for right in 0..merged.len() {
+        let term = merged[right].1;
+        counts[term] += 1;
+        if counts[term] == 1 {
+            present += 1;
+        }
+
+        // Drop terms from the left which occur again later in the window.
+        while counts[merged[left].1] > 1 {
+            counts[merged[left].1] -= 1;
+            left += 1;
+        }
+
+        if present == lists.len() && merged[right].0 - merged[left].0 <= width {
+            matches += 1;
+        }
+    }
+
+    matches
+}
+
+// Term proximity accumulators, as in BM25TP (Rasolofo and Savoy, "Term
+// Proximity Scoring for Keyword-Based Retrieval Systems", ECIR 2003). Every
+// pair of occurrences of two different terms at most `PROXIMITY_DISTANCE`
+// apart adds 1/d^2 to both terms, where d is the distance between them.
+pub fn term_proximity(lists: &[&[u64]]) -> Vec<f32> {
+    let mut merged = lists
+        .iter()
+        .enumerate()
+        .flat_map(|(term, list)| list.iter().map(move |&p| (p, term)))
+        .collect::<Vec<_>>();
+    merged.sort_unstable();
+
+    let mut acc = vec![0.0; lists.len()];
+
+    for (i, &(p, a)) in merged.iter().enumerate() {
+        for &(q, b) in merged[i + 1..].iter() {
+            let d = q - p;
+            if d > PROXIMITY_DISTANCE {
+                break;
+            }
+
+            if a != b {
+                let tpi = 1.0 / (d * d) as f32;
+                acc[a] += tpi;
+                acc[b] += tpi;
+            }
+        }
+    }
+
+    acc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::builder::IndexBuilder;
+    use crate::indexing::testing::{index, matched, ranked, TempDir};
+
+    #[test]
+    fn windows() {
+        let a: &[u64] = &[0, 10, 20];
+        let b: &[u64] = &[2, 8];
+        let c: &[u64] = &[3];
+
+        assert_eq!(window_matches(&[a, b], 2, false), 2);
+        assert_eq!(window_matches(&[a, b], 2, true), 1);
+        assert_eq!(window_matches(&[b, a], 2, true), 1);
+        assert_eq!(window_matches(&[a, b], 1, false), 0);
+        assert_eq!(window_matches(&[a, b, c], 3, false), 1);
+        assert_eq!(window_matches(&[a, c, b], 3, true), 0);
+        assert_eq!(window_matches(&[a, &[]], 100, false), 0);
+    }
+
+    #[test]
+    fn proximity() {
+        let acc = term_proximity(&[&[0, 20], &[2], &[40]]);
+        assert_eq!(acc, vec![0.25, 0.25, 0.0]);
+
+        let acc = term_proximity(&[&[0], &[1, 3]]);
+        assert_eq!(acc, vec![1.0 + 1.0 / 9.0, 1.0 + 1.0 / 9.0]);
+    }
+
+    #[test]
+    fn window_queries() {
+        let dir = TempDir::new("windows");
+        let mut index = index(
+            &dir,
+            IndexBuilder::new().positions(true),
+            &[
+                ("a", "the wall street journal said"),
+                ("b", "journal street wall"),
+                ("c", "wall street journal and the wall street journal"),
+                ("d", "a wall in the street"),
+            ],
+        );
+
+        assert_eq!(matched(&index, "wall NEAR/2 street"), vec!["a", "b", "c"]);
+        assert_eq!(matched(&index, "wall ONEAR/2 street"), vec!["a", "c"]);
+        assert_eq!(matched(&index, "street ONEAR/4 wall"), vec!["b", "c"]);
+
+        // Proximity only reorders documents which match anyway.
+        let plain = matched(&index, "wall street said");
+        index.set_proximity(true);
+        assert_eq!(matched(&index, "wall street said"), plain);
+        assert_eq!(ranked(&index, "street said")[0], "a");
+    }
+}
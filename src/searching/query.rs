@@ -0,0 +1,1094 @@
+use crate::indexing::wildcard::is_pattern;
+use crate::parsing::analyzer::Analyzer;
+use crate::parsing::field::Field;
+use crate::searching::scorer::TermStats;
+
+// A parsed query. Anything between double quotes is a phrase, which only
+// matches documents holding its terms next to each other and in order.
+// Words joined by `NEAR/k` must all occur within k positions of each other,
+// in any order, and words joined by `ONEAR/k` must also occur in the order
+// given. The rest of the query is a bag of terms, as before.
+//
+// Prefixing a word or a phrase with the name of a field and a colon only
+// matches it within that field, given an index built with fields.
+//
+//   "wall street journal" stocks
+//   merger NEAR/5 acquisition
+//   insider ONEAR/3 trading ONEAR/3 ruling
+//   hl:merger dateline:tokyo hl:"insider trading"
+//
+// A word holding `*` (any run of characters) or `?` (any one character) is a
+// pattern, which matches any term it fits. Each pattern is scored as a
+// single term, occurring wherever any of the terms it expands to does.
+//
+//   merg* takeover? hl:*bank*
+//
+// A word ending in `~1` or `~2` (or just `~`, for 2) is fuzzy, and also
+// matches terms within that many edits of it. Each of those is scored as a
+// term of its own, weighted down the more edits away it is, but as if it were
+// as common as the most common of them, so a rare misspelling doesn't
+// outscore the word it's a misspelling of.
+//
+//   mitsubishi~1 hl:takover~
+//
+// A query can also require or exclude words, phrases and windows, with `AND`,
+// `OR` and `NOT` (which must be upper case), parentheses for grouping, and
+// `+` or `-` in front of something it must or mustn't hold,
+//
+//   (merger OR acquisition) AND NOT hl:rumor
+//   +"insider trading" ruling -appeal
+//
+// `AND` binds tighter than `OR`, and things written next to each other
+// without an operator are optional: a document needs to hold at least one of
+// them, unless something else is required with `+`. Such a query only
+// matches the documents its `filter` does, and they are ranked by everything
+// in the query which isn't excluded.
+//
+// Words and phrases are also matched by their synonyms, given a thesaurus,
+// unless the query starts with `=`.
+//
+//   =usa trade
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Query {
+    pub terms: Vec<String>,
+    pub phrases: Vec<Vec<String>>,
+    pub windows: Vec<Window>,
+    pub patterns: Vec<String>,
+    pub fuzzy: Vec<Fuzzy>,
+    // The weight of the score of each list of postings (see `boost`), where
+    // it isn't 1, and the statistics to score each of `terms` with in place
+    // of its own, where set. Only set once fuzzy terms and synonyms have
+    // been expanded.
+    pub boosts: Vec<f32>,
+    pub stats: Vec<Option<TermStats>>,
+    // Only present if the query uses boolean operators.
+    pub filter: Option<Expr>,
+    // Leaves out synonyms.
+    pub verbatim: bool,
+}
+
+// A boolean expression over words, phrases and windows, which matches a set
+// of documents.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Term(String),
+    Phrase(Vec<String>),
+    Window(Window),
+    Pattern(String),
+    Fuzzy(Fuzzy),
+    // Matches the documents every expression matches. With no expressions,
+    // matches every document.
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+}
+
+// Terms which must occur close together. Matches wherever the first and last
+// of the terms are at most `width` positions apart.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Window {
+    pub terms: Vec<String>,
+    pub width: u64,
+    pub ordered: bool,
+}
+
+// Analyzes a single word of the query, which may be scoped to a field.
+fn word_terms(word: &str, analyzer: &Analyzer) -> Vec<String> {
+    match Field::split(word) {
+        Some((field, rest)) => analyzer
+            .terms(rest)
+            .map(|term| field.qualify(&term))
+            .collect(),
+        None => analyzer.terms(word).collect(),
+    }
+}
+
+// A term which also matches the terms within `distance` edits of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fuzzy {
+    pub term: String,
+    pub distance: u32,
+}
+
+// Edits past this many match too much of the dictionary to be useful.
+const MAX_DISTANCE: u32 = 2;
+
+// Analyzes a single word ending in `~k` into a fuzzy term, which may be
+// scoped to a field. The word has to analyze to a single term.
+fn word_fuzzy(word: &str, analyzer: &Analyzer) -> Option<Fuzzy> {
+    let (word, distance) = word.rsplit_once('~')?;
+    let distance = match distance {
+        "" => MAX_DISTANCE,
+        _ => distance.parse::<u32>().ok()?.min(MAX_DISTANCE),
+    };
+
+    match word_terms(word, analyzer).as_slice() {
+        [term] => Some(Fuzzy {
+            term: term.clone(),
+            distance,
+        }),
+        _ => None,
+    }
+}
+
+// Analyzes a single word holding wildcards into a pattern, lowercased like a
+// term, which may be scoped to a field. Words without wildcards, or with
+// anything but letters, digits and apostrophes around them, aren't patterns.
+fn word_pattern(word: &str) -> Option<String> {
+    let (field, rest) = match Field::split(word) {
+        Some((field, rest)) => (Some(field), rest),
+        None => (None, word),
+    };
+
+    let literal = |c: char| c.is_alphanumeric() || c == '_' || c == '\'';
+    if !is_pattern(rest)
+        || !rest.chars().any(literal)
+        || !rest.chars().all(|c| literal(c) || c == '*' || c == '?')
+    {
+        return None;
+    }
+
+    let pattern = rest.to_ascii_lowercase();
+    Some(match field {
+        Some(field) => field.qualify(&pattern),
+        None => pattern,
+    })
+}
+
+// Parses a `NEAR/k` or `ONEAR/k` operator into its width and whether it's
+// ordered.
+fn operator(word: &str) -> Option<(u64, bool)> {
+    let (name, width) = word.split_once('/')?;
+    let width = width.parse().ok()?;
+
+    match name {
+        "NEAR" => Some((width, false)),
+        "ONEAR" => Some((width, true)),
+        _ => None,
+    }
+}
+
+impl Query {
+    pub fn parse(query: &str, analyzer: &Analyzer) -> Query {
+        if let Some(rest) = query.trim_start().strip_prefix('=') {
+            return Query {
+                verbatim: true,
+                ..Query::parse(rest, analyzer)
+            };
+        }
+
+        let tokens = tokenize(query);
+        if tokens.iter().any(Token::is_boolean) {
+            return Parser::new(tokens, analyzer).parse();
+        }
+
+        let mut parsed = Query::default();
+
+        // The field the next phrase is scoped to, if any.
+        let mut scope = None;
+
+        // Every other piece between quotes is a phrase. A missing closing
+        // quote ends the phrase at the end of the query.
+        for (i, piece) in query.split('"').enumerate() {
+            if i % 2 == 0 {
+                // A field name left dangling before a phrase scopes it.
+                scope = piece
+                    .split_whitespace()
+                    .last()
+                    .and_then(|w| w.strip_suffix(':'))
+                    .and_then(Field::from_name);
+                parsed.parse_words(piece, analyzer);
+                continue;
+            }
+
+            let terms = match scope {
+                Some(field) => analyzer
+                    .terms(piece)
+                    .map(|term| field.qualify(&term))
+                    .collect::<Vec<_>>(),
+                None => piece
+                    .split_whitespace()
+                    .flat_map(|word| word_terms(word, analyzer))
+                    .collect::<Vec<_>>(),
+            };
+            if terms.len() < 2 {
+                parsed.terms.extend(terms);
+            } else {
+                parsed.phrases.push(terms);
+            }
+        }
+
+        parsed
+    }
+
+    // The weight of the score of the `i`th list of postings, given they're
+    // in the order `terms`, phrases, windows and then patterns.
+    pub fn boost(&self, i: usize) -> f32 {
+        self.boosts.get(i).copied().unwrap_or(1.0)
+    }
+
+    // The statistics to score the `i`th list of postings with, if not its
+    // own.
+    pub fn stats(&self, i: usize) -> Option<TermStats> {
+        self.stats.get(i).copied().flatten()
+    }
+
+    // Parses a piece of the query outside quotes, which may join words
+    // with proximity operators.
+    fn parse_words(&mut self, piece: &str, analyzer: &Analyzer) {
+        let mut loose = Vec::new();
+        let mut windows: Vec<(Vec<&str>, u64, bool)> = Vec::new();
+
+        // The previous word, and whether it's part of the last window.
+        let mut previous: Option<(&str, bool)> = None;
+        let mut joining = None;
+
+        for word in piece.split_whitespace() {
+            if let Some(op) = operator(word) {
+                // An operator with nothing before it is ignored.
+                joining = previous.map(|_| op);
+                continue;
+            }
+
+            match (joining.take(), previous) {
+                (Some((width, ordered)), Some((before, in_window))) => {
+                    match windows.last_mut() {
+                        Some(w) if in_window && w.1 == width && w.2 == ordered => w.0.push(word),
+                        _ => {
+                            if !in_window {
+                                loose.pop();
+                            }
+                            windows.push((vec![before, word], width, ordered));
+                        }
+                    }
+                    previous = Some((word, true));
+                }
+                _ => {
+                    loose.push(word);
+                    previous = Some((word, false));
+                }
+            }
+        }
+
+        for word in loose {
+            if let Some(pattern) = word_pattern(word) {
+                self.patterns.push(pattern);
+            } else if let Some(fuzzy) = word_fuzzy(word, analyzer) {
+                self.fuzzy.push(fuzzy);
+            } else {
+                self.terms.extend(word_terms(word, analyzer));
+            }
+        }
+
+        for (words, width, ordered) in windows {
+            let terms = words
+                .iter()
+                .flat_map(|w| word_terms(w, analyzer))
+                .collect::<Vec<_>>();
+
+            if terms.len() < 2 {
+                self.terms.extend(terms);
+            } else {
+                self.windows.push(Window {
+                    terms,
+                    width,
+                    ordered,
+                });
+            }
+        }
+    }
+}
+
+// Rewrites `query` with the corrections `suggest` gives for the terms of its
+// plain words, leaving everything else (operators, quotes, patterns and fuzzy
+// words) as written. `suggest` is given each term as analyzed, scoped to the
+// word's field if it has one. Returns None if nothing was corrected.
+pub fn correct<F>(
+    query: &str,
+    analyzer: &Analyzer,
+    mut suggest: F,
+) -> std::io::Result<Option<String>>
+where
+    F: FnMut(&str) -> std::io::Result<Option<String>>,
+{
+    // Stuck to the front or back of a word without being part of it.
+    let syntax: &[char] = &['"', '(', ')', '+', '-', '='];
+
+    let mut corrected = String::with_capacity(query.len());
+    let mut changed = false;
+
+    for piece in query.split_inclusive(char::is_whitespace) {
+        let word = piece.trim_end();
+        let start = word.len() - word.trim_start_matches(syntax).len();
+        let core = word.trim_matches(syntax);
+        let end = start + core.len();
+
+        let (field, rest) = match Field::split(core) {
+            Some((field, rest)) => (Some(field), rest),
+            None => (None, core),
+        };
+
+        let plain = !matches!(core, "AND" | "OR" | "NOT")
+            && operator(core).is_none()
+            && !is_pattern(core)
+            && !core.contains('~');
+
+        let terms = analyzer.terms(rest).collect::<Vec<_>>();
+        let correction = match terms.as_slice() {
+            // Only words which are nothing but their term are corrected.
+            [term] if plain && *term == rest.to_ascii_lowercase() => {
+                let scoped = field.map_or_else(|| term.clone(), |f| f.qualify(term));
+                suggest(&scoped)?
+            }
+            _ => None,
+        };
+
+        match correction {
+            Some(correction) => {
+                let unscoped = Field::split(&correction).map_or(correction.as_str(), |(_, t)| t);
+                corrected.push_str(&piece[..end - rest.len()]);
+                corrected.push_str(unscoped);
+                corrected.push_str(&piece[end..]);
+                changed = true;
+            }
+            None => corrected.push_str(piece),
+        }
+    }
+
+    Ok(if changed { Some(corrected) } else { None })
+}
+
+// A piece of a query, when it's parsed as a boolean query.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token<'a> {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    Plus,
+    Minus,
+    Near(u64, bool),
+    Word(&'a str),
+    // The text between a pair of quotes, and the field before them if any.
+    Phrase(Option<Field>, &'a str),
+}
+
+impl Token<'_> {
+    // Parentheses alone don't make a query boolean, as they turn up in
+    // plain queries, like `trade (1990)`.
+    fn is_boolean(&self) -> bool {
+        matches!(
+            self,
+            Token::And | Token::Or | Token::Not | Token::Plus | Token::Minus
+        )
+    }
+}
+
+// Splits off a phrase from `text`, which starts just past its opening quote.
+// A missing closing quote ends the phrase at the end of the query.
+fn quoted(text: &str) -> (&str, &str) {
+    match text.split_once('"') {
+        Some((phrase, rest)) => (phrase, rest),
+        None => (text, ""),
+    }
+}
+
+fn tokenize(query: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = query.trim_start();
+
+    // Whether the next token starts a word, rather than being stuck to the
+    // end of the last one.
+    let mut word_start = true;
+
+    while let Some(c) = rest.chars().next() {
+        let next = &rest[c.len_utf8()..];
+        let starts_word = word_start;
+        word_start = c == '(';
+
+        match c {
+            '(' => {
+                tokens.push(Token::Open);
+                rest = next;
+            }
+            ')' => {
+                tokens.push(Token::Close);
+                rest = next;
+            }
+            '"' => {
+                let (phrase, after) = quoted(next);
+                tokens.push(Token::Phrase(None, phrase));
+                rest = after;
+            }
+            // Only an operator at the start of a word, when it's stuck to
+            // what follows it.
+            '+' | '-' if starts_word && next.starts_with(|c: char| !c.is_whitespace()) => {
+                word_start = true;
+                tokens.push(if c == '+' { Token::Plus } else { Token::Minus });
+                rest = next;
+            }
+            _ => {
+                let end = rest
+                    .find(|c: char| c.is_whitespace() || c == '(' || c == ')' || c == '"')
+                    .unwrap_or(rest.len());
+                let (word, after) = rest.split_at(end);
+                rest = after;
+
+                // A field name left dangling before a phrase scopes it.
+                let scope = word.strip_suffix(':').and_then(Field::from_name);
+                if let (Some(field), Some(after)) = (scope, rest.strip_prefix('"')) {
+                    let (phrase, after) = quoted(after);
+                    tokens.push(Token::Phrase(Some(field), phrase));
+                    rest = after;
+                } else {
+                    tokens.push(match word {
+                        "AND" => Token::And,
+                        "OR" => Token::Or,
+                        "NOT" => Token::Not,
+                        _ => match operator(word) {
+                            Some((width, ordered)) => Token::Near(width, ordered),
+                            None => Token::Word(word),
+                        },
+                    });
+                }
+            }
+        }
+
+        let trimmed = rest.trim_start();
+        word_start |= trimmed.len() < rest.len();
+        rest = trimmed;
+    }
+
+    tokens
+}
+
+// Whether a document must, mustn't or may hold a clause of a query.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Occur {
+    Should,
+    Must,
+    MustNot,
+}
+
+// Turns a clause into an expression which can be combined with others.
+fn operand((expr, occur): (Option<Expr>, Occur)) -> Option<Expr> {
+    match occur {
+        Occur::MustNot => expr.map(|e| Expr::Not(Box::new(e))),
+        _ => expr,
+    }
+}
+
+// Combines the expressions with `join`, unless there's only one.
+fn join(mut exprs: Vec<Expr>, join: fn(Vec<Expr>) -> Expr) -> Option<Expr> {
+    match exprs.len() {
+        0 => None,
+        1 => exprs.pop(),
+        _ => Some(join(exprs)),
+    }
+}
+
+// Parses a boolean query by recursive descent,
+//
+//   sequence := or*
+//   or       := and ("OR" and)*
+//   and      := unary ("AND" unary)*
+//   unary    := ("NOT" | "+" | "-") unary | primary
+//   primary  := "(" sequence ")" | phrase | word (NEAR/k word)*
+//
+// Anything out of place, like an operator with nothing after it or an
+// unmatched parenthesis, is skipped rather than rejected.
+struct Parser<'a, 'b> {
+    tokens: Vec<Token<'a>>,
+    next: usize,
+    // Number of parentheses open.
+    depth: usize,
+    // Whether the clause being parsed is excluded, so isn't scored.
+    negated: bool,
+    analyzer: &'b Analyzer,
+    // Everything which isn't excluded, to rank the matches by.
+    scored: Query,
+}
+
+impl<'a, 'b> Parser<'a, 'b> {
+    fn new(tokens: Vec<Token<'a>>, analyzer: &'b Analyzer) -> Self {
+        Parser {
+            tokens,
+            next: 0,
+            depth: 0,
+            negated: false,
+            analyzer,
+            scored: Query::default(),
+        }
+    }
+
+    fn parse(mut self) -> Query {
+        let filter = self.sequence();
+
+        Query {
+            filter: Some(filter.unwrap_or(Expr::Or(Vec::new()))),
+            ..self.scored
+        }
+    }
+
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.next).copied()
+    }
+
+    fn eat(&mut self, token: Token) -> bool {
+        let matched = self.peek() == Some(token);
+        if matched {
+            self.next += 1;
+        }
+        matched
+    }
+
+    fn sequence(&mut self) -> Option<Expr> {
+        let mut must = Vec::new();
+        let mut should = Vec::new();
+        let mut must_not = Vec::new();
+
+        while let Some(token) = self.peek() {
+            if token == Token::Close {
+                if self.depth > 0 {
+                    break;
+                }
+                self.next += 1;
+                continue;
+            }
+
+            match self.or() {
+                (Some(expr), Occur::Must) => must.push(expr),
+                (Some(expr), Occur::Should) => should.push(expr),
+                (Some(expr), Occur::MustNot) => must_not.push(Expr::Not(Box::new(expr))),
+                (None, _) => {}
+            }
+        }
+
+        if must.is_empty() && should.is_empty() && must_not.is_empty() {
+            return None;
+        }
+
+        // Optional clauses only matter without required ones, and then a
+        // document has to hold at least one of them.
+        let mut clauses = if must.is_empty() {
+            join(should, Expr::Or).into_iter().collect()
+        } else {
+            must
+        };
+        clauses.extend(must_not);
+
+        Some(join(clauses, Expr::And).unwrap_or(Expr::And(Vec::new())))
+    }
+
+    fn or(&mut self) -> (Option<Expr>, Occur) {
+        let first = self.and();
+        if self.peek() != Some(Token::Or) {
+            return first;
+        }
+
+        let mut operands = vec![first];
+        while self.eat(Token::Or) {
+            operands.push(self.and());
+        }
+
+        let exprs = operands.into_iter().filter_map(operand).collect();
+        (join(exprs, Expr::Or), Occur::Should)
+    }
+
+    fn and(&mut self) -> (Option<Expr>, Occur) {
+        let first = self.unary();
+        if self.peek() != Some(Token::And) {
+            return first;
+        }
+
+        let mut operands = vec![first];
+        while self.eat(Token::And) {
+            operands.push(self.unary());
+        }
+
+        let exprs = operands.into_iter().filter_map(operand).collect();
+        (join(exprs, Expr::And), Occur::Should)
+    }
+
+    fn unary(&mut self) -> (Option<Expr>, Occur) {
+        if self.eat(Token::Not) || self.eat(Token::Minus) {
+            self.negated = !self.negated;
+            let (expr, occur) = self.unary();
+            self.negated = !self.negated;
+
+            let occur = match occur {
+                Occur::MustNot => Occur::Should,
+                _ => Occur::MustNot,
+            };
+            return (expr, occur);
+        }
+
+        if self.eat(Token::Plus) {
+            return match self.unary() {
+                (expr, Occur::MustNot) => (expr, Occur::MustNot),
+                (expr, _) => (expr, Occur::Must),
+            };
+        }
+
+        (self.primary(), Occur::Should)
+    }
+
+    fn primary(&mut self) -> Option<Expr> {
+        let token = self.peek()?;
+        if token == Token::Close {
+            return None;
+        }
+        self.next += 1;
+
+        match token {
+            Token::Open => {
+                self.depth += 1;
+                let expr = self.sequence();
+                self.depth -= 1;
+                self.eat(Token::Close);
+                expr
+            }
+            Token::Phrase(scope, text) => {
+                let terms = match scope {
+                    Some(field) => self
+                        .analyzer
+                        .terms(text)
+                        .map(|term| field.qualify(&term))
+                        .collect::<Vec<_>>(),
+                    None => text
+                        .split_whitespace()
+                        .flat_map(|word| word_terms(word, self.analyzer))
+                        .collect::<Vec<_>>(),
+                };
+
+                if terms.len() < 2 {
+                    self.terms(terms)
+                } else {
+                    if !self.negated {
+                        self.scored.phrases.push(terms.clone());
+                    }
+                    Some(Expr::Phrase(terms))
+                }
+            }
+            Token::Word(word) => self.words(word),
+            // An operator out of place.
+            _ => None,
+        }
+    }
+
+    // Parses a word and any words joined to it by proximity operators.
+    fn words(&mut self, first: &str) -> Option<Expr> {
+        let mut windows: Vec<(Vec<&str>, u64, bool)> = Vec::new();
+        let mut previous = first;
+
+        while let (Some(Token::Near(width, ordered)), Some(Token::Word(word))) =
+            (self.peek(), self.tokens.get(self.next + 1).copied())
+        {
+            self.next += 2;
+
+            match windows.last_mut() {
+                Some(w) if w.1 == width && w.2 == ordered => w.0.push(word),
+                _ => windows.push((vec![previous, word], width, ordered)),
+            }
+            previous = word;
+        }
+
+        if windows.is_empty() {
+            if let Some(pattern) = word_pattern(first) {
+                if !self.negated {
+                    self.scored.patterns.push(pattern.clone());
+                }
+                return Some(Expr::Pattern(pattern));
+            }
+            if let Some(fuzzy) = word_fuzzy(first, self.analyzer) {
+                if !self.negated {
+                    self.scored.fuzzy.push(fuzzy.clone());
+                }
+                return Some(Expr::Fuzzy(fuzzy));
+            }
+            return self.terms(word_terms(first, self.analyzer));
+        }
+
+        let mut exprs = Vec::new();
+        for (words, width, ordered) in windows {
+            let terms = words
+                .iter()
+                .flat_map(|w| word_terms(w, self.analyzer))
+                .collect::<Vec<_>>();
+
+            if terms.len() < 2 {
+                exprs.extend(self.terms(terms));
+            } else {
+                let window = Window {
+                    terms,
+                    width,
+                    ordered,
+                };
+                if !self.negated {
+                    self.scored.windows.push(window.clone());
+                }
+                exprs.push(Expr::Window(window));
+            }
+        }
+
+        join(exprs, Expr::And)
+    }
+
+    // A word can analyze to several terms (or none), all of which have to
+    // match.
+    fn terms(&mut self, terms: Vec<String>) -> Option<Expr> {
+        if !self.negated {
+            self.scored.terms.extend(terms.iter().cloned());
+        }
+
+        join(terms.into_iter().map(Expr::Term).collect(), Expr::And)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::builder::IndexBuilder;
+    use crate::indexing::deletes::delete_documents;
+    use crate::indexing::index::DiskIndex;
+    use crate::indexing::testing::{index, matched, ranked, TempDir};
+
+    #[test]
+    fn parse() {
+        let analyzer = Analyzer::new();
+        let query = Query::parse(
+            r#"Stocks "Wall Street Journal" fell "dow" "new york"#,
+            &analyzer,
+        );
+
+        assert_eq!(query.terms, vec!["stocks", "fell", "dow"]);
+        assert_eq!(
+            query.phrases,
+            vec![vec!["wall", "street", "journal"], vec!["new", "york"]]
+        );
+
+        let query = Query::parse("no phrases here", &analyzer);
+        assert_eq!(query.terms.len(), 3);
+        assert!(query.phrases.is_empty());
+        assert!(!query.verbatim);
+
+        let query = Query::parse(r#" ="new york" OR usa"#, &analyzer);
+        assert!(query.verbatim);
+        assert_eq!(query.terms, vec!["usa"]);
+    }
+
+    #[test]
+    fn windows() {
+        let analyzer = Analyzer::new();
+        let query = Query::parse(
+            "NEAR/2 stocks merger NEAR/5 Acquisition NEAR/5 bid ONEAR/1 deal fell near/3 NEAR/x",
+            &analyzer,
+        );
+
+        assert_eq!(
+            query.terms,
+            vec!["stocks", "fell", "near", "3", "near", "x"]
+        );
+        assert_eq!(
+            query.windows,
+            vec![
+                Window {
+                    terms: vec!["merger", "acquisition", "bid"]
+                        .into_iter()
+                        .map(String::from)
+                        .collect(),
+                    width: 5,
+                    ordered: false,
+                },
+                Window {
+                    terms: vec!["bid".to_string(), "deal".to_string()],
+                    width: 1,
+                    ordered: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn fields() {
+        let analyzer = Analyzer::new();
+        let query = Query::parse(
+            r#"HL:Merger dateline:tokyo nope:x hl:"insider trading" "so:wall so:street" hl:bid NEAR/2 deal"#,
+            &analyzer,
+        );
+
+        assert_eq!(
+            query.terms,
+            vec!["hl:merger", "dateline:tokyo", "nope", "x"]
+        );
+        assert_eq!(
+            query.phrases,
+            vec![
+                vec!["hl:insider", "hl:trading"],
+                vec!["so:wall", "so:street"]
+            ]
+        );
+        assert_eq!(query.windows[0].terms, vec!["hl:bid", "deal"]);
+    }
+
+    #[test]
+    fn patterns() {
+        let analyzer = Analyzer::new();
+        let query = Query::parse("Merg* take?ver HL:*Bank* * wall-st* stocks", &analyzer);
+
+        assert_eq!(query.patterns, vec!["merg*", "take?ver", "hl:*bank*"]);
+        assert_eq!(query.terms, vec!["wall", "st", "stocks"]);
+    }
+
+    #[test]
+    fn corrections() {
+        let analyzer = Analyzer::new();
+        let suggest = |term: &str| {
+            Ok(match term {
+                "stok" => Some("stock".to_string()),
+                "hl:tradng" => Some("hl:trading".to_string()),
+                "wal" => Some("wall".to_string()),
+                _ => None,
+            })
+        };
+        let correct = |query| correct(query, &analyzer, suggest).unwrap();
+
+        assert_eq!(
+            correct(r#"(Stok OR bonds) AND NOT HL:tradng "wal street" stok*"#),
+            Some(r#"(stock OR bonds) AND NOT HL:trading "wall street" stok*"#.to_string())
+        );
+        assert_eq!(correct("+stok~1 -wal's"), None);
+        assert_eq!(correct("stocks"), None);
+        assert_eq!(correct("=stok"), Some("=stock".to_string()));
+    }
+
+    #[test]
+    fn fuzzy() {
+        let analyzer = Analyzer::new();
+        let fuzzy = |term: &str, distance| Fuzzy {
+            term: term.to_string(),
+            distance,
+        };
+
+        let query = Query::parse("Mitsubishi~1 HL:takover~ bid~9 new~york ~1", &analyzer);
+        assert_eq!(
+            query.fuzzy,
+            vec![
+                fuzzy("mitsubishi", 1),
+                fuzzy("hl:takover", 2),
+                fuzzy("bid", 2)
+            ]
+        );
+        assert_eq!(query.terms, vec!["new", "york", "1"]);
+
+        let query = Query::parse("mitsubishi~1 AND NOT motors", &analyzer);
+        assert_eq!(
+            query.filter,
+            Some(Expr::And(vec![
+                Expr::Fuzzy(fuzzy("mitsubishi", 1)),
+                Expr::Not(Box::new(Expr::Term("motors".to_string()))),
+            ]))
+        );
+    }
+
+    #[test]
+    fn boolean() {
+        let analyzer = Analyzer::new();
+        let term = |t: &str| Expr::Term(t.to_string());
+        let not = |e: Expr| Expr::Not(Box::new(e));
+
+        // Plain queries have no filter, even with parentheses or hyphens.
+        for plain in ["wall street", "u.s.-japan trade (1990)", "anti-dumping"] {
+            let query = Query::parse(plain, &analyzer);
+            assert_eq!(query.filter, None, "{}", plain);
+            assert_eq!(query.terms, analyzer.terms(plain).collect::<Vec<_>>());
+        }
+        assert_eq!(Query::parse("\"new york\"-times", &analyzer).filter, None);
+
+        let query = Query::parse("(merger OR acquisition) AND NOT hl:rumor", &analyzer);
+        assert_eq!(
+            query.filter,
+            Some(Expr::And(vec![
+                Expr::Or(vec![term("merger"), term("acquisition")]),
+                not(term("hl:rumor")),
+            ]))
+        );
+        assert_eq!(query.terms, vec!["merger", "acquisition"]);
+
+        // Optional words don't constrain the matches once something is
+        // required, but are still scored.
+        let query = Query::parse(r#"+"insider trading" ruling -appeal"#, &analyzer);
+        assert_eq!(
+            query.filter,
+            Some(Expr::And(vec![
+                Expr::Phrase(vec!["insider".to_string(), "trading".to_string()]),
+                not(term("appeal")),
+            ]))
+        );
+        assert_eq!(query.terms, vec!["ruling"]);
+        assert_eq!(query.phrases.len(), 1);
+
+        // AND binds tighter than OR, and juxtaposed clauses are optional.
+        let query = Query::parse("stocks OR bonds tokyo AND market", &analyzer);
+        assert_eq!(
+            query.filter,
+            Some(Expr::Or(vec![
+                Expr::Or(vec![term("stocks"), term("bonds")]),
+                Expr::And(vec![term("tokyo"), term("market")]),
+            ]))
+        );
+
+        let query = Query::parse("-stocks", &analyzer);
+        assert_eq!(query.filter, Some(not(term("stocks"))));
+        assert!(query.terms.is_empty());
+
+        assert_eq!(
+            Query::parse("NOT NOT stocks", &analyzer).filter,
+            Some(term("stocks"))
+        );
+
+        // Anything out of place is skipped.
+        let query = Query::parse("stocks AND ) ( OR", &analyzer);
+        assert_eq!(query.filter, Some(term("stocks")));
+
+        let query = Query::parse("merg* AND NOT hl:rum?r", &analyzer);
+        assert_eq!(
+            query.filter,
+            Some(Expr::And(vec![
+                Expr::Pattern("merg*".to_string()),
+                not(Expr::Pattern("hl:rum?r".to_string())),
+            ]))
+        );
+        assert_eq!(query.patterns, vec!["merg*"]);
+
+        let query = Query::parse("merger NEAR/5 bid -rumor", &analyzer);
+        assert_eq!(query.windows.len(), 1);
+        assert!(matches!(
+            query.filter,
+            Some(Expr::And(ref e)) if matches!(e[0], Expr::Window(_))
+        ));
+    }
+
+    #[test]
+    fn phrase_queries() {
+        let root = TempDir::new("phrases");
+        let docs = [
+            ("a", "the wall street journal said"),
+            ("b", "journal street wall"),
+            ("c", "wall street journal and the wall street journal"),
+            ("d", "a wall in the street"),
+        ];
+
+        // Both in memory and through runs.
+        for budget in [usize::MAX, 0] {
+            let builder = IndexBuilder::new()
+                .positions(true)
+                .memory_budget(budget)
+                .temp_dir(root.join("runs"));
+            let index = index(root.join(budget.to_string()), builder, &docs);
+            assert!(index.has_positions());
+
+            let (postings, positions) = index.positions("wall").unwrap();
+            assert_eq!(postings.len(), 4);
+            assert_eq!(positions, vec![1, 2, 0, 5, 1]);
+
+            let phrase = ["wall", "street", "journal"].map(String::from);
+            let matches = index.phrase(&phrase).unwrap();
+            assert_eq!(
+                matches
+                    .iter()
+                    .map(|p| (p.document, p.frequency))
+                    .collect::<Vec<_>>(),
+                vec![(0, 1), (2, 2)]
+            );
+            assert_eq!(ranked(&index, "\"wall street journal\""), vec!["c", "a"]);
+        }
+
+        // Phrases can't be matched without positions.
+        let index = index(
+            root.join("plain"),
+            IndexBuilder::new(),
+            &[("a", "wall street"), ("b", "main street")],
+        );
+        assert!(index.search("\"wall street\"").is_err());
+        assert_eq!(index.search("wall street").unwrap().count(), 1);
+    }
+
+    #[test]
+    fn boolean_queries() {
+        let dir = TempDir::new("boolean");
+        let docs = [
+            ("a", "wall street journal"),
+            ("b", "wall street merger talks"),
+            ("c", "tokyo stock merger"),
+            ("d", "journal of commerce"),
+            ("e", "wall street tokyo"),
+        ];
+        index(&dir, IndexBuilder::new().positions(true), &docs);
+        delete_documents(&dir, ["e"]).unwrap();
+        let index = DiskIndex::from_disk(&dir).unwrap();
+
+        assert_eq!(matched(&index, "wall AND merger"), vec!["b"]);
+        assert_eq!(matched(&index, "wall OR tokyo"), vec!["a", "b", "c"]);
+        assert_eq!(matched(&index, "wall -merger"), vec!["a"]);
+        assert_eq!(matched(&index, "+journal wall"), vec!["a", "d"]);
+        assert_eq!(
+            matched(&index, "(wall OR commerce) AND journal"),
+            vec!["a", "d"]
+        );
+        assert_eq!(
+            matched(&index, "\"wall street\" AND NOT journal"),
+            vec!["b"]
+        );
+        assert_eq!(
+            matched(&index, "merger AND NOT wall NEAR/3 talks"),
+            vec!["c"]
+        );
+        // Everything but the excluded, and deleted, documents.
+        assert_eq!(matched(&index, "NOT wall"), vec!["c", "d"]);
+        assert!(matched(&index, "wall AND missing").is_empty());
+
+        // The optional words still rank the required matches.
+        assert_eq!(ranked(&index, "+journal wall street"), vec!["a", "d"]);
+
+        // Terms are intersected across segments, skipping deleted documents.
+        let mut builder = IndexBuilder::new().positions(true);
+        builder.add_document("f", ["wall", "merger"]).unwrap();
+        builder.add_document("g", ["tokyo", "journal"]).unwrap();
+        builder.write_segment(&dir).unwrap();
+        delete_documents(&dir, ["b"]).unwrap();
+        let index = DiskIndex::from_disk(&dir).unwrap();
+
+        assert_eq!(matched(&index, "wall AND merger"), vec!["f"]);
+        assert_eq!(matched(&index, "journal -wall"), vec!["d", "g"]);
+        assert_eq!(matched(&index, "NOT (wall OR journal)"), vec!["c"]);
+        assert_eq!(
+            matched(&index, "+merger +\"wall street\""),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn plain_punctuation() {
+        let dir = TempDir::new("punctuation");
+        let docs = [
+            ("a", "u.s. japan trade talks in 1990"),
+            ("b", "anti dumping duties on japan"),
+            ("c", "trade deficit"),
+        ];
+        let index = index(&dir, IndexBuilder::new(), &docs);
+
+        // Hyphens and parentheses rank as the words on their own do.
+        let scored = |query: &str| index.search(query).unwrap().collect::<Vec<_>>();
+        assert_eq!(
+            scored("u.s.-japan trade (1990)"),
+            scored("u.s. japan trade 1990")
+        );
+        assert_eq!(scored("anti-dumping"), scored("anti dumping"));
+        assert_eq!(
+            ranked(&index, "u.s.-japan trade (1990)"),
+            vec!["a", "c", "b"]
+        );
+    }
+}
@@ -0,0 +1,439 @@
+// Ranking functions. A `Scorer` scores each query term (or phrase, or window)
+// of a document separately, and a document's score is the sum over every
+// query term it holds. Scorers only see documents holding at least one query
+// term, so models which also score missing terms (like query likelihood) are
+// written in a rank equivalent form over the matching terms.
+//
+// Lengths and counts a model divides by are taken to be at least 1, so an
+// empty document or collection scores a finite number rather than NaN.
+
+// Statistics over the whole collection, not counting deleted documents.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Collection {
+    // N, the number of documents.
+    pub documents: u64,
+    // avgdl, the average document length.
+    pub avg_length: f32,
+    // |C|, the total number of terms.
+    pub total_length: u64,
+}
+
+// Statistics of a single query term across the collection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TermStats {
+    // n(q_i), the number of documents holding the term.
+    pub documents: u64,
+    // F(q_i), the number of times the term occurs in the collection.
+    pub frequency: u64,
+}
+
+pub trait Scorer: Send + Sync {
+    // Scores `frequency` occurrences of a term in a document `length` terms
+    // long. A score of exactly 0 doesn't count as a match.
+    fn score(&self, collection: &Collection, term: &TermStats, frequency: u64, length: u64) -> f32;
+
+    // Added once to the score of every matching document, given the number
+    // of terms in the query. Mustn't be positive, so that `upper_bound`
+    // holds for the whole document.
+    fn document(&self, _collection: &Collection, _length: u64, _terms: usize) -> f32 {
+        0.0
+    }
+
+    // An upper bound on `score` for any posting of a term, given the highest
+    // frequency of the term in a document and the length of the shortest
+    // document holding it. The default relies on scores growing with
+    // frequency and shrinking with length, which holds for every built-in
+    // model but PL2. Only used to skip documents which can't make the top k.
+    fn upper_bound(
+        &self,
+        collection: &Collection,
+        term: &TermStats,
+        max_frequency: u64,
+        min_length: u64,
+    ) -> f32 {
+        self.score(collection, term, max_frequency, min_length)
+            .max(0.0)
+    }
+}
+
+// Names of the built-in scorers, as built by
+// `SearchOptions::build_scorer`.
+pub const SCORERS: [&str; 9] = [
+    "bm25",
+    "bm25-classic",
+    "bm25-lucene",
+    "bm25+",
+    "dirichlet",
+    "jelinek-mercer",
+    "pl2",
+    "inl2",
+    "tfidf",
+];
+
+// The IDF variants BM25 can be used with, where N is the number of documents
+// and n(q_i) the number of documents containing q_i.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Idf {
+    // IDF(q_i) = ln( N / n(q_i) )
+    Plain,
+    // IDF(q_i) = max( 0, ln( (N - n(q_i) + 0.5) / (n(q_i) + 0.5) ) )
+    // (Robertson and Sparck Jones.) Clamped at 0 for terms in more than half
+    // of the documents, which would otherwise score negatively, breaking the
+    // bounds top-k search relies on.
+    Classic,
+    // IDF(q_i) = ln( (N - n(q_i) + 0.5) / (n(q_i) + 0.5) + 1 )
+    // (As in Lucene.) Always positive.
+    Lucene,
+}
+
+impl Idf {
+    pub fn idf(self, documents: u64, n_q_i: u64) -> f32 {
+        let n = documents.max(1) as f32;
+        let n_q_i = n_q_i.max(1) as f32;
+
+        match self {
+            Idf::Plain => (n / n_q_i).ln(),
+            Idf::Classic => ((n - n_q_i + 0.5) / (n_q_i + 0.5)).ln().max(0.0),
+            Idf::Lucene => ((n - n_q_i + 0.5) / (n_q_i + 0.5) + 1.0).ln(),
+        }
+    }
+}
+
+// (BM25)
+// score(D,Q) = Sum{1..n}
+// IDF(q_i) * ( ( f(q_i, D) * (k_1 + 1) ) /
+//   ( f(q_i, D) + k_1 * (1 - b + b * (|D| / avgdl))) )
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bm25 {
+    pub k1: f32,
+    pub b: f32,
+    pub idf: Idf,
+}
+
+impl Default for Bm25 {
+    // Reference: Andrew Trotman, Matt Crane, "Snip!".
+    // http://www.cs.otago.ac.nz/homepages/andrew/papers/2011-13.pdf
+    fn default() -> Self {
+        Bm25 {
+            k1: 0.9,
+            b: 0.4,
+            idf: Idf::Plain,
+        }
+    }
+}
+
+impl Bm25 {
+    pub fn idf(mut self, idf: Idf) -> Self {
+        self.idf = idf;
+        self
+    }
+
+    // The length normalised k_1 of a document,
+    // K = k_1 * (1 - b + b * (|D| / avgdl))
+    pub fn k(&self, length: u64, avg_length: f32) -> f32 {
+        self.k1 * (1.0 - self.b + self.b * relative_length(length, avg_length))
+    }
+}
+
+impl Scorer for Bm25 {
+    fn score(&self, collection: &Collection, term: &TermStats, frequency: u64, length: u64) -> f32 {
+        let idf = self.idf.idf(collection.documents, term.documents);
+
+        // `score_qt` is determined by multiplying by `idf`, so if `idf` is
+        // 0 we know that the score can't positively effect the outcome.
+        if idf == 0.0 {
+            return 0.0;
+        }
+
+        let term_freq = frequency as f32;
+        idf * ((term_freq * (self.k1 + 1.0)) / (term_freq + self.k(length, collection.avg_length)))
+    }
+}
+
+// (BM25+)
+// Lv and Zhai, "Lower-Bounding Term Frequency Normalization", CIKM 2011.
+// score(D,Q) = Sum{1..n} ln( (N + 1) / n(q_i) ) * ( BM25 tf part + delta )
+// The lower bound `delta` keeps very long documents from scoring a matching
+// term as if it were missing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bm25Plus {
+    pub k1: f32,
+    pub b: f32,
+    pub delta: f32,
+}
+
+impl Default for Bm25Plus {
+    fn default() -> Self {
+        Bm25Plus {
+            k1: 0.9,
+            b: 0.4,
+            delta: 1.0,
+        }
+    }
+}
+
+impl Scorer for Bm25Plus {
+    fn score(&self, collection: &Collection, term: &TermStats, frequency: u64, length: u64) -> f32 {
+        let idf = ((collection.documents as f32 + 1.0) / term.documents.max(1) as f32).ln();
+        let tf = frequency as f32;
+        let k = self.k1 * (1.0 - self.b + self.b * relative_length(length, collection.avg_length));
+
+        idf * ((tf * (self.k1 + 1.0)) / (tf + k) + self.delta)
+    }
+}
+
+// |D| / avgdl, or 1 if every document is empty.
+fn relative_length(length: u64, avg_length: f32) -> f32 {
+    if avg_length > 0.0 {
+        length as f32 / avg_length
+    } else {
+        1.0
+    }
+}
+
+// The probability of a term in the collection language model, F(q_i) / |C|.
+fn collection_probability(collection: &Collection, term: &TermStats) -> f32 {
+    term.frequency.max(1) as f32 / collection.total_length.max(1) as f32
+}
+
+// Query likelihood with Dirichlet smoothing (Zhai and Lafferty, "A Study of
+// Smoothing Methods for Language Models Applied to Ad Hoc Information
+// Retrieval", SIGIR 2001),
+// score(D,Q) = Sum{q_i in D} ln( 1 + f(q_i, D) / (mu * P(q_i|C)) )
+//   + |Q| * ln( mu / (|D| + mu) )
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dirichlet {
+    pub mu: f32,
+}
+
+impl Default for Dirichlet {
+    fn default() -> Self {
+        Dirichlet { mu: 2000.0 }
+    }
+}
+
+impl Scorer for Dirichlet {
+    fn score(
+        &self,
+        collection: &Collection,
+        term: &TermStats,
+        frequency: u64,
+        _length: u64,
+    ) -> f32 {
+        let p = collection_probability(collection, term);
+        (1.0 + frequency as f32 / (self.mu * p)).ln()
+    }
+
+    fn document(&self, _collection: &Collection, length: u64, terms: usize) -> f32 {
+        terms as f32 * (self.mu / (length as f32 + self.mu)).ln()
+    }
+}
+
+// Query likelihood with Jelinek-Mercer smoothing (Zhai and Lafferty, as
+// above), where `lambda` is the weight of the collection model,
+// score(D,Q) = Sum{q_i in D}
+// ln( 1 + ((1 - lambda) * f(q_i, D) / |D|) / (lambda * P(q_i|C)) )
+#[derive(Debug, Clone, PartialEq)]
+pub struct JelinekMercer {
+    pub lambda: f32,
+}
+
+impl Default for JelinekMercer {
+    fn default() -> Self {
+        JelinekMercer { lambda: 0.7 }
+    }
+}
+
+impl Scorer for JelinekMercer {
+    fn score(&self, collection: &Collection, term: &TermStats, frequency: u64, length: u64) -> f32 {
+        let p = collection_probability(collection, term);
+        let document = frequency as f32 / length.max(1) as f32;
+
+        (1.0 + ((1.0 - self.lambda) * document) / (self.lambda * p)).ln()
+    }
+}
+
+// Normalisation 2 of the divergence from randomness models (Amati and Van
+// Rijsbergen, "Probabilistic Models of Information Retrieval Based on
+// Measuring the Divergence from Randomness", TOIS 2002),
+// tfn = f(q_i, D) * log2( 1 + c * avgdl / |D| )
+fn normalisation_2(c: f32, collection: &Collection, frequency: u64, length: u64) -> f32 {
+    frequency as f32 * (1.0 + c * collection.avg_length / length.max(1) as f32).log2()
+}
+
+// (PL2) A Poisson model of randomness, with the Laplace after effect,
+// score(D,Q) = Sum{1..n} 1 / (tfn + 1) * ( tfn * log2(tfn / lambda)
+//   + (lambda - tfn) * log2(e) + 0.5 * log2(2 * pi * tfn) )
+// where lambda = F(q_i) / N.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pl2 {
+    pub c: f32,
+}
+
+impl Default for Pl2 {
+    fn default() -> Self {
+        Pl2 { c: 1.0 }
+    }
+}
+
+impl Scorer for Pl2 {
+    fn score(&self, collection: &Collection, term: &TermStats, frequency: u64, length: u64) -> f32 {
+        let tfn = normalisation_2(self.c, collection, frequency, length);
+        // Only when every document is empty, where nothing can match.
+        if tfn == 0.0 {
+            return 0.0;
+        }
+        let lambda = term.frequency.max(1) as f32 / collection.documents.max(1) as f32;
+
+        (tfn * (tfn / lambda).log2()
+            + (lambda - tfn) * std::f32::consts::LOG2_E
+            + 0.5 * (2.0 * std::f32::consts::PI * tfn).log2())
+            / (tfn + 1.0)
+    }
+
+    // PL2 doesn't always grow with the frequency of a term, so its terms
+    // are never skipped.
+    fn upper_bound(&self, _: &Collection, _: &TermStats, _: u64, _: u64) -> f32 {
+        f32::INFINITY
+    }
+}
+
+// (InL2) An inverse document frequency model of randomness, with the Laplace
+// after effect,
+// score(D,Q) = Sum{1..n} tfn / (tfn + 1) * log2( (N + 1) / (n(q_i) + 0.5) )
+#[derive(Debug, Clone, PartialEq)]
+pub struct InL2 {
+    pub c: f32,
+}
+
+impl Default for InL2 {
+    fn default() -> Self {
+        InL2 { c: 1.0 }
+    }
+}
+
+impl Scorer for InL2 {
+    fn score(&self, collection: &Collection, term: &TermStats, frequency: u64, length: u64) -> f32 {
+        let tfn = normalisation_2(self.c, collection, frequency, length);
+        let idf = ((collection.documents as f32 + 1.0) / (term.documents as f32 + 0.5)).log2();
+
+        tfn / (tfn + 1.0) * idf
+    }
+}
+
+// Classic TF-IDF with cosine normalisation, as in Lucene's classic
+// similarity,
+// score(D,Q) = Sum{1..n} sqrt(f(q_i, D)) * IDF(q_i)^2 / sqrt(|D|)
+// where IDF(q_i) = 1 + ln( N / (n(q_i) + 1) ). The norm of the document's
+// vector is approximated by the square root of its length, as only the
+// query terms' weights are at hand. The query's norm doesn't change the
+// ranking, so it's left out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TfIdf;
+
+impl Scorer for TfIdf {
+    fn score(&self, collection: &Collection, term: &TermStats, frequency: u64, length: u64) -> f32 {
+        let idf = 1.0 + (collection.documents.max(1) as f32 / (term.documents as f32 + 1.0)).ln();
+        (frequency as f32).sqrt() * idf * idf / (length.max(1) as f32).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::searching::options::SearchOptions;
+
+    const COLLECTION: Collection = Collection {
+        documents: 1000,
+        avg_length: 100.0,
+        total_length: 100_000,
+    };
+    const RARE: TermStats = TermStats {
+        documents: 10,
+        frequency: 20,
+    };
+    const COMMON: TermStats = TermStats {
+        documents: 500,
+        frequency: 2000,
+    };
+
+    #[test]
+    fn idf() {
+        assert_eq!(Idf::Plain.idf(10, 10), 0.0);
+        assert_eq!(Idf::Classic.idf(10, 9), 0.0);
+        assert!(Idf::Classic.idf(10, 4) > 0.0);
+        assert!(Idf::Lucene.idf(10, 10) > 0.0);
+        assert!(Idf::Plain.idf(10, 1) > Idf::Plain.idf(10, 2));
+    }
+
+    // Every model should prefer rarer terms, more occurrences and shorter
+    // documents.
+    #[test]
+    fn models() {
+        for name in SCORERS {
+            let scorer = SearchOptions {
+                scorer: name.to_string(),
+                ..SearchOptions::default()
+            }
+            .build_scorer()
+            .unwrap();
+            let score = |term, frequency, length| {
+                scorer.score(&COLLECTION, term, frequency, length)
+                    + scorer.document(&COLLECTION, length, 1)
+            };
+
+            assert!(score(&RARE, 2, 100) > score(&COMMON, 2, 100), "{}", name);
+            assert!(score(&RARE, 3, 100) > score(&RARE, 1, 100), "{}", name);
+            assert!(score(&RARE, 2, 50) > score(&RARE, 2, 200), "{}", name);
+        }
+    }
+
+    #[test]
+    fn bm25() {
+        let scorer = Bm25::default();
+        let idf = (1000.0f32 / 10.0).ln();
+
+        assert_eq!(
+            scorer.score(&COLLECTION, &RARE, 1, 100),
+            idf * (1.9 / (1.0 + 0.9))
+        );
+        assert_eq!(scorer.k(100, 100.0), 0.9);
+
+        // A term in every document can't match.
+        let everywhere = TermStats {
+            documents: 1000,
+            frequency: 1000,
+        };
+        assert_eq!(scorer.score(&COLLECTION, &everywhere, 1, 100), 0.0);
+    }
+
+    // An empty document, or statistics of nothing at all, still score a
+    // number.
+    #[test]
+    fn empty() {
+        let empty = Collection {
+            documents: 0,
+            avg_length: 0.0,
+            total_length: 0,
+        };
+        let nowhere = TermStats {
+            documents: 0,
+            frequency: 0,
+        };
+
+        for name in SCORERS {
+            let scorer = SearchOptions {
+                scorer: name.to_string(),
+                ..SearchOptions::default()
+            }
+            .build_scorer()
+            .unwrap();
+
+            for (collection, term) in [(&COLLECTION, &RARE), (&empty, &nowhere)] {
+                let score = scorer.score(collection, term, 1, 0);
+                assert!(score.is_finite(), "{} scored {}", name, score);
+                assert!(scorer.document(collection, 0, 1).is_finite(), "{}", name);
+            }
+        }
+    }
+}
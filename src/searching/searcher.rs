@@ -0,0 +1,226 @@
+use crate::indexing::index::DiskIndex;
+use crate::parsing::analyzer::Analyzer;
+use crate::searching::query::{correct, Query};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+// A single ranked document returned by a search.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchResult {
+    // Internal document ID, as assigned by the indexer.
+    pub document: u64,
+    // The DOCNO of the document.
+    pub name: String,
+    pub score: f32,
+}
+
+// Runs queries against a `DiskIndex`, analyzing each query the same way the
+// documents were analyzed at index time.
+pub struct Searcher {
+    index: DiskIndex,
+    analyzer: Analyzer,
+}
+
+impl Searcher {
+    // Opens the index stored in the directory `dir`.
+    pub fn open<P: AsRef<Path>>(dir: P) -> std::io::Result<Searcher> {
+        Ok(Searcher::new(DiskIndex::from_disk(dir)?))
+    }
+
+    pub fn new(index: DiskIndex) -> Searcher {
+        Searcher {
+            index,
+            analyzer: Analyzer::new(),
+        }
+    }
+
+    pub fn index(&mut self) -> &mut DiskIndex {
+        &mut self.index
+    }
+
+    // Returns every matching document, best match first. Quoted parts of
+    // the query are matched as phrases. A searcher can be shared between
+    // threads, each running its own queries.
+    pub fn search(&self, query: &str) -> std::io::Result<Vec<SearchResult>> {
+        let query = Query::parse(query, &self.analyzer);
+        let results = self.index.search_query(&query)?;
+
+        Ok(results
+            .map(|(score, document)| SearchResult {
+                document,
+                name: self.index.document(document).to_string(),
+                score,
+            })
+            .collect())
+    }
+
+    // Suggests a corrected query, replacing each word whose term few or no
+    // documents hold with a far more common term a couple of edits away.
+    // Returns None if there's nothing to correct.
+    pub fn suggest(&self, query: &str) -> std::io::Result<Option<String>> {
+        correct(query, &self.analyzer, |term| self.index.suggest(term))
+    }
+
+    // Runs every one of `queries` on `threads` threads at once, all sharing
+    // the index, and returns their results in the order the queries were
+    // given. Each thread takes the next query not yet started, so a few slow
+    // queries don't hold the others up. Stops at the first query which fails.
+    pub fn search_all<S>(
+        &self,
+        queries: &[S],
+        threads: usize,
+    ) -> std::io::Result<Vec<Vec<SearchResult>>>
+    where
+        S: AsRef<str> + Sync,
+    {
+        let next = AtomicUsize::new(0);
+
+        let batches = thread::scope(|scope| {
+            let workers = (0..threads.clamp(1, queries.len().max(1)))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut results = Vec::new();
+                        loop {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            if i >= queries.len() {
+                                return Ok(results);
+                            }
+
+                            match self.search(queries[i].as_ref()) {
+                                Ok(r) => results.push((i, r)),
+                                Err(e) => {
+                                    // Stops the other threads early.
+                                    next.store(queries.len(), Ordering::Relaxed);
+                                    return Err(e);
+                                }
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            workers
+                .into_iter()
+                .map(|w| w.join().unwrap())
+                .collect::<std::io::Result<Vec<_>>>()
+        })?;
+
+        let mut results = vec![Vec::new(); queries.len()];
+        for (i, r) in batches.into_iter().flatten() {
+            results[i] = r;
+        }
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::builder::IndexBuilder;
+    use crate::indexing::deletes::delete_documents;
+    use crate::indexing::index::DiskIndex;
+    use crate::indexing::testing::TempDir;
+
+    #[test]
+    fn search_all_keeps_query_order() {
+        let dir = TempDir::new("search-all");
+
+        let mut builder = IndexBuilder::new();
+        for i in 0..100 {
+            let terms = (0..5).map(|j| format!("t{}", (i + j * 3) % 40));
+            builder.add_document(&format!("d{}", i), terms).unwrap();
+        }
+        builder.write(&dir).unwrap();
+
+        let searcher = Searcher::open(&dir).unwrap();
+        let queries = (0..40)
+            .map(|i| format!("t{} t{}", i, (i * 7) % 40))
+            .collect::<Vec<_>>();
+
+        let expected = queries
+            .iter()
+            .map(|q| searcher.search(q).unwrap())
+            .collect::<Vec<_>>();
+        for threads in [1, 3, 100] {
+            assert_eq!(searcher.search_all(&queries, threads).unwrap(), expected);
+        }
+        assert!(searcher.search_all::<&str>(&[], 4).unwrap().is_empty());
+    }
+
+    #[test]
+    fn suggestions() {
+        let dir = TempDir::new("suggest");
+
+        let mut builder = IndexBuilder::new();
+        for i in 0..12 {
+            builder
+                .add_document(&format!("d{}", i), ["stock", "market"])
+                .unwrap();
+        }
+        builder.add_document("x", ["stok"]).unwrap();
+        builder.add_document("y", ["stoke"]).unwrap();
+        builder.write(&dir).unwrap();
+
+        let mut searcher = Searcher::open(&dir).unwrap();
+        let index = searcher.index();
+        assert_eq!(index.suggest("stok").unwrap(), Some("stock".to_string()));
+        assert_eq!(index.suggest("markte").unwrap(), Some("market".to_string()));
+        assert_eq!(index.suggest("stoke").unwrap(), Some("stock".to_string()));
+        // Common terms, and rare ones with nothing far more common nearby,
+        // are left alone.
+        assert_eq!(index.suggest("stock").unwrap(), None);
+        assert_eq!(index.suggest("zzzz").unwrap(), None);
+
+        assert_eq!(
+            searcher.suggest("Stok markte*  OR markte").unwrap(),
+            Some("stock markte*  OR market".to_string())
+        );
+        assert_eq!(searcher.suggest("stock market").unwrap(), None);
+
+        // Deleted documents don't count, so "stock" is no longer held by
+        // more than ten times as many as "stok".
+        delete_documents(&dir, ["d0", "d1", "d2"]).unwrap();
+        let index = DiskIndex::from_disk(&dir).unwrap();
+        assert_eq!(index.document_frequency("stock").unwrap(), 9);
+        assert_eq!(index.suggest("stok").unwrap(), None);
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let dir = TempDir::new("shared");
+
+        // Enough terms for several dictionary blocks, which the threads
+        // decode as they go.
+        let mut builder = IndexBuilder::new();
+        for i in 0..500 {
+            let terms = (0..10).map(|j| format!("t{}", (i * 7 + j * 13) % 3000));
+            builder.add_document(&format!("d{}", i), terms).unwrap();
+        }
+        builder.write(&dir).unwrap();
+
+        let index = DiskIndex::from_disk(&dir).unwrap();
+        let queries = (0..3000)
+            .step_by(37)
+            .map(|i| format!("t{} t{}", i, (i * 11) % 3000))
+            .collect::<Vec<_>>();
+
+        let search = |index: &DiskIndex| {
+            queries
+                .iter()
+                .map(|q| index.search(q).unwrap().collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+
+        let expected = search(&DiskIndex::from_disk(&dir).unwrap());
+        std::thread::scope(|scope| {
+            let threads = (0..4)
+                .map(|_| scope.spawn(|| search(&index)))
+                .collect::<Vec<_>>();
+            for thread in threads {
+                assert_eq!(thread.join().unwrap(), expected);
+            }
+        });
+    }
+}
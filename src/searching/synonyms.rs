@@ -0,0 +1,237 @@
+use crate::parsing::analyzer::Analyzer;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Error, ErrorKind};
+
+// Default weight of a synonym's score, relative to the word it stands in for.
+pub const DEFAULT_SYNONYM_WEIGHT: f32 = 0.5;
+
+// Synonyms to expand query words and phrases with. Each entry is analyzed
+// the way documents are, so a phrase maps to (and from) its terms.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thesaurus {
+    // Analyzed terms -> the synonyms they're expanded with, in the order
+    // they were read
+    synonyms: HashMap<Vec<String>, Vec<Vec<String>>>,
+    weight: f32,
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+impl Default for Thesaurus {
+    fn default() -> Self {
+        Thesaurus {
+            synonyms: HashMap::new(),
+            weight: DEFAULT_SYNONYM_WEIGHT,
+        }
+    }
+}
+
+impl Thesaurus {
+    pub fn new() -> Thesaurus {
+        Thesaurus::default()
+    }
+
+    // Reads synonyms in Solr's format, where each line either lists words
+    // and phrases which all mean the same, or maps some onto others with
+    // `=>`. Lines starting with `#` are comments.
+    //
+    //   tv, television, telly
+    //   usa, u.s.a. => united states, america
+    //
+    // Lines of WordNet's Prolog database (`wn_s.pl`) are read too, each
+    // synset's words meaning the same.
+    //
+    //   s(108544813,1,'united states',n,1,2).
+    pub fn read<R: BufRead>(reader: R) -> std::io::Result<Thesaurus> {
+        let analyzer = Analyzer::new();
+        let mut thesaurus = Thesaurus::new();
+
+        // Synset ID -> words
+        let mut synsets: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(fact) = line.strip_prefix("s(") {
+                let (synset, word) = synset_word(fact)
+                    .ok_or_else(|| invalid(format!("bad synset line '{}'", line)))?;
+                synsets.entry(synset).or_default().push(word);
+                continue;
+            }
+
+            let list = |side: &str| {
+                side.split(',')
+                    .map(|entry| analyzer.terms(entry).collect::<Vec<_>>())
+                    .filter(|terms| !terms.is_empty())
+                    .collect::<Vec<_>>()
+            };
+
+            match line.split_once("=>") {
+                Some((from, to)) => thesaurus.map(&list(from), &list(to)),
+                None => thesaurus.equate(&list(line)),
+            }
+        }
+
+        for words in synsets.values() {
+            let entries = words
+                .iter()
+                .map(|word| analyzer.terms(word).collect::<Vec<_>>())
+                .filter(|terms| !terms.is_empty())
+                .collect::<Vec<_>>();
+            thesaurus.equate(&entries);
+        }
+
+        Ok(thesaurus)
+    }
+
+    // Sets how much a synonym's score counts for, relative to the word it
+    // stands in for.
+    pub fn weight(mut self, weight: f32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    pub fn synonym_weight(&self) -> f32 {
+        self.weight
+    }
+
+    // Expands each of `from` with every one of `to`.
+    pub fn map(&mut self, from: &[Vec<String>], to: &[Vec<String>]) {
+        for key in from {
+            let synonyms = self.synonyms.entry(key.clone()).or_default();
+            for synonym in to {
+                if synonym != key && !synonyms.contains(synonym) {
+                    synonyms.push(synonym.clone());
+                }
+            }
+        }
+    }
+
+    // Expands each of `entries` with all the others.
+    pub fn equate(&mut self, entries: &[Vec<String>]) {
+        self.map(entries, entries);
+    }
+
+    // The synonyms of a term, or of a phrase's terms.
+    pub fn synonyms(&self, terms: &[String]) -> &[Vec<String>] {
+        self.synonyms.get(terms).map_or(&[], Vec::as_slice)
+    }
+}
+
+// Parses the synset ID and word out of the rest of a WordNet `s(...)` fact,
+// where the word is quoted and doubles any quote within it.
+fn synset_word(fact: &str) -> Option<(u64, String)> {
+    let (synset, rest) = fact.split_once(',')?;
+    let (_, rest) = rest.split_once(",'")?;
+
+    let mut word = String::new();
+    let mut chars = rest.chars().peekable();
+    loop {
+        match chars.next()? {
+            '\'' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                word.push('\'');
+            }
+            '\'' => break,
+            c => word.push(c),
+        }
+    }
+
+    Some((synset.trim().parse().ok()?, word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::builder::IndexBuilder;
+    use crate::indexing::index::DiskIndex;
+    use crate::indexing::testing::TempDir;
+
+    fn terms(words: &str) -> Vec<String> {
+        words.split(' ').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn read() {
+        let file = "# countries\n\
+                    usa, u.s.a. => united states, america\n\
+                    \n\
+                    TV, television\n\
+                    s(108544813,1,'United States',n,1,2).\n\
+                    s(108544813,2,'US',n,2,0).\n\
+                    s(100001,1,'o''clock',n,1,0).\n";
+        let thesaurus = Thesaurus::read(file.as_bytes()).unwrap();
+
+        assert_eq!(
+            thesaurus.synonyms(&terms("usa")),
+            &[terms("united states"), terms("america")]
+        );
+        // Mappings only go one way.
+        assert!(thesaurus.synonyms(&terms("america")).is_empty());
+        assert_eq!(thesaurus.synonyms(&terms("tv")), &[terms("television")]);
+        assert_eq!(thesaurus.synonyms(&terms("television")), &[terms("tv")]);
+        // Phrases have synonyms too, such as the other words of a synset.
+        assert_eq!(thesaurus.synonyms(&terms("united states")), &[terms("us")]);
+
+        assert!(Thesaurus::read("s(1,1,broken".as_bytes()).is_err());
+        assert_eq!(thesaurus.clone().weight(0.8).synonym_weight(), 0.8);
+    }
+
+    #[test]
+    fn expansion() {
+        let root = TempDir::new("synonyms");
+
+        let mut thesaurus = Thesaurus::new();
+        thesaurus.map(&[terms("usa")], &[terms("united states"), terms("america")]);
+
+        for positions in [true, false] {
+            let dir = root.join(positions.to_string());
+            let mut builder = IndexBuilder::new().positions(positions);
+            builder.add_document("a", ["usa", "trade"]).unwrap();
+            builder
+                .add_document("b", ["united", "states", "trade"])
+                .unwrap();
+            builder
+                .add_document("c", ["states", "united", "trade"])
+                .unwrap();
+            builder.add_document("d", ["america", "bank"]).unwrap();
+            builder.add_document("e", ["trade"]).unwrap();
+            builder.write(&dir).unwrap();
+
+            let mut index = DiskIndex::from_disk(&dir).unwrap();
+            let matched = |index: &DiskIndex, query: &str| {
+                let mut matched = index
+                    .search(query)
+                    .unwrap()
+                    .map(|(_, doc)| index.document(doc).to_string())
+                    .collect::<Vec<_>>();
+                // Synonyms score less than the word itself.
+                matched[1..].sort();
+                matched
+            };
+            assert_eq!(matched(&index, "usa"), vec!["a"]);
+
+            index.set_synonyms(Some(thesaurus.clone()));
+            // Without positions, `united states` can't be told apart from
+            // `states united`.
+            let expected = if positions {
+                vec!["a", "b", "d"]
+            } else {
+                vec!["a", "b", "c", "d"]
+            };
+            assert_eq!(matched(&index, "usa"), expected);
+            assert_eq!(matched(&index, "=usa"), vec!["a"]);
+            assert_eq!(
+                matched(&index, "usa AND NOT bank"),
+                expected[..expected.len() - 1]
+            );
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use crate::indexing::index::{Posting, TermBound};
+use crate::indexing::postings::Cursor;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+// A scored document. Documents are ranked by score, and ties go to the lower
+// document ID so the top k is the same whatever order documents are seen in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Ranked {
+    score: f32,
+    document: u64,
+}
+
+impl Eq for Ranked {}
+
+impl Ord for Ranked {
+    // The worse document is the greater, so the heap keeps the worst of the
+    // top k on top.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .score
+            .total_cmp(&self.score)
+            .then(self.document.cmp(&other.document))
+    }
+}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Keeps the `k` best documents seen so far.
+pub struct TopK {
+    k: usize,
+    heap: BinaryHeap<Ranked>,
+}
+
+impl TopK {
+    pub fn new(k: usize) -> TopK {
+        TopK {
+            k,
+            heap: BinaryHeap::with_capacity(k.min(4096) + 1),
+        }
+    }
+
+    // Once k documents have been seen, the score of the worst of them. A new
+    // document needs to beat it to get in.
+    pub fn threshold(&self) -> Option<f32> {
+        if self.heap.len() >= self.k {
+            Some(self.heap.peek().map_or(f32::INFINITY, |r| r.score))
+        } else {
+            None
+        }
+    }
+
+    pub fn push(&mut self, score: f32, document: u64) {
+        let ranked = Ranked { score, document };
+
+        if self.heap.len() < self.k {
+            self.heap.push(ranked);
+        } else if let Some(mut worst) = self.heap.peek_mut() {
+            if ranked < *worst {
+                *worst = ranked;
+            }
+        }
+    }
+
+    // The documents kept, best first.
+    pub fn into_sorted(self) -> Vec<(f32, u64)> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|r| (r.score, r.document))
+            .collect()
+    }
+}
+
+// Document at a time evaluation with MaxScore pruning (Turtle and Flood,
+// "Query Evaluation: Strategies and Optimizations", IP&M 1995), refined with
+// the bounds of each block of postings as Block-Max MaxScore does (Chang and
+// Poon, "Optimizing Top-k Document Retrieval Strategies for Block-Max
+// Indexes", WSDM 2013). Returns the `k` best documents, best first.
+//
+// `bounds` holds an upper bound on `score` for each cursor. Once the top k is
+// full, the cursors with the lowest bounds which together can't beat the
+// worst of the top k are non-essential: only documents in the other cursors
+// are considered, and a document's non-essential cursors are only looked at
+// while they could still lift it into the top k.
+//
+// `block` bounds `score` for a block of a cursor's postings, given the
+// block's bound. A run of documents whose blocks can't together beat the
+// worst of the top k is skipped over, without decoding the blocks of any
+// cursor but those which have postings past it. Cursors which don't keep
+// blocks are bounded by `bounds` throughout.
+//
+// A posting scored exactly 0 doesn't count as a match, and `document` is
+// added once to the score of every matching document. It mustn't be
+// positive, or the bounds wouldn't hold.
+pub fn max_score<S, B, D>(
+    cursors: &mut [Box<dyn Cursor + '_>],
+    bounds: &[f32],
+    k: usize,
+    score: S,
+    block: B,
+    document: D,
+) -> std::io::Result<Vec<(f32, u64)>>
+where
+    S: Fn(usize, &Posting) -> f32,
+    B: Fn(usize, TermBound) -> f32,
+    D: Fn(u64) -> f32,
+{
+    let mut top = TopK::new(k);
+    if k == 0 {
+        return Ok(Vec::new());
+    }
+
+    // Cursors in increasing order of their bounds, and the sum of the bounds
+    // of each cursor and every cursor before it in that order.
+    let mut order = (0..cursors.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| bounds[a].partial_cmp(&bounds[b]).unwrap_or(Ordering::Equal));
+    let cumulative = order
+        .iter()
+        .scan(0.0, |sum, &i| {
+            *sum += bounds[i];
+            Some(*sum)
+        })
+        .collect::<Vec<f32>>();
+
+    let mut scores = vec![0.0; cursors.len()];
+    // The bound of each cursor's block holding the current document, and
+    // the last document the block covers. Only looked up again once the
+    // documents have moved past it.
+    let mut blocks = bounds.to_vec();
+    let mut ends = vec![None; cursors.len()];
+    // `order[..essential]` are the non-essential cursors.
+    let mut essential = 0;
+
+    loop {
+        let doc = order[essential..]
+            .iter()
+            .filter_map(|&i| cursors[i].posting().map(|p| p.document))
+            .min();
+        let doc = match doc {
+            Some(doc) => doc,
+            None => break,
+        };
+
+        if let Some(threshold) = top.threshold() {
+            // Every document up to the end of the first of these blocks to
+            // end is bounded by them.
+            let mut end = u64::MAX;
+            let mut total = 0.0;
+            for (i, cursor) in cursors.iter().enumerate() {
+                if ends[i].is_none_or(|last| last < doc) {
+                    (blocks[i], ends[i]) = match cursor.posting() {
+                        None => (0.0, Some(u64::MAX)),
+                        Some(_) => match cursor.block_bound(doc) {
+                            Some((last, bound)) => (block(i, bound).min(bounds[i]), Some(last)),
+                            None => (bounds[i], Some(u64::MAX)),
+                        },
+                    };
+                }
+                end = end.min(ends[i].unwrap_or(u64::MAX));
+                total += blocks[i];
+            }
+
+            if total <= threshold {
+                let next = match end.checked_add(1) {
+                    Some(next) => next,
+                    None => break,
+                };
+                for &i in &order[essential..] {
+                    cursors[i].next_geq(next)?;
+                }
+                continue;
+            }
+        }
+
+        scores.iter_mut().for_each(|s| *s = 0.0);
+
+        let mut partial = 0.0;
+        let mut matched = false;
+        let mut add = |i: usize, posting: &Posting, partial: &mut f32| {
+            let s = score(i, posting);
+            scores[i] = s;
+            *partial += s;
+            matched |= s != 0.0;
+        };
+
+        for &i in &order[essential..] {
+            if let Some(posting) = cursors[i].posting() {
+                if posting.document == doc {
+                    add(i, &posting, &mut partial);
+                    cursors[i].next_geq(doc + 1)?;
+                }
+            }
+        }
+
+        // The non-essential cursors, highest bound first, for as long as
+        // they could make a difference.
+        let mut remaining = order[..essential].iter().map(|&i| blocks[i]).sum::<f32>();
+        let mut pruned = false;
+        for j in (0..essential).rev() {
+            if let Some(threshold) = top.threshold() {
+                if partial + cumulative[j].min(remaining) <= threshold {
+                    pruned = true;
+                    break;
+                }
+            }
+
+            let i = order[j];
+            remaining -= blocks[i];
+            if let Some(posting) = cursors[i].next_geq(doc)? {
+                if posting.document == doc {
+                    add(i, &posting, &mut partial);
+                }
+            }
+        }
+
+        if pruned || !matched {
+            continue;
+        }
+
+        // Summed in cursor order, so scores come out exactly as they would
+        // scoring a term at a time.
+        let total = scores.iter().fold(0.0, |a, &s| a + s);
+        top.push(total + document(doc), doc);
+
+        if let Some(threshold) = top.threshold() {
+            while essential < order.len() && cumulative[essential] <= threshold {
+                essential += 1;
+            }
+        }
+    }
+
+    Ok(top.into_sorted())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::codec::Codec;
+    use crate::indexing::index::write_postings;
+    use crate::indexing::postings::{DecodedCursor, PostingsCursor, POSTINGS_BLOCK_SIZE};
+    use std::cell::Cell;
+
+    #[test]
+    fn top_k() {
+        let mut top = TopK::new(3);
+        assert_eq!(top.threshold(), None);
+
+        for (score, doc) in [(1.0, 5), (3.0, 1), (2.0, 9), (2.0, 4), (0.5, 2)] {
+            top.push(score, doc);
+        }
+        assert_eq!(top.threshold(), Some(2.0));
+        assert_eq!(top.into_sorted(), vec![(3.0, 1), (2.0, 4), (2.0, 9)]);
+
+        let mut top = TopK::new(0);
+        top.push(1.0, 0);
+        assert!(top.into_sorted().is_empty());
+    }
+
+    // MaxScore should find exactly the documents an exhaustive evaluation
+    // ranks highest, with or without the bounds of blocks.
+    #[test]
+    fn max_score_matches_exhaustive() {
+        // A small deterministic pseudo random generator.
+        let mut seed = 12345u64;
+        let mut next = move |n: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % n
+        };
+
+        // Frequencies are low in every other run of 500 documents, so some
+        // blocks score far less than others.
+        let mut lists = vec![Vec::new(); 4];
+        for document in 0..5000 {
+            let most = if document / 500 % 2 == 0 { 5 } else { 1 };
+            for (i, list) in lists.iter_mut().enumerate() {
+                if next(10) < 2 + i as u64 * 2 {
+                    list.push(Posting {
+                        document,
+                        frequency: 1 + next(most),
+                    });
+                }
+            }
+        }
+
+        let weights = [4.0, 2.0, 1.0, 0.5];
+        let length = |doc: u64| 1 + doc % 7;
+        let score = |i: usize, p: &Posting| {
+            // A list which never matches, like a term in every document.
+            if i == 3 {
+                0.0
+            } else {
+                weights[i] * p.frequency as f32 / (p.frequency + length(p.document)) as f32
+            }
+        };
+        let block = |i: usize, b: TermBound| {
+            weights[i] * b.max_frequency as f32 / (b.max_frequency + b.min_length) as f32
+        };
+        let document = |doc: u64| -((doc % 3) as f32) * 0.01;
+        let bounds = weights.map(|w| w * 5.0 / 6.0);
+
+        let mut exhaustive = TopK::new(usize::MAX);
+        for doc in 0..5000 {
+            let mut total = 0.0;
+            let mut matched = false;
+            for (i, list) in lists.iter().enumerate() {
+                if let Some(p) = list.iter().find(|p| p.document == doc) {
+                    let s = score(i, p);
+                    total += s;
+                    matched |= s != 0.0;
+                }
+            }
+            if matched {
+                exhaustive.push(total + document(doc), doc);
+            }
+        }
+        let exhaustive = exhaustive.into_sorted();
+
+        let encoded = lists
+            .iter()
+            .map(|list| {
+                let bounds = list
+                    .chunks(POSTINGS_BLOCK_SIZE)
+                    .map(|block| TermBound::of(block, length))
+                    .collect::<Vec<_>>();
+                let mut bytes = Vec::new();
+                let n = list.len() as u64;
+                write_postings(
+                    Codec::Varint,
+                    n,
+                    list.iter().copied(),
+                    &[],
+                    &bounds,
+                    &mut bytes,
+                )
+                .unwrap();
+                bytes
+            })
+            .collect::<Vec<_>>();
+
+        for k in [1, 10, 100, 10000] {
+            let expected = &exhaustive[..k.min(exhaustive.len())];
+
+            // Counts the postings scored.
+            let scored = Cell::new(0);
+            let counted = |i: usize, p: &Posting| {
+                scored.set(scored.get() + 1);
+                score(i, p)
+            };
+
+            let mut decoded = lists
+                .iter()
+                .map(|list| Box::new(DecodedCursor::new(list.clone())) as Box<dyn Cursor>)
+                .collect::<Vec<_>>();
+            let top = max_score(&mut decoded, &bounds, k, counted, block, document).unwrap();
+            assert_eq!(top, expected, "k = {}", k);
+            let without_blocks = scored.replace(0);
+
+            let mut cursors = encoded
+                .iter()
+                .map(|bytes| {
+                    let cursor = PostingsCursor::read(Codec::Varint, &mut bytes.as_slice());
+                    Box::new(cursor.unwrap()) as Box<dyn Cursor>
+                })
+                .collect::<Vec<_>>();
+            let top = max_score(&mut cursors, &bounds, k, counted, block, document).unwrap();
+            assert_eq!(top, expected, "k = {}", k);
+
+            // The blocks which can't make the top k are skipped.
+            if k < 100 {
+                assert!(scored.get() < without_blocks, "k = {}", k);
+            }
+        }
+    }
+}